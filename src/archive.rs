@@ -0,0 +1,823 @@
+//! archive
+//!
+//! The on-disk format of named archives: a compressed tarball sealed in
+//! fixed-size AES-256-GCM chunks, split across one or more volumes. Each
+//! volume starts with a short header recording its number and how the
+//! tarball is compressed.
+//!
+//! Every chunk is authenticated along with the header, its position in the
+//! volume and whether it is the last one, so chunks can't be altered,
+//! reordered or dropped without decryption failing, and each volume can be
+//! checked on its own. The tarball can be decrypted as a stream without
+//! ever holding all of it.
+
+use aes_gcm::{
+    aead::{generic_array::GenericArray, AeadInPlace, KeyInit},
+    Aes256Gcm,
+};
+use flate2::{read::GzDecoder, write::GzEncoder};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use xz2::{read::XzDecoder, write::XzEncoder};
+use zeroize::Zeroizing;
+
+/// Plaintext bytes sealed per chunk.
+///
+pub(crate) const CHUNK: usize = 1 << 16;
+const TAG: usize = 16;
+pub(crate) const HEADER: usize = 24;
+const MAGIC: &[u8; 8] = b"STASHARC";
+const VERSION: u8 = 1;
+
+/// How the tarball inside an archive, or a single file, is compressed.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    #[default]
+    Gzip,
+    Zstd,
+    Xz,
+}
+impl Compression {
+    ///
+    /// Returns the level used when none is given.
+    ///
+    pub fn default_level(self) -> u32 {
+        match self {
+            Compression::None => 0,
+            Compression::Gzip => 6,
+            Compression::Zstd => 3,
+            Compression::Xz => 6,
+        }
+    }
+
+    /// Returns the highest level supported.
+    ///
+    pub fn max_level(self) -> u32 {
+        match self {
+            Compression::None => 0,
+            Compression::Gzip => 9,
+            Compression::Zstd => 22,
+            Compression::Xz => 9,
+        }
+    }
+
+    /// Returns the byte recording this compression in a header.
+    ///
+    fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Gzip => 1,
+            Compression::Zstd => 2,
+            Compression::Xz => 3,
+        }
+    }
+
+    /// Returns the compression recorded as `id` in a header.
+    ///
+    fn from_id(id: u8) -> Result<Self, Error> {
+        match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Gzip),
+            2 => Ok(Compression::Zstd),
+            3 => Ok(Compression::Xz),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown archive compression: {}", id),
+            )),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(compression: &str) -> Result<Self, Error> {
+        match compression {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            "xz" => Ok(Compression::Xz),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown compression: {}", compression),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The settings recorded at the start of each volume of an archive.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub compression: Compression,
+    pub level: u8,
+    pub volume: u32,
+}
+impl Header {
+    ///
+    /// Encodes the header as it is stored.
+    ///
+    fn to_bytes(self) -> [u8; HEADER] {
+        let mut bytes = [0; HEADER];
+        bytes[..8].copy_from_slice(MAGIC);
+        bytes[8] = VERSION;
+        bytes[9] = self.compression.id();
+        bytes[10] = self.level;
+        bytes[12..16].copy_from_slice(&(CHUNK as u32).to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.volume.to_le_bytes());
+
+        bytes
+    }
+
+    /// Decodes a stored header, refusing anything this version can't read.
+    ///
+    fn parse(bytes: &[u8; HEADER]) -> Result<Self, Error> {
+        //
+        if &bytes[..8] != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a stash archive"));
+        }
+        if bytes[8] != VERSION || bytes[12..16] != (CHUNK as u32).to_le_bytes() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported archive format version {}", bytes[8]),
+            ));
+        }
+
+        Ok(Header {
+            compression: Compression::from_id(bytes[9])?,
+            level: bytes[10],
+            volume: u32::from_le_bytes([bytes[16], bytes[17], bytes[18], bytes[19]]),
+        })
+    }
+}
+
+/// Returns the nonce and associated data for chunk `counter` of a volume.
+///
+/// The volume number comes from `header`, so no two chunks of an archive
+/// share a nonce.
+///
+fn chunk_params(
+    nonce: &[u8],
+    header: &[u8; HEADER],
+    counter: u64,
+    last: bool,
+) -> ([u8; 12], Vec<u8>) {
+    //
+    let mut chunk_nonce = [0; 12];
+    chunk_nonce.copy_from_slice(nonce);
+    for (byte, volume) in chunk_nonce[..4].iter_mut().zip(&header[16..20]) {
+        *byte ^= volume;
+    }
+    for (byte, count) in chunk_nonce[4..].iter_mut().zip(counter.to_be_bytes()) {
+        *byte ^= count;
+    }
+    let mut aad = header.to_vec();
+    aad.extend(counter.to_le_bytes());
+    aad.push(last as u8);
+
+    (chunk_nonce, aad)
+}
+
+/// Seals everything written to it into chunks, written on to `inner`.
+///
+pub(crate) struct Sealer<W: Write> {
+    inner: W,
+    cipher: Aes256Gcm,
+    nonce: Vec<u8>,
+    header: [u8; HEADER],
+    counter: u64,
+    buf: Zeroizing<Vec<u8>>,
+}
+impl<W: Write> Sealer<W> {
+    ///
+    /// Writes `header` to `inner`, ready to seal with `key` and `nonce`.
+    ///
+    pub fn new(mut inner: W, key: &[u8], nonce: &[u8], header: Header) -> Result<Self, Error> {
+        //
+        let header = header.to_bytes();
+        inner.write_all(&header)?;
+
+        Ok(Sealer {
+            inner,
+            cipher: Aes256Gcm::new(GenericArray::from_slice(key)),
+            nonce: nonce.to_vec(),
+            header,
+            counter: 0,
+            buf: Zeroizing::new(Vec::with_capacity(CHUNK + TAG)),
+        })
+    }
+
+    /// Seals and writes out the first `len` buffered bytes as one chunk.
+    ///
+    fn seal(&mut self, len: usize, last: bool) -> io::Result<()> {
+        //
+        let (nonce, aad) = chunk_params(&self.nonce, &self.header, self.counter, last);
+        let mut chunk = Zeroizing::new(self.buf.drain(..len).collect::<Vec<u8>>());
+        self.cipher
+            .encrypt_in_place(GenericArray::from_slice(&nonce), &aad, &mut *chunk)
+            .map_err(|err| Error::other(format!("Failed to encrypt archive: {}", err)))?;
+        self.inner.write_all(&chunk)?;
+        self.counter += 1;
+
+        Ok(())
+    }
+
+    /// Seals whatever is left as the last chunk, and returns `inner`.
+    ///
+    pub fn finish(mut self) -> io::Result<W> {
+        //
+        let len = self.buf.len();
+        self.seal(len, true)?;
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+}
+impl<W: Write> Write for Sealer<W> {
+    ///
+    /// Buffers `data`, sealing a chunk whenever more than a chunk is waiting.
+    ///
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        //
+        self.buf.extend_from_slice(data);
+        while self.buf.len() > CHUNK {
+            self.seal(CHUNK, false)?;
+        }
+
+        Ok(data.len())
+    }
+
+    /// Flushes `inner`, but never seals a partial chunk.
+    ///
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Opens chunks read from `inner`, checking each before handing it out.
+///
+pub(crate) struct Opener<R: Read> {
+    inner: R,
+    cipher: Aes256Gcm,
+    nonce: Vec<u8>,
+    header: [u8; HEADER],
+    counter: u64,
+    pending: Vec<u8>,
+    plain: Zeroizing<Vec<u8>>,
+    pos: usize,
+    done: bool,
+}
+impl<R: Read> Opener<R> {
+    ///
+    /// Reads the header from `inner`, ready to open with `key` and `nonce`.
+    ///
+    pub fn new(mut inner: R, key: &[u8], nonce: &[u8]) -> Result<(Self, Header), Error> {
+        //
+        let mut header = [0; HEADER];
+        inner
+            .read_exact(&mut header)
+            .map_err(|err| match err.kind() {
+                ErrorKind::UnexpectedEof => {
+                    Error::new(ErrorKind::InvalidData, "Archive is truncated")
+                }
+                _ => err,
+            })?;
+        let parsed = Header::parse(&header)?;
+
+        let opener = Opener {
+            inner,
+            cipher: Aes256Gcm::new(GenericArray::from_slice(key)),
+            nonce: nonce.to_vec(),
+            header,
+            counter: 0,
+            pending: Vec::with_capacity(CHUNK + TAG + 1),
+            plain: Zeroizing::new(Vec::new()),
+            pos: 0,
+            done: false,
+        };
+
+        Ok((opener, parsed))
+    }
+
+    /// Opens the next chunk, returning `false` once past the last.
+    ///
+    fn next_chunk(&mut self) -> io::Result<bool> {
+        //
+        if self.done {
+            return Ok(false);
+        }
+
+        //  Read one byte past a full chunk to learn whether it's the last
+        //
+        let mut byte = [0; 8192];
+        while self.pending.len() <= CHUNK + TAG {
+            let want = (CHUNK + TAG + 1 - self.pending.len()).min(byte.len());
+            match self.inner.read(&mut byte[..want]) {
+                Ok(0) => break,
+                Ok(read) => self.pending.extend_from_slice(&byte[..read]),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        let last = self.pending.len() <= CHUNK + TAG;
+        let len = self.pending.len().min(CHUNK + TAG);
+        if len < TAG {
+            return Err(Error::new(ErrorKind::InvalidData, "Archive is truncated"));
+        }
+
+        let (nonce, aad) = chunk_params(&self.nonce, &self.header, self.counter, last);
+        let mut chunk = Zeroizing::new(self.pending.drain(..len).collect::<Vec<u8>>());
+        self.cipher
+            .decrypt_in_place(GenericArray::from_slice(&nonce), &aad, &mut *chunk)
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Archive is corrupt or truncated at chunk {}", self.counter),
+                )
+            })?;
+
+        self.plain = chunk;
+        self.pos = 0;
+        self.counter += 1;
+        self.done = last;
+
+        Ok(true)
+    }
+}
+impl<R: Read + Seek> Opener<R> {
+    ///
+    /// Moves to the plaintext at `offset`, without opening any chunk before it.
+    ///
+    pub fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        //
+        let index = offset / CHUNK as u64;
+        let start = HEADER as u64 + index * (CHUNK + TAG) as u64;
+        self.inner.seek(SeekFrom::Start(start))?;
+        self.counter = index;
+        self.pending.clear();
+        self.plain = Zeroizing::new(Vec::new());
+        self.pos = 0;
+        self.done = false;
+
+        let skip = (offset % CHUNK as u64) as usize;
+        if skip > 0 {
+            if !self.next_chunk()? || skip > self.plain.len() {
+                return Err(Error::new(ErrorKind::InvalidData, "Archive is truncated"));
+            }
+            self.pos = skip;
+        }
+
+        Ok(())
+    }
+}
+impl<R: Read> Read for Opener<R> {
+    ///
+    /// Hands out plaintext, opening chunks as needed.
+    ///
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        //
+        while self.pos == self.plain.len() {
+            if !self.next_chunk()? {
+                return Ok(0);
+            }
+        }
+        let len = out.len().min(self.plain.len() - self.pos);
+        out[..len].copy_from_slice(&self.plain[self.pos..self.pos + len]);
+        self.pos += len;
+
+        Ok(len)
+    }
+}
+
+/// Compresses a tarball on its way into a `Sealer`.
+///
+pub(crate) enum Encoder<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Xz(XzEncoder<W>),
+}
+impl<W: Write> Encoder<W> {
+    ///
+    /// Starts compressing into `inner` as `header` says.
+    ///
+    pub fn new(inner: W, header: Header) -> io::Result<Self> {
+        //
+        let level = header.level.into();
+        let encoder = match header.compression {
+            Compression::None => Encoder::None(inner),
+            Compression::Gzip => {
+                Encoder::Gzip(GzEncoder::new(inner, flate2::Compression::new(level)))
+            }
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(inner, level as i32)?),
+            Compression::Xz => Encoder::Xz(XzEncoder::new(inner, level)),
+        };
+
+        Ok(encoder)
+    }
+
+    /// Writes out anything still buffered, and returns `inner`.
+    ///
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::None(inner) => Ok(inner),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Xz(encoder) => encoder.finish(),
+        }
+    }
+}
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::None(inner) => inner.write(data),
+            Encoder::Gzip(encoder) => encoder.write(data),
+            Encoder::Zstd(encoder) => encoder.write(data),
+            Encoder::Xz(encoder) => encoder.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::None(inner) => inner.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+            Encoder::Xz(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Checks whether `head`, the start of some data, looks like a format that
+/// is compressed already, so compressing it again would gain nothing.
+///
+pub(crate) fn is_compressed(head: &[u8]) -> bool {
+    //
+    const MAGICS: &[&[u8]] = &[
+        b"\x1f\x8b",           // gzip
+        b"\x28\xb5\x2f\xfd",   // zstd
+        b"\xfd7zXZ\x00",       // xz
+        b"BZh",                // bzip2
+        b"\x04\x22\x4d\x18",   // lz4
+        b"PK\x03\x04",         // zip, and everything built on it
+        b"7z\xbc\xaf\x27\x1c", // 7-Zip
+        b"Rar!\x1a\x07",       // RAR
+        b"\x89PNG",            // PNG
+        b"\xff\xd8\xff",       // JPEG
+        b"GIF8",               // GIF
+        b"OggS",               // Ogg
+        b"fLaC",               // FLAC
+        b"ID3",                // MP3
+        b"\x1a\x45\xdf\xa3",   // Matroska, WebM
+    ];
+    MAGICS.iter().any(|magic| head.starts_with(magic))
+        || head.get(4..8) == Some(b"ftyp")
+        || head.starts_with(b"RIFF") && matches!(head.get(8..12), Some(b"WEBP" | b"AVI "))
+}
+
+/// Returns a reader decompressing `inner` as `compression` says.
+///
+pub(crate) fn decoder<'a, R: Read + 'a>(
+    inner: R,
+    compression: Compression,
+) -> io::Result<Box<dyn Read + 'a>> {
+    //
+    let decoder: Box<dyn Read + 'a> = match compression {
+        Compression::None => Box::new(inner),
+        Compression::Gzip => Box::new(GzDecoder::new(inner)),
+        Compression::Zstd => Box::new(zstd::Decoder::new(inner)?),
+        Compression::Xz => Box::new(XzDecoder::new(inner)),
+    };
+
+    Ok(decoder)
+}
+
+/// Counts the bytes written through it, to find members within a tarball.
+///
+pub(crate) struct Counter<W: Write> {
+    pub inner: W,
+    pub count: u64,
+}
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(data)?;
+        self.count += written as u64;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// One volume of an archive, as recorded in its manifest.
+///
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Volume {
+    pub name: String,
+    pub size: u64,
+}
+
+/// Returns the file name of volume `number` of the archive called `name`.
+///
+fn volume_name(name: &str, number: u32) -> String {
+    format!("{}.{:03}", name, number)
+}
+
+/// Returns the plaintext each full volume holds, given its `size` on disk.
+///
+fn volume_capacity(size: u64) -> u64 {
+    size.saturating_sub(HEADER as u64) / (CHUNK + TAG) as u64 * CHUNK as u64
+}
+
+/// Seals everything written to it into volumes of at most `size` bytes.
+///
+/// Volumes are written to `dir` as `name.001`, `name.002` and so on, each
+/// sealed as a stream of its own. Without a size, everything goes in one.
+///
+pub(crate) struct VolumeWriter {
+    dir: PathBuf,
+    name: String,
+    key: Zeroizing<Vec<u8>>,
+    nonce: Vec<u8>,
+    header: Header,
+    capacity: u64,
+    written: u64,
+    sealer: Option<Sealer<File>>,
+    volumes: Vec<Volume>,
+}
+impl VolumeWriter {
+    ///
+    /// Starts the first volume of the archive called `name` in `dir`.
+    ///
+    pub fn new(
+        dir: &Path,
+        name: &str,
+        key: &[u8],
+        nonce: &[u8],
+        header: Header,
+        size: Option<u64>,
+    ) -> Result<Self, Error> {
+        //
+        let capacity = match size {
+            Some(size) => volume_capacity(size),
+            None => u64::MAX,
+        };
+        if capacity == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Volume size must be at least {} bytes",
+                    HEADER + CHUNK + TAG
+                ),
+            ));
+        }
+
+        let mut writer = VolumeWriter {
+            dir: dir.to_path_buf(),
+            name: name.to_string(),
+            key: Zeroizing::new(key.to_vec()),
+            nonce: nonce.to_vec(),
+            header,
+            capacity,
+            written: 0,
+            sealer: None,
+            volumes: Vec::new(),
+        };
+        writer.open()?;
+
+        Ok(writer)
+    }
+
+    /// Starts the next volume.
+    ///
+    fn open(&mut self) -> io::Result<()> {
+        //
+        let number = self.volumes.len() as u32 + 1;
+        let name = volume_name(&self.name, number);
+        let file = File::create(self.dir.join(&name))?;
+        let header = Header {
+            volume: number,
+            ..self.header
+        };
+
+        self.sealer = Some(Sealer::new(file, &self.key, &self.nonce, header)?);
+        self.volumes.push(Volume { name, size: 0 });
+        self.written = 0;
+
+        Ok(())
+    }
+
+    /// Seals the last chunk of the current volume, and records its size.
+    ///
+    fn close(&mut self) -> io::Result<()> {
+        //
+        if let Some(sealer) = self.sealer.take() {
+            let file = sealer.finish()?;
+            file.sync_all()?;
+            if let Some(volume) = self.volumes.last_mut() {
+                volume.size = file.metadata()?.len();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Closes the last volume, and returns every volume in order.
+    ///
+    pub fn finish(mut self) -> io::Result<Vec<Volume>> {
+        self.close()?;
+        Ok(self.volumes)
+    }
+}
+impl Write for VolumeWriter {
+    ///
+    /// Writes as much of `data` as fits, starting a new volume once one is full.
+    ///
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        //
+        if data.is_empty() {
+            return Ok(0);
+        }
+        if self.written == self.capacity {
+            self.close()?;
+            self.open()?;
+        }
+        let len = (data.len() as u64).min(self.capacity - self.written) as usize;
+        match self.sealer.as_mut() {
+            Some(sealer) => sealer.write_all(&data[..len])?,
+            None => return Err(Error::other("Archive is already finished")),
+        }
+        self.written += len as u64;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.sealer.as_mut() {
+            Some(sealer) => sealer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Opens the volumes of an archive in order, as one stream of plaintext.
+///
+pub(crate) struct VolumeReader {
+    paths: Vec<PathBuf>,
+    key: Zeroizing<Vec<u8>>,
+    nonce: Vec<u8>,
+    capacity: u64,
+    index: usize,
+    opener: Opener<File>,
+}
+impl VolumeReader {
+    ///
+    /// Checks every volume listed in `volumes` against the files in `dir`,
+    /// then opens the first.
+    ///
+    /// Reports a volume that is missing, truncated, or in the place of
+    /// another, before any of the tarball is read.
+    ///
+    pub fn new(
+        dir: &Path,
+        volumes: &[Volume],
+        key: &[u8],
+        nonce: &[u8],
+    ) -> Result<(Self, Header), Error> {
+        //
+        let mut paths = Vec::new();
+        for (index, volume) in volumes.iter().enumerate() {
+            let path = dir.join(&volume.name);
+            let size = match path.metadata() {
+                Ok(metadata) => metadata.len(),
+                Err(_) => {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!("Volume {} is missing", volume.name),
+                    ));
+                }
+            };
+            if size < volume.size {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Volume {} is truncated ({} of {} bytes)",
+                        volume.name, size, volume.size
+                    ),
+                ));
+            }
+
+            let mut header = [0; HEADER];
+            File::open(&path)?.read_exact(&mut header)?;
+            let number = Header::parse(&header)
+                .map_err(|err| Error::new(err.kind(), format!("Volume {}: {}", volume.name, err)))?
+                .volume;
+            if number as usize != index + 1 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Volume {} is out of order: it holds volume {}",
+                        volume.name, number
+                    ),
+                ));
+            } else if size != volume.size {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Volume {} is the wrong size", volume.name),
+                ));
+            }
+            paths.push(path);
+        }
+        let Some(first) = paths.first() else {
+            return Err(Error::new(ErrorKind::InvalidData, "Archive has no volumes"));
+        };
+
+        let (opener, header) = Opener::new(File::open(first)?, key, nonce)?;
+        let capacity = match volumes {
+            [_] => u64::MAX,
+            _ => volume_capacity(volumes[0].size),
+        };
+        let reader = VolumeReader {
+            paths,
+            key: Zeroizing::new(key.to_vec()),
+            nonce: nonce.to_vec(),
+            capacity,
+            index: 0,
+            opener,
+        };
+
+        Ok((reader, header))
+    }
+
+    /// Opens the volume at `index`.
+    ///
+    fn open(&mut self, index: usize) -> io::Result<()> {
+        //
+        let file = File::open(&self.paths[index])?;
+        let (opener, _) = Opener::new(file, &self.key, &self.nonce)?;
+        self.opener = opener;
+        self.index = index;
+
+        Ok(())
+    }
+
+    /// Moves to the plaintext at `offset`, without opening anything before it.
+    ///
+    pub fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        //
+        let index = (offset / self.capacity) as usize;
+        if index >= self.paths.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "Archive is truncated"));
+        }
+        self.open(index)?;
+
+        self.opener.seek_to(offset % self.capacity)
+    }
+
+    /// Returns the file name of the volume being read.
+    ///
+    fn volume(&self) -> String {
+        self.paths[self.index]
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().to_string())
+    }
+}
+impl Read for VolumeReader {
+    ///
+    /// Hands out plaintext, moving on to the next volume at the end of each.
+    ///
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        //
+        loop {
+            let read = self.opener.read(out).map_err(|err| {
+                Error::new(err.kind(), format!("Volume {}: {}", self.volume(), err))
+            })?;
+            if read > 0 || out.is_empty() || self.index + 1 == self.paths.len() {
+                return Ok(read);
+            }
+            self.open(self.index + 1)?;
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//! backup
+//!
+//! The layout of backup repositories: a directory, on a local disk or a
+//! mounted remote, that any number of backups of a stash are made into.
+//!
+//! ```text
+//! <target>/config                 key derivation header (see `bundle`)
+//! <target>/blobs/ab/abcdef…       ciphertext, named by its SHA-256
+//! <target>/snapshots/<id>         one encrypted snapshot per backup
+//! <target>/tmp/                   partial writes, renamed into place
+//! ```
+//!
+//! Blobs are copied from the stash as they are, still encrypted under their
+//! own keys, and only when the repository doesn't already hold them. The
+//! keys themselves, and everything else in the stash's database, go into
+//! the snapshot, which is sealed under a key derived from the repository's
+//! passphrase.
+
+use crate::bundle::{Kdf, HEADER};
+use aes_gcm::{
+    aead::{
+        generic_array::GenericArray, rand_core::RngCore, AeadCore, AeadInPlace, KeyInit, OsRng,
+    },
+    Aes256Gcm,
+};
+use chrono::{DateTime, Datelike, Local, TimeZone};
+use std::{
+    collections::BTreeSet,
+    fs::{self, File, OpenOptions},
+    io::{self, Error, ErrorKind, Read, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+use zeroize::Zeroizing;
+
+const MAGIC: &[u8; 8] = b"STASHBAK";
+
+/// An open backup repository, with its key.
+///
+pub(crate) struct Repo {
+    path: PathBuf,
+    key: Zeroizing<Vec<u8>>,
+}
+impl Repo {
+    ///
+    /// Checks whether `path` holds a backup repository.
+    ///
+    pub(crate) fn exists(path: &Path) -> bool {
+        path.join("config").is_file()
+    }
+
+    /// Opens the repository at `path`, creating it first if there is none.
+    ///
+    pub(crate) fn open_or_create(path: &Path, passphrase: &str) -> Result<Self, Error> {
+        //
+        if Self::exists(path) {
+            return Self::open(path, passphrase);
+        }
+        for dir in ["", "blobs", "snapshots", "tmp"] {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(path.join(dir))?;
+        }
+        let kdf = Kdf::new();
+        let derived = kdf.derive(passphrase)?;
+        let mut config = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path.join("config"))?;
+        config.write_all(&kdf.to_bytes(MAGIC, &derived[32..]))?;
+        config.sync_all()?;
+
+        Ok(Repo {
+            path: path.to_path_buf(),
+            key: Zeroizing::new(derived[..32].to_vec()),
+        })
+    }
+
+    /// Opens the repository at `path`, checking `passphrase` against it.
+    ///
+    pub(crate) fn open(path: &Path, passphrase: &str) -> Result<Self, Error> {
+        //
+        let mut header = [0; HEADER];
+        File::open(path.join("config"))
+            .and_then(|mut file| file.read_exact(&mut header))
+            .map_err(|err| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("No backup repository at {}: {}", path.display(), err),
+                )
+            })?;
+        let (kdf, verifier) = Kdf::parse(&header, MAGIC, "backup repository")?;
+        let derived = kdf.derive(passphrase)?;
+        if derived[32..] != verifier {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "Wrong passphrase for backup repository",
+            ));
+        }
+
+        Ok(Repo {
+            path: path.to_path_buf(),
+            key: Zeroizing::new(derived[..32].to_vec()),
+        })
+    }
+
+    /// Returns where the blob with `hash` is kept.
+    ///
+    pub(crate) fn blob_path(&self, hash: &str) -> PathBuf {
+        self.path.join("blobs").join(&hash[..2]).join(hash)
+    }
+
+    /// Copies the file at `src` in as the blob `hash`, unless it's already there.
+    ///
+    /// Returns whether anything was copied.
+    ///
+    pub(crate) fn put_blob(&self, src: &Path, hash: &str) -> Result<bool, Error> {
+        //
+        let path = self.blob_path(hash);
+        if path.is_file() {
+            return Ok(false);
+        }
+        let mut tmp = self.tmp_file()?;
+        io::copy(&mut File::open(src)?, &mut tmp.1)?;
+        tmp.1.sync_all()?;
+        self.place(&tmp.0, &path)?;
+
+        Ok(true)
+    }
+
+    /// Lists the hashes of every blob in the repository.
+    ///
+    pub(crate) fn blobs(&self) -> Result<Vec<String>, Error> {
+        //
+        let mut blobs = Vec::new();
+        for dir in fs::read_dir(self.path.join("blobs"))? {
+            for blob in fs::read_dir(dir?.path())? {
+                blobs.push(blob?.file_name().to_string_lossy().to_string());
+            }
+        }
+
+        Ok(blobs)
+    }
+
+    /// Removes the blob `hash`, along with its directory once that's empty.
+    ///
+    pub(crate) fn remove_blob(&self, hash: &str) -> Result<(), Error> {
+        //
+        let path = self.blob_path(hash);
+        fs::remove_file(&path)?;
+        if let Some(dir) = path.parent() {
+            let _ = fs::remove_dir(dir);
+        }
+
+        Ok(())
+    }
+
+    /// Lists the ids of every snapshot, oldest first.
+    ///
+    pub(crate) fn snapshots(&self) -> Result<Vec<String>, Error> {
+        //
+        let mut ids = Vec::new();
+        for snapshot in fs::read_dir(self.path.join("snapshots"))? {
+            ids.push(snapshot?.file_name().to_string_lossy().to_string());
+        }
+        ids.sort();
+
+        Ok(ids)
+    }
+
+    /// Encrypts `plaintext` into a new snapshot taken at `created`.
+    ///
+    /// Snapshots are named after the local time they were taken, which keeps
+    /// them in order. Returns the id of the new snapshot.
+    ///
+    pub(crate) fn write_snapshot(&self, plaintext: &[u8], created: u64) -> Result<String, Error> {
+        //
+        let time = Local
+            .timestamp_opt(created as i64, 0)
+            .single()
+            .unwrap_or_else(Local::now)
+            .format("%Y-%m-%dT%H%M%S")
+            .to_string();
+        let mut id = time.clone();
+        let mut n = 1;
+        while self.path.join("snapshots").join(&id).exists() {
+            n += 1;
+            id = format!("{}-{}", time, n);
+        }
+
+        //  Seal the snapshot to its id, so it can't pass for another
+        //
+        let nonce = Aes256Gcm::generate_nonce(OsRng);
+        let mut buffer = plaintext.to_vec();
+        Aes256Gcm::new(GenericArray::from_slice(&self.key))
+            .encrypt_in_place(&nonce, id.as_bytes(), &mut buffer)
+            .map_err(|err| Error::other(format!("Failed to encrypt snapshot: {}", err)))?;
+        let mut tmp = self.tmp_file()?;
+        tmp.1.write_all(&nonce)?;
+        tmp.1.write_all(&buffer)?;
+        tmp.1.sync_all()?;
+        self.place(&tmp.0, &self.path.join("snapshots").join(&id))?;
+
+        Ok(id)
+    }
+
+    /// Decrypts the snapshot `id`.
+    ///
+    pub(crate) fn read_snapshot(&self, id: &str) -> Result<Zeroizing<Vec<u8>>, Error> {
+        //
+        let mut buffer = Zeroizing::new(fs::read(self.path.join("snapshots").join(id))?);
+        if buffer.len() < 12 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Snapshot {} is truncated", id),
+            ));
+        }
+        let nonce = GenericArray::clone_from_slice(&buffer[..12]);
+        buffer.drain(..12);
+        Aes256Gcm::new(GenericArray::from_slice(&self.key))
+            .decrypt_in_place(&nonce, id.as_bytes(), &mut *buffer)
+            .map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Failed to decrypt snapshot {}: {}", id, err),
+                )
+            })?;
+
+        Ok(buffer)
+    }
+
+    /// Removes the snapshot `id`, leaving its blobs for `gc` to collect.
+    ///
+    pub(crate) fn remove_snapshot(&self, id: &str) -> Result<(), Error> {
+        fs::remove_file(self.path.join("snapshots").join(id))
+    }
+
+    /// Creates a file to write to under `tmp/`, with a random name.
+    ///
+    fn tmp_file(&self) -> Result<(PathBuf, File), Error> {
+        //
+        let dir = self.path.join("tmp");
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)?;
+        let mut name = [0; 8];
+        OsRng.fill_bytes(&mut name);
+        let path = dir.join(
+            name.iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>(),
+        );
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+
+        Ok((path, file))
+    }
+
+    /// Moves a finished file from `tmp/` to `path`.
+    ///
+    fn place(&self, tmp: &Path, path: &Path) -> Result<(), Error> {
+        //
+        if let Some(dir) = path.parent() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)?;
+        }
+        fs::rename(tmp, path).inspect_err(|_| {
+            let _ = fs::remove_file(tmp);
+        })
+    }
+}
+
+/// Finds the period, such as a day or a week, that a time falls in.
+///
+type Period = fn(&DateTime<Local>) -> (i32, u32);
+
+/// Picks the snapshots to keep out of `snapshots`, given as `(id, created)`
+/// pairs, oldest first.
+///
+/// The newest snapshot of each of the last `daily` days, and of each of the
+/// last `weekly` ISO weeks, that have any are kept, as is the newest of all.
+/// With neither, every snapshot is kept.
+///
+pub(crate) fn retain(
+    snapshots: &[(String, u64)],
+    daily: Option<usize>,
+    weekly: Option<usize>,
+) -> BTreeSet<String> {
+    //
+    if daily.is_none() && weekly.is_none() {
+        return snapshots.iter().map(|(id, _)| id.clone()).collect();
+    }
+    let mut keep: BTreeSet<String> = snapshots
+        .last()
+        .map(|(id, _)| id.clone())
+        .into_iter()
+        .collect();
+
+    //  Walk back from the newest, keeping the first seen in each period
+    //
+    let periods: [(Option<usize>, Period); 2] = [
+        (daily, |time| (time.year(), time.ordinal())),
+        (weekly, |time| {
+            (time.iso_week().year(), time.iso_week().week())
+        }),
+    ];
+    for (count, period) in periods {
+        let Some(count) = count else { continue };
+        let mut seen = BTreeSet::new();
+        for (id, created) in snapshots.iter().rev() {
+            let Some(time) = Local.timestamp_opt(*created as i64, 0).single() else {
+                continue;
+            };
+            if seen.len() == count && !seen.contains(&period(&time)) {
+                break;
+            }
+            if seen.insert(period(&time)) {
+                keep.insert(id.clone());
+            }
+        }
+    }
+
+    keep
+}
@@ -0,0 +1,175 @@
+//! bundle
+//!
+//! The file format of portable bundles: a short header holding what it
+//! takes to derive a key from the bundle's passphrase, followed by a
+//! tarball sealed in chunks just as an archive volume is.
+//!
+//! The key is derived with Argon2id, whose parameters are recorded so they
+//! can be raised later without breaking older bundles. A second half of the
+//! derived bytes is kept as a verifier, so a wrong passphrase is reported as
+//! such rather than as a corrupt bundle. Backup repositories keep the same
+//! header, under their own magic, as their `config`.
+
+use crate::archive::{Compression, Header, Opener, Sealer};
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use argon2::{Algorithm, Argon2, Params, Version};
+use std::{
+    fs::{File, OpenOptions},
+    io::{Error, ErrorKind, Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+use zeroize::Zeroizing;
+
+const MAGIC: &[u8; 8] = b"STASHBDL";
+const VERSION: u8 = 1;
+pub(crate) const HEADER: usize = 84;
+
+/// Argon2id memory cost in KiB, passes and lanes for new bundles.
+///
+const COSTS: (u32, u32, u32) = (64 * 1024, 3, 1);
+
+/// What it takes to turn a passphrase back into a key.
+///
+pub(crate) struct Kdf {
+    costs: (u32, u32, u32),
+    salt: [u8; 16],
+    pub(crate) nonce: [u8; 12],
+}
+impl Kdf {
+    ///
+    /// Creates parameters for a new key, with a random salt and nonce.
+    ///
+    pub(crate) fn new() -> Self {
+        let mut kdf = Kdf {
+            costs: COSTS,
+            salt: [0; 16],
+            nonce: [0; 12],
+        };
+        OsRng.fill_bytes(&mut kdf.salt);
+        OsRng.fill_bytes(&mut kdf.nonce);
+
+        kdf
+    }
+
+    /// Returns the key and verifier derived from `passphrase`.
+    ///
+    pub(crate) fn derive(&self, passphrase: &str) -> Result<Zeroizing<[u8; 64]>, Error> {
+        //
+        let (memory, passes, lanes) = self.costs;
+        let params = Params::new(memory, passes, lanes, Some(64)).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid bundle key parameters: {}", err),
+            )
+        })?;
+        let mut derived = Zeroizing::new([0; 64]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, &mut *derived)
+            .map_err(|err| Error::other(format!("Failed to derive bundle key: {}", err)))?;
+
+        Ok(derived)
+    }
+
+    /// Encodes the header as it is stored, with `magic` and `verifier`.
+    ///
+    pub(crate) fn to_bytes(&self, magic: &[u8; 8], verifier: &[u8]) -> [u8; HEADER] {
+        let mut bytes = [0; HEADER];
+        bytes[..8].copy_from_slice(magic);
+        bytes[8] = VERSION;
+        bytes[12..16].copy_from_slice(&self.costs.0.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.costs.1.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.costs.2.to_le_bytes());
+        bytes[24..40].copy_from_slice(&self.salt);
+        bytes[40..52].copy_from_slice(&self.nonce);
+        bytes[52..].copy_from_slice(verifier);
+
+        bytes
+    }
+
+    /// Decodes a stored header, along with its verifier.
+    ///
+    /// `what` names the kind of file expected, for errors.
+    ///
+    pub(crate) fn parse(
+        bytes: &[u8; HEADER],
+        magic: &[u8; 8],
+        what: &str,
+    ) -> Result<(Self, [u8; 32]), Error> {
+        //
+        if &bytes[..8] != magic {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Not a stash {}", what),
+            ));
+        } else if bytes[8] != VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported {} format version {}", what, bytes[8]),
+            ));
+        }
+        let word = |at: usize| {
+            u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+        let mut kdf = Kdf {
+            costs: (word(12), word(16), word(20)),
+            salt: [0; 16],
+            nonce: [0; 12],
+        };
+        kdf.salt.copy_from_slice(&bytes[24..40]);
+        kdf.nonce.copy_from_slice(&bytes[40..52]);
+        let mut verifier = [0; 32];
+        verifier.copy_from_slice(&bytes[52..]);
+
+        Ok((kdf, verifier))
+    }
+}
+
+/// Creates a new bundle at `path`, ready to seal a tarball into.
+///
+/// Refuses to replace an existing file.
+///
+pub(crate) fn create(path: &Path, passphrase: &str) -> Result<Sealer<File>, Error> {
+    //
+    let kdf = Kdf::new();
+    let derived = kdf.derive(passphrase)?;
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(&kdf.to_bytes(MAGIC, &derived[32..]))?;
+    let header = Header {
+        compression: Compression::None,
+        level: 0,
+        volume: 1,
+    };
+
+    Sealer::new(file, &derived[..32], &kdf.nonce, header)
+}
+
+/// Opens the bundle at `path`, checking `passphrase` before anything else.
+///
+pub(crate) fn open(path: &Path, passphrase: &str) -> Result<Opener<File>, Error> {
+    //
+    let mut file = File::open(path)?;
+    let mut header = [0; HEADER];
+    file.read_exact(&mut header)
+        .map_err(|err| match err.kind() {
+            ErrorKind::UnexpectedEof => Error::new(ErrorKind::InvalidData, "Not a stash bundle"),
+            _ => err,
+        })?;
+    let (kdf, verifier) = Kdf::parse(&header, MAGIC, "bundle")?;
+    let derived = kdf.derive(passphrase)?;
+    if derived[32..] != verifier {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "Wrong passphrase for bundle",
+        ));
+    }
+
+    let (opener, _) = Opener::new(file, &derived[..32], &kdf.nonce)?;
+
+    Ok(opener)
+}
@@ -0,0 +1,382 @@
+//! chunks
+//!
+//! The store behind chunked entries. Plaintext is cut into chunks wherever
+//! its contents say so (content-defined chunking, with FastCDC), so an edit
+//! only changes the chunks around it, and the rest are shared with every
+//! other file and revision that holds them.
+//!
+//! Each chunk is named by an HMAC-SHA256 of its plaintext under the stash's
+//! chunk key, so names give nothing away to anyone without the key, and is
+//! sealed with AES-256-GCM under a random key of its own, kept in the
+//! database under its name. Once a chunk is no longer needed, dropping its
+//! key shreds it, however many copies of its ciphertext are left around.
+//! Chunks live under `.chunks/`, and a chunked entry leaves just a short stub
+//! in place of its ciphertext, with its list of chunks kept in the database.
+
+use crate::fuse::Contents;
+use aes_gcm::{
+    aead::{generic_array::GenericArray, rand_core::RngCore, AeadInPlace, KeyInit, OsRng},
+    Aes256Gcm,
+};
+use fastcdc::v2020::FastCDC;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sled::Tree;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{Error, ErrorKind, Read},
+    path::{Path, PathBuf},
+};
+use zeroize::{Zeroize, Zeroizing};
+
+/// What a chunked entry leaves in place of its ciphertext. It is shorter
+/// than any ciphertext can be, so the two are never mistaken for each other.
+///
+pub(crate) const STUB: &[u8; 8] = b"STASHCDC";
+
+/// Smallest, average and largest chunk sizes.
+///
+const SIZES: (u32, u32, u32) = (16 * 1024, 64 * 1024, 256 * 1024);
+
+/// Checks whether the stored file at `path` is a stub for a chunked entry.
+///
+pub(crate) fn is_stub(path: &Path) -> bool {
+    //
+    let mut bytes = [0; 9];
+    let Ok(mut file) = File::open(path) else {
+        return false;
+    };
+    matches!(file.read(&mut bytes), Ok(8)) && &bytes[..8] == STUB
+}
+
+/// Where the key of each chunk is kept: in the database, or, once the
+/// database has been let go of, in memory.
+///
+#[derive(Clone)]
+enum Keys {
+    Tree(Tree),
+    Loaded(HashMap<String, Zeroizing<Vec<u8>>>),
+}
+
+/// A directory of chunks, with the key they are named under and the keys
+/// they are sealed under.
+///
+#[derive(Clone)]
+pub(crate) struct ChunkStore {
+    dir: PathBuf,
+    key: Zeroizing<Vec<u8>>,
+    keys: Keys,
+}
+impl ChunkStore {
+    ///
+    /// Opens the chunks under `dir`, named under `key`, with the key of each
+    /// chunk kept in `keys` under its id.
+    ///
+    pub(crate) fn new(dir: &Path, key: &[u8], keys: Tree) -> Self {
+        ChunkStore {
+            dir: dir.to_path_buf(),
+            key: Zeroizing::new(key.to_vec()),
+            keys: Keys::Tree(keys),
+        }
+    }
+
+    /// Returns a new random key, for naming or sealing chunks.
+    ///
+    pub(crate) fn generate_key() -> Zeroizing<Vec<u8>> {
+        let mut key = Zeroizing::new(vec![0; 32]);
+        OsRng.fill_bytes(&mut key);
+        key
+    }
+
+    /// Returns a copy of the store that can only read the chunks `ids`, with
+    /// their keys loaded so it doesn't hold on to the database.
+    ///
+    pub(crate) fn detach<'a, I: IntoIterator<Item = &'a String>>(
+        &self,
+        ids: I,
+    ) -> Result<Self, Error> {
+        //
+        let mut keys = HashMap::new();
+        for id in ids {
+            keys.insert(id.clone(), self.chunk_key(id)?);
+        }
+
+        Ok(ChunkStore {
+            dir: self.dir.clone(),
+            key: self.key.clone(),
+            keys: Keys::Loaded(keys),
+        })
+    }
+
+    /// Returns the key the chunk `id` is sealed under.
+    ///
+    pub(crate) fn chunk_key(&self, id: &str) -> Result<Zeroizing<Vec<u8>>, Error> {
+        //
+        check_id(id)?;
+        let key = match &self.keys {
+            Keys::Tree(tree) => tree
+                .get(id)
+                .map_err(|err| Error::other(format!("Failed to read chunk key: {}", err)))?
+                .map(|key| Zeroizing::new(key.to_vec())),
+            Keys::Loaded(keys) => keys.get(id).cloned(),
+        };
+
+        key.filter(|key| key.len() == 32).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("Key of chunk {} not found", id),
+            )
+        })
+    }
+
+    /// Cuts `plaintext` into chunks and stores any that aren't there yet.
+    ///
+    /// Returns the chunk ids, in order.
+    ///
+    pub(crate) fn put(&self, plaintext: &[u8]) -> Result<Vec<String>, Error> {
+        //
+        let (min, avg, max) = SIZES;
+        FastCDC::new(plaintext, min, avg, max)
+            .map(|chunk| self.put_chunk(&plaintext[chunk.offset..chunk.offset + chunk.length]))
+            .collect()
+    }
+
+    /// Stores a single chunk unless it's already there, returning its id.
+    ///
+    pub(crate) fn put_chunk(&self, chunk: &[u8]) -> Result<String, Error> {
+        //
+        let Keys::Tree(keys) = &self.keys else {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "Chunk store is read-only",
+            ));
+        };
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key)
+            .map_err(|err| Error::new(ErrorKind::InvalidInput, err.to_string()))?;
+        mac.update(chunk);
+        let id = format!("{:x}", mac.finalize().into_bytes());
+        let path = self.chunk_path(&id)?;
+        if path.is_file() && self.chunk_key(&id).is_ok() {
+            return Ok(id);
+        }
+
+        //  Seal the chunk under a key of its own, recorded before the chunk
+        //  is, and only give the chunk its name once it's whole
+        //
+        let key = Self::generate_key();
+        let mut buffer = chunk.to_vec();
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(&key));
+        if let Err(err) =
+            cipher.encrypt_in_place(GenericArray::from_slice(&nonce(&id)), b"", &mut buffer)
+        {
+            buffer.zeroize();
+            return Err(Error::other(format!("Failed to encrypt chunk: {}", err)));
+        }
+        keys.insert(id.as_str(), key.as_slice())
+            .map_err(|err| Error::other(format!("Failed to add chunk key to database: {}", err)))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, &buffer).and_then(|_| fs::rename(&tmp_path, &path))?;
+
+        Ok(id)
+    }
+
+    /// Reassembles the plaintext of the chunks `ids`.
+    ///
+    pub(crate) fn get(&self, ids: &[String]) -> Result<Zeroizing<Vec<u8>>, Error> {
+        //
+        let mut plaintext = Zeroizing::new(Vec::new());
+        for id in ids {
+            let ciphertext = fs::read(self.chunk_path(id)?).map_err(|err| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("Failed to read chunk {}: {}", id, err),
+                )
+            })?;
+            plaintext.extend_from_slice(&self.open_chunk(id, ciphertext)?);
+        }
+
+        Ok(plaintext)
+    }
+
+    /// Opens the chunks `ids` to be read a range at a time.
+    ///
+    pub(crate) fn reader(&self, ids: &[String]) -> Result<Reader, Error> {
+        //
+        let mut chunks = Vec::with_capacity(ids.len());
+        let mut end = 0;
+        for id in ids {
+            let len = fs::metadata(self.chunk_path(id)?)
+                .map_err(|err| {
+                    Error::new(
+                        ErrorKind::NotFound,
+                        format!("Failed to read chunk {}: {}", id, err),
+                    )
+                })?
+                .len();
+            end += len.saturating_sub(16);
+            chunks.push((id.clone(), end));
+        }
+
+        Ok(Reader {
+            store: self.clone(),
+            chunks,
+            last: None,
+            offset: 0,
+        })
+    }
+
+    /// Decrypts `ciphertext`, stored as the chunk `id`.
+    ///
+    pub(crate) fn open_chunk(
+        &self,
+        id: &str,
+        ciphertext: Vec<u8>,
+    ) -> Result<Zeroizing<Vec<u8>>, Error> {
+        open(id, &self.chunk_key(id)?, ciphertext)
+    }
+
+    /// Returns where the chunk `id` is kept.
+    ///
+    pub(crate) fn chunk_path(&self, id: &str) -> Result<PathBuf, Error> {
+        check_id(id)?;
+        Ok(self.dir.join(&id[..2]).join(id))
+    }
+
+    /// Removes every chunk not in `referenced`, and its key, along with
+    /// leftovers from interrupted writes.
+    ///
+    /// Returns how many chunks were removed, and how many bytes they took.
+    ///
+    pub(crate) fn gc(&self, referenced: &HashSet<String>) -> Result<(usize, u64), Error> {
+        //
+        if let Keys::Tree(keys) = &self.keys {
+            for id in keys.iter().keys() {
+                let id = id?;
+                let name = String::from_utf8_lossy(&id);
+                if check_id(&name).is_ok() && !referenced.contains(&*name) {
+                    keys.remove(&id).map_err(|err| {
+                        Error::other(format!("Failed to remove chunk key: {}", err))
+                    })?;
+                }
+            }
+        }
+
+        let (mut count, mut bytes) = (0, 0);
+        let Ok(dirs) = fs::read_dir(&self.dir) else {
+            return Ok((0, 0));
+        };
+        for dir in dirs {
+            let dir = dir?.path();
+            for chunk in fs::read_dir(&dir)? {
+                let chunk = chunk?;
+                let name = chunk.file_name().to_string_lossy().to_string();
+                if !referenced.contains(&name) {
+                    bytes += chunk.metadata()?.len();
+                    fs::remove_file(chunk.path())?;
+                    count += usize::from(!name.ends_with(".tmp"));
+                }
+            }
+            let _ = fs::remove_dir(&dir);
+        }
+
+        Ok((count, bytes))
+    }
+}
+
+/// Refuses anything but a well-formed chunk id, which is also a safe path.
+///
+fn check_id(id: &str) -> Result<(), Error> {
+    //
+    if id.len() == 64 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid chunk id: {}", id),
+        ))
+    }
+}
+
+/// Decrypts `ciphertext`, stored as the chunk `id` and sealed under `key`.
+///
+pub(crate) fn open(id: &str, key: &[u8], ciphertext: Vec<u8>) -> Result<Zeroizing<Vec<u8>>, Error> {
+    //
+    check_id(id)?;
+    if key.len() != 32 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid key for chunk {}", id),
+        ));
+    }
+    let mut buffer = Zeroizing::new(ciphertext);
+    Aes256Gcm::new(GenericArray::from_slice(key))
+        .decrypt_in_place(GenericArray::from_slice(&nonce(id)), b"", &mut *buffer)
+        .map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Failed to decrypt chunk {}: {}", id, err),
+            )
+        })?;
+
+    Ok(buffer)
+}
+
+/// Derives a chunk's nonce from the first 12 bytes of its id.
+///
+fn nonce(id: &str) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    for (i, byte) in nonce.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&id[i * 2..i * 2 + 2], 16).unwrap_or_default();
+    }
+    nonce
+}
+
+/// A chunked file opened for reading. Only the chunks under a range are
+/// decrypted to read it, and the last one is kept for the next range. Read
+/// in order, it holds one chunk at a time.
+///
+pub(crate) struct Reader {
+    store: ChunkStore,
+    //  Each chunk's id, and where its plaintext ends in the file
+    //
+    chunks: Vec<(String, u64)>,
+    last: Option<(usize, Zeroizing<Vec<u8>>)>,
+    offset: u64,
+}
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let bytes = self.read_at(self.offset, buf.len())?;
+        buf[..bytes.len()].copy_from_slice(&bytes);
+        self.offset += bytes.len() as u64;
+        Ok(bytes.len())
+    }
+}
+impl Contents for Reader {
+    //
+    fn read_at(&mut self, offset: u64, size: usize) -> Result<Zeroizing<Vec<u8>>, Error> {
+        //
+        let mut out = Zeroizing::new(Vec::with_capacity(size));
+        let mut index = self.chunks.partition_point(|(_, end)| *end <= offset);
+        let mut offset = offset;
+        while out.len() < size && index < self.chunks.len() {
+            if self.last.as_ref().map(|(last, _)| *last) != Some(index) {
+                let id = &self.chunks[index].0;
+                let ciphertext = fs::read(self.store.chunk_path(id)?)?;
+                self.last = Some((index, self.store.open_chunk(id, ciphertext)?));
+            }
+            let (_, plaintext) = self.last.as_ref().unwrap();
+            let start = index.checked_sub(1).map_or(0, |prev| self.chunks[prev].1);
+            let from = ((offset - start) as usize).min(plaintext.len());
+            let to = plaintext.len().min(from + size - out.len());
+            out.extend_from_slice(&plaintext[from..to]);
+            offset = self.chunks[index].1;
+            index += 1;
+        }
+
+        Ok(out)
+    }
+}
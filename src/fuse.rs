@@ -0,0 +1,465 @@
+//! fuse
+//!
+//! A read-only tree of files whose contents are produced on demand, served
+//! to the kernel through `fuser`.
+//!
+//! The file system is mounted read-only, so the kernel itself refuses every
+//! change, and requests from anyone but the owner of the mount are refused
+//! with `EACCES`.
+
+use fuser::{
+    Config, Errno, FileAttr, FileHandle, FileType, Filesystem, FopenFlags, Generation, INodeNo,
+    LockOwner, MountOption, OpenAccMode, OpenFlags, ReplyAttr, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyOpen, Request, Session, SessionACL,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{CString, OsStr},
+    fs,
+    io::{Error, ErrorKind},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Mutex,
+    time::{Duration, UNIX_EPOCH},
+};
+use zeroize::Zeroizing;
+
+const ROOT: u64 = 1;
+const TTL: Duration = Duration::from_secs(1);
+
+/// A file to expose, named by its path within the mount.
+///
+pub(crate) struct FileInfo {
+    pub name: String,
+    pub size: u64,
+    pub modified: u64,
+    pub symlink: bool,
+}
+
+/// The contents of an open file, produced a range at a time.
+///
+pub(crate) trait Contents: Send {
+    ///
+    /// Returns up to `size` bytes from `offset` on.
+    ///
+    fn read_at(&mut self, offset: u64, size: usize) -> Result<Zeroizing<Vec<u8>>, Error>;
+}
+
+impl Contents for Zeroizing<Vec<u8>> {
+    fn read_at(&mut self, offset: u64, size: usize) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let start = (offset as usize).min(self.len());
+        let end = start.saturating_add(size).min(self.len());
+        Ok(Zeroizing::new(self[start..end].to_vec()))
+    }
+}
+
+/// Opens a file by its name, for `Contents` to be read from.
+///
+type Open = Box<dyn Fn(&str) -> Result<Box<dyn Contents>, Error> + Send + Sync>;
+
+/// A node in the mounted tree, numbered by its position plus one.
+///
+enum Node {
+    Dir {
+        parent: u64,
+        children: BTreeMap<String, u64>,
+    },
+    File(FileInfo),
+}
+
+/// The file system itself, as served to the kernel.
+///
+struct View {
+    nodes: Vec<Node>,
+    owner: (u32, u32),
+    open: Open,
+    handles: Mutex<HashMap<u64, Box<dyn Contents>>>,
+}
+
+/// A mounted view, not yet being served.
+///
+pub(crate) struct Mount(Session<View>);
+
+/// Mounts `files` at `dir`, accessible only to `owner`.
+///
+/// `open` is called with a file's name to produce its contents when it is
+/// opened, or its target when it is a symlink. Contents are dropped, and so
+/// wiped, once the file is closed.
+///
+/// Mounts directly when allowed to, and otherwise asks `fusermount3` (or
+/// `fusermount`) to do it.
+///
+pub(crate) fn mount<F>(
+    dir: &Path,
+    files: Vec<FileInfo>,
+    owner: (u32, u32),
+    open: F,
+) -> Result<Mount, Error>
+where
+    F: Fn(&str) -> Result<Box<dyn Contents>, Error> + Send + Sync + 'static,
+{
+    //
+    let view = View {
+        nodes: build(files),
+        owner,
+        open: Box::new(open),
+        handles: Mutex::new(HashMap::new()),
+    };
+
+    //  The kernel only lets others in with `allow_other`, so ask for it when
+    //  the owner isn't who mounts, and leave it to `View` to keep them out
+    //
+    let mut config = Config::default();
+    config.mount_options = vec![
+        MountOption::FSName("stash".to_string()),
+        MountOption::Subtype("stash".to_string()),
+        MountOption::RO,
+        MountOption::NoSuid,
+        MountOption::NoDev,
+        MountOption::NoExec,
+        MountOption::DefaultPermissions,
+    ];
+    // SAFETY: `getuid` can't fail.
+    if owner.0 != unsafe { libc::getuid() } {
+        config.acl = SessionACL::All;
+    }
+
+    Session::new(view, dir, &config)
+        .map(Mount)
+        .map_err(|err| Error::other(format!("Failed to mount {}: {}", dir.display(), err)))
+}
+impl Mount {
+    ///
+    /// Serves the view until it is unmounted.
+    ///
+    pub(crate) fn serve(self) -> Result<(), Error> {
+        self.0.run()
+    }
+}
+
+impl View {
+    ///
+    /// Returns the node `ino`, as long as `req` comes from the owner.
+    ///
+    fn node(&self, req: &Request, ino: INodeNo) -> Result<&Node, Errno> {
+        //
+        if req.uid() != self.owner.0 {
+            return Err(Errno::EACCES);
+        }
+        self.nodes
+            .get((ino.0 as usize).wrapping_sub(1))
+            .ok_or(Errno::ENOENT)
+    }
+
+    /// Returns the attributes of node `ino`.
+    ///
+    fn attr(&self, ino: u64) -> FileAttr {
+        //
+        let (size, modified, kind, perm, nlink) = match &self.nodes[ino as usize - 1] {
+            Node::Dir { children, .. } => {
+                (0, 0, FileType::Directory, 0o500, 2 + children.len() as u32)
+            }
+            Node::File(info) if info.symlink => {
+                (info.size, info.modified, FileType::Symlink, 0o777, 1)
+            }
+            Node::File(info) => (info.size, info.modified, FileType::RegularFile, 0o400, 1),
+        };
+        let time = UNIX_EPOCH + Duration::from_secs(modified);
+
+        FileAttr {
+            ino: INodeNo(ino),
+            size,
+            blocks: size.div_ceil(512),
+            atime: time,
+            mtime: time,
+            ctime: time,
+            crtime: time,
+            kind,
+            perm,
+            nlink,
+            uid: self.owner.0,
+            gid: self.owner.1,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        }
+    }
+
+    /// Opens `node`, which must be a file.
+    ///
+    fn contents(&self, node: &Node) -> Result<Box<dyn Contents>, Errno> {
+        //
+        let Node::File(info) = node else {
+            return Err(Errno::EISDIR);
+        };
+        (self.open)(&info.name).map_err(|_| Errno::EIO)
+    }
+}
+
+impl Filesystem for View {
+    //
+    fn lookup(&self, req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
+        //
+        let child = match self.node(req, parent) {
+            Ok(Node::Dir { children, .. }) => name
+                .to_str()
+                .and_then(|name| children.get(name))
+                .ok_or(Errno::ENOENT),
+            Ok(Node::File(_)) => Err(Errno::ENOTDIR),
+            Err(errno) => Err(errno),
+        };
+        match child {
+            Ok(&child) => reply.entry(&TTL, &self.attr(child), Generation(0)),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn getattr(&self, req: &Request, ino: INodeNo, _fh: Option<FileHandle>, reply: ReplyAttr) {
+        match self.node(req, ino) {
+            Ok(_) => reply.attr(&TTL, &self.attr(ino.0)),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn readlink(&self, req: &Request, ino: INodeNo, reply: ReplyData) {
+        //
+        let target = self
+            .node(req, ino)
+            .and_then(|node| self.contents(node))
+            .and_then(|mut contents| {
+                contents
+                    .read_at(0, libc::PATH_MAX as usize)
+                    .map_err(|_| Errno::EIO)
+            });
+        match target {
+            Ok(target) => reply.data(&target),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn open(&self, req: &Request, ino: INodeNo, flags: OpenFlags, reply: ReplyOpen) {
+        //
+        if flags.acc_mode() != OpenAccMode::O_RDONLY {
+            return reply.error(Errno::EROFS);
+        }
+        let contents = match self.node(req, ino).and_then(|node| self.contents(node)) {
+            Ok(contents) => contents,
+            Err(errno) => return reply.error(errno),
+        };
+
+        let mut handles = self.handles.lock().unwrap();
+        let fh = (1..).find(|fh| !handles.contains_key(fh)).unwrap();
+        handles.insert(fh, contents);
+        reply.opened(FileHandle(fh), FopenFlags::empty());
+    }
+
+    fn read(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        fh: FileHandle,
+        offset: u64,
+        size: u32,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        reply: ReplyData,
+    ) {
+        //
+        let mut handles = self.handles.lock().unwrap();
+        let data = match handles.get_mut(&fh.0) {
+            Some(contents) => contents
+                .read_at(offset, size as usize)
+                .map_err(|_| Errno::EIO),
+            None => Err(Errno::EBADF),
+        };
+        match data {
+            Ok(data) => reply.data(&data),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn release(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        fh: FileHandle,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.handles.lock().unwrap().remove(&fh.0);
+        reply.ok();
+    }
+
+    fn readdir(
+        &self,
+        req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        mut reply: ReplyDirectory,
+    ) {
+        //
+        let (parent, children) = match self.node(req, ino) {
+            Ok(Node::Dir { parent, children }) => (*parent, children),
+            Ok(Node::File(_)) => return reply.error(Errno::ENOTDIR),
+            Err(errno) => return reply.error(errno),
+        };
+        let dots = [(".", ino.0), ("..", parent)];
+        let all = dots
+            .into_iter()
+            .chain(children.iter().map(|(name, &child)| (name.as_str(), child)));
+
+        for (index, (name, child)) in all.enumerate().skip(offset as usize) {
+            let kind = self.attr(child).kind;
+            if reply.add(INodeNo(child), index as u64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// Detaches the FUSE file system mounted at `dir`.
+///
+pub(crate) fn unmount(dir: &Path) -> Result<(), Error> {
+    //
+    let target = c_path(dir)?;
+    // SAFETY: `target` is NUL-terminated and outlives the call.
+    if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } == 0 {
+        return Ok(());
+    }
+    let err = Error::last_os_error();
+    if err.raw_os_error() != Some(libc::EPERM) {
+        return Err(Error::other(format!(
+            "Failed to unmount {}: {}",
+            dir.display(),
+            err
+        )));
+    }
+
+    //  Unprivileged mounts have to go back through the helper
+    //
+    for helper in ["fusermount3", "fusermount"] {
+        if let Ok(status) = Command::new(helper)
+            .args(["-u", "-z", "--"])
+            .arg(dir)
+            .status()
+        {
+            if status.success() {
+                return Ok(());
+            }
+            break;
+        }
+    }
+
+    Err(Error::new(
+        ErrorKind::PermissionDenied,
+        format!("Failed to unmount {}: {}", dir.display(), err),
+    ))
+}
+
+/// Lists the mount points of every stash currently mounted.
+///
+pub(crate) fn mounts() -> Vec<PathBuf> {
+    //
+    let Ok(table) = fs::read_to_string("/proc/self/mounts") else {
+        return Vec::new();
+    };
+
+    table
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            match fields.as_slice() {
+                ["stash", dir, "fuse" | "fuse.stash", ..] => Some(PathBuf::from(unescape(dir))),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Decodes the octal escapes used for spaces and such in `/proc/self/mounts`.
+///
+fn unescape(field: &str) -> String {
+    //
+    let mut out = Vec::new();
+    let bytes = field.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = field
+            .get(i + 1..i + 4)
+            .and_then(|code| u8::from_str_radix(code, 8).ok());
+        match (bytes[i], code) {
+            (b'\\', Some(code)) => {
+                out.push(code);
+                i += 4;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&out).to_string()
+}
+
+/// Converts `path` for use in a system call.
+///
+fn c_path(path: &Path) -> Result<CString, Error> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid mount point"))
+}
+
+/// Arranges `files` into a tree of directories, rooted at node 1.
+///
+fn build(files: Vec<FileInfo>) -> Vec<Node> {
+    //
+    let mut nodes = vec![Node::Dir {
+        parent: ROOT,
+        children: BTreeMap::new(),
+    }];
+
+    'files: for info in files {
+        let parts: Vec<String> = info.name.split('/').map(String::from).collect();
+        if parts
+            .iter()
+            .any(|part| part.is_empty() || part == "." || part == "..")
+        {
+            continue;
+        }
+
+        //  Walk down to the file's directory, creating what's missing
+        //
+        let mut dir = ROOT;
+        for part in &parts[..parts.len() - 1] {
+            let next = nodes.len() as u64 + 1;
+            let Node::Dir { children, .. } = &mut nodes[dir as usize - 1] else {
+                continue 'files;
+            };
+            let child = *children.entry(part.clone()).or_insert(next);
+            if child == next {
+                nodes.push(Node::Dir {
+                    parent: dir,
+                    children: BTreeMap::new(),
+                });
+            }
+            dir = child;
+        }
+
+        let next = nodes.len() as u64 + 1;
+        let name = parts[parts.len() - 1].clone();
+        if let Node::Dir { children, .. } = &mut nodes[dir as usize - 1] {
+            if children.contains_key(&name) {
+                continue;
+            }
+            children.insert(name, next);
+            nodes.push(Node::File(info));
+        }
+    }
+
+    nodes
+}
@@ -2,15 +2,48 @@ use aes_gcm::{
     aead::{generic_array::GenericArray, AeadCore, AeadInPlace, KeyInit, OsRng},
     Aes256Gcm,
 };
+use glob::{MatchOptions, Pattern};
+use hmac::{Hmac, Mac};
 use linux_keyutils::{KeyRing, KeyRingIdentifier};
+use memfd::{FileSeal, Memfd, MemfdOptions};
+use memmap2::MmapMut;
+use rayon::prelude::*;
+use regex::bytes::Regex;
 use serde_derive::{self, Deserialize, Serialize};
-use sled::{self, Config, Db};
+use sha2::{Digest, Sha256};
+use sled::{self, Config, Db, Tree};
 use std::{
-    env, fs,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env,
+    ffi::OsStr,
+    fs,
     io::{self, Error, ErrorKind, Read, Seek, Write},
-    path::{Path, PathBuf},
-    process::Command,
+    mem,
+    os::fd::AsRawFd,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{symlink, DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+    },
+    path::{Component, Path, PathBuf},
+    process::{Command, ExitStatus},
+    str::FromStr,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use zeroize::{Zeroize, Zeroizing};
+
+pub use archive::Compression;
+use archive::{
+    decoder, is_compressed, Counter, Encoder, Header, Sealer, Volume, VolumeReader, VolumeWriter,
+};
+use backup::Repo;
+use chunks::ChunkStore;
+
+mod archive;
+mod backup;
+mod bundle;
+mod chunks;
+mod fuse;
 
 #[allow(unused_macros)]
 macro_rules! zeroize_all {
@@ -23,7 +56,7 @@ macro_rules! zeroize_all {
 
 /// Represents a secret consisting of a key and nonce.
 ///
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 struct Secret {
     key: Vec<u8>,
     nonce: Vec<u8>,
@@ -63,1133 +96,7756 @@ impl Secret {
     fn split(&self) -> (Vec<u8>, Vec<u8>) {
         (self.key.clone(), self.nonce.clone())
     }
+
+    /// Checks whether the secret has been left out, as it is where it's
+    /// stored sealed.
+    ///
+    fn is_empty(&self) -> bool {
+        self.key.is_empty() && self.nonce.is_empty()
+    }
 }
 
-/// Represents a stash that holds encrypted files.
+/// Metadata recorded for each stashed entry, keyed by its logical name.
 ///
-#[derive(Debug, Clone)]
-pub struct Stash {
-    path: PathBuf,
-    contents: PathBuf,
-    is_archived: bool,
-    keyring: KeyRing,
-    db: Db,
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Entry {
+    pub original: String,
+    pub mode: u32,
+    pub modified: u64,
+    pub symlink: bool,
+    pub stashed: u64,
+    pub size: u64,
+    pub hash: String,
+    pub revision: u32,
+    pub revisions: Vec<Revision>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    labels: Vec<u8>,
 }
-
-impl Default for Stash {
+impl Entry {
     ///
-    /// Creates a new `Stash` with default configuration.
+    /// Captures the metadata of the file at `path`.
     ///
-    fn default() -> Self {
-        Self::new().unwrap()
+    fn from(path: &Path, meta: &fs::Metadata, symlink: bool) -> Self {
+        let original = std::path::absolute(path)
+            .unwrap_or_else(|_| path.to_path_buf())
+            .to_string_lossy()
+            .to_string();
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |time| time.as_secs());
+
+        Entry {
+            original,
+            mode: meta.mode() & 0o7777,
+            modified,
+            symlink,
+            stashed: now(),
+            size: meta.len(),
+            hash: String::new(),
+            revision: 1,
+            revisions: Vec::new(),
+            chunks: Vec::new(),
+            compression: None,
+            labels: Vec::new(),
+        }
     }
-}
 
-impl Stash {
-    ///
-    /// Creates a new instance of the `Stash` struct.
+    /// Applies the recorded permissions and modification time to `path`.
     ///
-    pub fn new() -> Result<Self, Error> {
-        //
-        let home = env::var("HOME").map_err(|err| {
-            Error::new(
-                ErrorKind::Other,
-                format!("Failed to get `HOME` environment variable: {}", err),
-            )
-        })?;
-        let path = PathBuf::from(&home);
-
-        //  Bail if stash path doesn't exist
+    fn restore(&self, path: &Path) -> Result<(), Error> {
         //
-        if !path.exists() {
-            return Err(Error::new(ErrorKind::NotFound, "Stash path does not exist"));
+        if self.mode != 0 {
+            fs::set_permissions(path, fs::Permissions::from_mode(self.mode))?;
         }
-        let contents = path.join("contents");
-        let mut is_archived = false;
-
-        //  Turn on archive mode if tarball exists
-        //
-        if contents.exists() {
-            is_archived = true;
+        if self.modified != 0 {
+            let file = fs::OpenOptions::new().write(true).open(path)?;
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(self.modified))?;
         }
-        let db_path = path.join(".db");
-        let keyring = KeyRing::from_special_id(KeyRingIdentifier::Session, false).unwrap();
-        let db = Self::get_db(&db_path)?;
 
-        Ok(Stash {
-            path,
-            contents,
-            is_archived,
-            keyring,
-            db,
-        })
+        Ok(())
     }
+}
 
-    #[cfg(test)]
-    ///
-    /// Creates a new test stash at a specified directory.
-    ///
-    pub fn test(dir: &Path) -> Self {
-        //
-        let path = dir.join("test_stash");
-        fs::create_dir(&path).unwrap();
+/// A prior version of an entry, kept alongside its own secret.
+///
+/// In the database, the secret is sealed under a key-encryption key kept
+/// for the entry, and `secret` is left empty.
+///
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Revision {
+    pub number: u32,
+    pub stashed: u64,
+    #[serde(default)]
+    pub modified: u64,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub hash: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    #[serde(default, skip_serializing_if = "Secret::is_empty")]
+    secret: Secret,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    sealed: Vec<u8>,
+}
 
-        let contents = path.join("contents");
-        let mut is_archived = false;
+/// Tags and a note attached to an entry. They're sealed under a key derived
+/// from the stash's master secret before going into its metadata, so they
+/// survive new revisions, which get new keys.
+///
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct Labels {
+    tags: BTreeSet<String>,
+    note: String,
+}
 
-        //  Turn on archive mode if tarball exists
-        //
-        if contents.exists() {
-            is_archived = true;
+/// Summary of one revision of an entry, as listed by `stash log`.
+///
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RevisionInfo {
+    pub number: u32,
+    pub stashed: u64,
+    pub hash: String,
+    pub current: bool,
+}
+
+/// Determines what happens when a file's name is already taken.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Conflict {
+    #[default]
+    Error,
+    Overwrite,
+    Rename,
+    Version,
+}
+
+impl FromStr for Conflict {
+    type Err = Error;
+
+    fn from_str(policy: &str) -> Result<Self, Error> {
+        match policy {
+            "error" => Ok(Conflict::Error),
+            "overwrite" => Ok(Conflict::Overwrite),
+            "rename" => Ok(Conflict::Rename),
+            "version" => Ok(Conflict::Version),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown conflict policy: {}", policy),
+            )),
         }
-        let keyring = KeyRing::from_special_id(KeyRingIdentifier::Session, false).unwrap();
-        let db_path = path.join(".db");
-        let db = Self::get_db(&db_path).unwrap();
+    }
+}
 
-        Stash {
-            path,
-            contents,
-            is_archived,
-            keyring,
-            db,
+/// Returns the current time in seconds since the Unix epoch.
+///
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// Inserts ` (n)` before the extension of the last component of `name`.
+///
+fn numbered(name: &str, n: u32) -> String {
+    //
+    let (dir, file) = match name.rsplit_once('/') {
+        Some((dir, file)) => (format!("{}/", dir), file),
+        None => (String::new(), name),
+    };
+    //  Treat leading dots as part of the stem
+    //
+    match file.rfind('.') {
+        Some(dot) if dot > 0 => format!("{}{} ({}){}", dir, &file[..dot], n, &file[dot..]),
+        _ => format!("{}{} ({})", dir, file, n),
+    }
+}
+
+/// Determines how symbolic links are treated when adding a directory.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Symlinks {
+    #[default]
+    Skip,
+    Follow,
+    Preserve,
+}
+
+impl FromStr for Symlinks {
+    type Err = Error;
+
+    fn from_str(policy: &str) -> Result<Self, Error> {
+        match policy {
+            "skip" => Ok(Symlinks::Skip),
+            "follow" => Ok(Symlinks::Follow),
+            "preserve" => Ok(Symlinks::Preserve),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown symlink policy: {}", policy),
+            )),
         }
     }
+}
 
-    /// Retrieves or creates a `sled` database at the specified path.
+/// Exclusion patterns read from a `.stashignore` file.
+///
+struct Ignore {
+    patterns: Vec<(Pattern, bool)>,
+}
+impl Ignore {
     ///
-    fn get_db(db_path: &Path) -> Result<Db, Error> {
+    /// Reads `.stashignore` from `dir`, if there is one.
+    ///
+    fn load(dir: &Path) -> Result<Self, Error> {
         //
-        let db_str = db_path
-            .to_str()
-            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Invalid database path"))?;
+        let mut patterns = Vec::new();
+        let path = dir.join(".stashignore");
+        if !path.exists() {
+            return Ok(Ignore { patterns });
+        }
 
-        //  Open the database if it exists
+        //  One glob per line, ignoring blanks and comments
         //
-        if db_path.exists() {
-            sled::open(db_str).map_err(|err| {
-                Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to open database: {}", err),
-                )
-            })
-            //  Otherwise create it
+        for line in fs::read_to_string(&path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            //      Trailing slash only matches directories
             //
-        } else {
-            let config = Config::new().path(db_str);
-            config.open().map_err(|err| {
+            let dir_only = line.ends_with('/');
+            let glob = line.trim_start_matches('/').trim_end_matches('/');
+            let pattern = Pattern::new(glob).map_err(|err| {
                 Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to create database: {}", err),
+                    ErrorKind::InvalidData,
+                    format!("Invalid pattern `{}` in .stashignore: {}", line, err),
                 )
-            })
+            })?;
+            patterns.push((pattern, dir_only));
         }
+
+        Ok(Ignore { patterns })
     }
 
-    /// Add a file to the stash, optionally as a copy.
+    /// Checks whether `rel` (relative to the added directory) is excluded.
     ///
-    pub fn add(&mut self, file: &str, copy: bool) -> Result<(), Error> {
-        //
-        //  Refuse to add file if stash is archived
-        //
-        if self.is_archived {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                "Stash is in archive mode. Call `stash unpack` before adding more files",
-            ));
-        }
-        //  Refuse to add a directory
-        //
-        let src_path = Path::new(file);
-        if src_path.is_dir() {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                "Source file is a directory",
-            ));
-        }
-        //  Take file name of target file
-        //
-        let dst_path = self.path.join(src_path.file_name().ok_or_else(|| {
-            Error::new(ErrorKind::InvalidInput, "Failed to resolve new file path")
-        })?);
-
-        //  Refuse to overwrite existing stashed file
+    fn matches(&self, rel: &str, is_dir: bool) -> bool {
         //
-        if dst_path.exists() {
-            return Err(Error::new(
-                ErrorKind::AlreadyExists,
-                "File already in stash",
-            ));
-        }
-
-        let secret = Secret::new();
-        let description = src_path.to_string_lossy().to_string();
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        let name = rel.rsplit('/').next().unwrap_or(rel);
 
-        //  Copy or move depending on option
+        //  Patterns with a slash are anchored, others match any file name
         //
-        if copy {
-            fs::copy(src_path, &dst_path)?;
-        } else {
-            fs::rename(src_path, &dst_path)?;
-        }
+        self.patterns.iter().any(|(pattern, dir_only)| {
+            (!dir_only || is_dir)
+                && if pattern.as_str().contains('/') {
+                    pattern.matches_with(rel, options)
+                } else {
+                    pattern.matches_with(name, options)
+                }
+        })
+    }
+}
 
-        //  Encrypt file in place
+/// A private directory on tmpfs for plaintext that has to exist as a file.
+///
+/// Everything inside is overwritten and removed when dropped, so the
+/// plaintext is wiped even if whatever was using it fails. A process killed
+/// outright never drops it, so what it leaves behind is swept up later.
+///
+struct Scratch {
+    path: PathBuf,
+}
+impl Scratch {
+    ///
+    /// Creates a fresh `0700` directory under `$XDG_RUNTIME_DIR` or `/dev/shm`,
+    /// named `<prefix>.<pid>.<nonce>`.
+    ///
+    fn new(prefix: &str) -> Result<Self, Error> {
         //
-        Self::encrypt(&dst_path, &secret).map_err(|err| {
-            Error::new(ErrorKind::Other, format!("Failed to encrypt file: {}", err))
-        })?;
-
-        //  Add filename and secret to database
+        //  Never reuse a directory someone else could have prepared
         //
-        self.db
-            .insert(description.as_bytes(), secret.join())
-            .map_err(|err| {
-                Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to add encryption key to database: {}", err),
-                )
-            })?;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.subsec_nanos());
+        let path = Self::base()?.join(format!("{}.{}.{:08x}", prefix, std::process::id(), nanos));
+        fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&path)
+            .map_err(|err| Error::other(format!("Failed to create private directory: {}", err)))?;
+
+        Ok(Scratch { path })
+    }
 
-        //  Cache filename and secret in keyring
-        //
-        self.keyring
-            .add_key(&description, &secret.join())
-            .map_err(|err| {
+    /// Returns the tmpfs directory scratch directories are made in.
+    ///
+    fn base() -> Result<PathBuf, Error> {
+        env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_dir())
+            .or_else(|| Some(PathBuf::from("/dev/shm")).filter(|dir| dir.is_dir()))
+            .ok_or_else(|| {
                 Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to cache encryption key: {}", err),
+                    ErrorKind::NotFound,
+                    "No tmpfs directory available (set `XDG_RUNTIME_DIR`)",
                 )
-            })?;
-
-        Ok(())
+            })
     }
 
-    /// Move a file from the stash into the current directory.
+    /// Wipes and removes our directories named `<prefix>.<pid>.<nonce>` whose
+    /// process is gone, as left by one that was killed before it could.
     ///
-    pub fn grab(&mut self, file: &str, copy: bool) -> Result<(), Error> {
-        //
-        //  Bail if archived and not copying the tarball
+    fn sweep(prefix: &str) {
         //
-        if self.is_archived && !copy && file != "contents" {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                "Stash is in archive mode. Use `stash unpack` to unpack",
-            ));
+        let Ok(read_dir) = Self::base().and_then(fs::read_dir) else {
+            return;
+        };
+        // SAFETY: `getuid` can't fail.
+        let uid = unsafe { libc::getuid() };
+        for item in read_dir.flatten() {
+            let name = item.file_name().to_string_lossy().to_string();
+            let pid = name
+                .strip_prefix(prefix)
+                .and_then(|rest| rest.strip_prefix('.'))
+                .and_then(|rest| rest.split_once('.'))
+                .and_then(|(pid, _)| pid.parse::<u32>().ok());
+            let Some(pid) = pid else {
+                continue;
+            };
+            let ours = fs::symlink_metadata(item.path())
+                .is_ok_and(|meta| meta.is_dir() && meta.uid() == uid);
+            if ours && !Path::new("/proc").join(pid.to_string()).exists() {
+                drop(Scratch { path: item.path() });
+            }
         }
-        let src_path = self.path.join(file);
-        let dst_path = env::current_dir()?.join(file);
-        let secret;
+    }
 
-        //  Refuse to overwrite existing file
+    /// Writes `contents` to a new `0600` file called `name` inside the directory.
+    ///
+    fn write(&self, name: &str, contents: &[u8]) -> Result<PathBuf, Error> {
         //
-        if dst_path.exists() {
-            return Err(Error::new(
-                ErrorKind::AlreadyExists,
-                "File already exists in current directory",
-            ));
+        let path = self.path.join(name);
+        if let Some(parent) = path.parent() {
+            fs::DirBuilder::new()
+                .mode(0o700)
+                .recursive(true)
+                .create(parent)?;
         }
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        file.write_all(contents)?;
 
-        //  Get secret from keyring if it's there
-        //
-        if let Ok(key) = self.keyring.search(file) {
-            secret = Secret::from(&key.read_to_vec().map_err(|err| {
-                Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to read encryption key: {}", err),
-                )
-            })?);
-            //      Remove secret if file is moved
-            //
-            if !copy {
-                key.invalidate().map_err(|err| {
-                    Error::new(
-                        ErrorKind::Other,
-                        format!("Failed to remove cached key: {}", err),
-                    )
-                })?;
-            }
-            //  Or check database for file secrets
-            //
-        } else if let Some(value) = self.db.get(file)? {
-            secret = Secret::from(&value);
-            //
-            //      Or throw
-        } else {
-            return Err(Error::new(ErrorKind::NotFound, "Secret not found"));
-        }
+        Ok(path)
+    }
 
-        //  Decrypt file in place
-        //
-        Self::decrypt(&src_path, &secret).map_err(|err| {
-            Error::new(ErrorKind::Other, format!("Failed to decrypt file: {}", err))
-        })?;
+    /// Keeps the directory past the end of this process, returning its path.
+    ///
+    fn keep(mut self) -> PathBuf {
+        std::mem::take(&mut self.path)
+    }
 
-        //  Copy depending on option passed in
+    /// Overwrites every file under `dir` with zeros.
+    ///
+    fn shred(dir: &Path) {
         //
-        if copy {
-            fs::copy(src_path, dst_path).map_err(|err| {
-                Error::new(ErrorKind::Other, format!("Failed to copy file: {}", err))
-            })?;
-            //      Or move
-        } else {
-            fs::rename(src_path, dst_path).map_err(|err| {
-                Error::new(ErrorKind::Other, format!("Failed to move file: {}", err))
-            })?;
-            //      And remove encryption secrets from database
-            //
-            self.db.remove(file).map_err(|err| {
-                Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to remove file from database: {}", err),
-                )
-            })?;
+        let Ok(read_dir) = fs::read_dir(dir) else {
+            return;
+        };
+        for item in read_dir.flatten() {
+            let path = item.path();
+            match item.file_type() {
+                Ok(kind) if kind.is_dir() => Self::shred(&path),
+                Ok(kind) if kind.is_file() => {
+                    if let Ok(mut file) = fs::OpenOptions::new().write(true).open(&path) {
+                        let len = file.metadata().map_or(0, |meta| meta.len());
+                        let _ = io::copy(&mut io::repeat(0).take(len), &mut file);
+                        let _ = file.sync_all();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+impl Drop for Scratch {
+    ///
+    /// Wipes and removes the directory.
+    ///
+    fn drop(&mut self) {
+        if self.path.as_os_str().is_empty() {
+            return;
         }
+        Self::shred(&self.path);
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
 
-        //  Toggle archive mode if tarball was removed
+/// Holds off `SIGINT`, `SIGTERM` and `SIGHUP` for as long as it lives.
+///
+/// They're caught and dropped rather than ignored, since ignoring them would
+/// carry over to any program started meanwhile.
+///
+struct Shield {
+    previous: Vec<(libc::c_int, libc::sigaction)>,
+}
+impl Shield {
+    ///
+    /// Starts holding off the signals.
+    ///
+    fn new() -> Self {
         //
-        if !copy && file == "contents" {
-            self.is_archived = false;
+        extern "C" fn hold(_: libc::c_int) {}
+
+        let mut previous = Vec::new();
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            // SAFETY: the handler does nothing, so is safe to run at any point,
+            // and both actions are valid for the duration of the call.
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = hold as *const () as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                let mut old: libc::sigaction = std::mem::zeroed();
+                if libc::sigaction(signal, &action, &mut old) == 0 {
+                    previous.push((signal, old));
+                }
+            }
         }
 
-        Ok(())
+        Shield { previous }
+    }
+}
+impl Drop for Shield {
+    ///
+    /// Puts back how each signal was handled before.
+    ///
+    fn drop(&mut self) {
+        for (signal, old) in &self.previous {
+            // SAFETY: `old` is what `sigaction` gave back for this signal.
+            unsafe {
+                libc::sigaction(*signal, old, std::ptr::null_mut());
+            }
+        }
     }
+}
 
-    /// Delete `file` in the stash.
+/// Plaintext held in a sealed, anonymous in-memory file.
+///
+/// The file can be read through `/proc/self/fd/N` by child processes, but
+/// nobody can change its contents or size once sealed. Its pages are zeroed
+/// through a mapping made before sealing, then released, when dropped.
+///
+struct Sealed {
+    memfd: Memfd,
+    map: Option<MmapMut>,
+}
+impl Sealed {
     ///
-    pub fn delete(&mut self, file: &str) -> Result<(), Error> {
+    /// Copies `contents` into a new sealed memfd that child processes inherit.
+    ///
+    /// It's named `stash-<index>` rather than after the file it holds, which
+    /// would show in `/proc` and could run past the kernel's limit on names.
+    ///
+    fn new(index: usize, contents: &[u8]) -> Result<Self, Error> {
         //
-        //  Bail if archived and not deleting `contents`
+        let to_err =
+            |err: memfd::Error| Error::other(format!("Failed to create in-memory file: {}", err));
+        let memfd = MemfdOptions::default()
+            .allow_sealing(true)
+            .close_on_exec(false)
+            .create(format!("stash-{}", index))
+            .map_err(to_err)?;
+        memfd.as_file().set_len(contents.len() as u64)?;
+
+        //  Keep a writable mapping so the pages can be wiped after sealing
         //
-        if self.is_archived && file != "contents" {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                "Stash is in archive mode. Use `stash unpack` to unpack",
-            ));
-            //  Otherwise, bail if deleting program files
-            //
-        } else if file == ".db" || file == ".secret" {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                format!("Cannot delete program file {}", file),
-            ));
+        let map = if contents.is_empty() {
+            None
+        } else {
+            // SAFETY: the memfd is private to this process until sealed, and
+            // its size can't change while mapped.
+            let mut map = unsafe { MmapMut::map_mut(memfd.as_file())? };
+            map.copy_from_slice(contents);
+            Some(map)
+        };
+        memfd
+            .add_seals(&[
+                FileSeal::SealShrink,
+                FileSeal::SealGrow,
+                FileSeal::SealFutureWrite,
+                FileSeal::SealSeal,
+            ])
+            .map_err(to_err)?;
+
+        Ok(Sealed { memfd, map })
+    }
+
+    /// Returns the path a child process can open the file by.
+    ///
+    fn path(&self) -> String {
+        format!("/proc/self/fd/{}", self.memfd.as_raw_fd())
+    }
+}
+impl Drop for Sealed {
+    ///
+    /// Wipes the plaintext before the descriptor is closed.
+    ///
+    fn drop(&mut self) {
+        if let Some(map) = self.map.as_mut() {
+            map.zeroize();
         }
-        //  Make sure specified file exists
-        //
-        let target_path = self.path.join(file);
-        if !target_path.exists() {
-            return Err(Error::new(
+    }
+}
+
+/// A named archive, recorded along with the secrets of its tarball and manifest.
+///
+/// Only how many files it holds is recorded here, since the names are left
+/// to the encrypted manifest. Incremental archives also record the archive
+/// they follow on from, if any.
+///
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Archive {
+    secret: Secret,
+    manifest: Secret,
+    created: u64,
+    #[serde(default)]
+    count: usize,
+    #[serde(default)]
+    parent: Option<String>,
+    #[serde(default)]
+    incremental: bool,
+}
+
+/// The encrypted manifest of an archive: its volumes in order, its files,
+/// and for incremental archives, the files deleted since the last one and
+/// a `baseline` of every file's hash as of the archive.
+///
+#[derive(Deserialize, Serialize)]
+struct Manifest {
+    volumes: Vec<Volume>,
+    files: Vec<Member>,
+    #[serde(default)]
+    deleted: Vec<String>,
+    #[serde(default)]
+    baseline: BTreeMap<String, String>,
+}
+
+/// One archived file, as described by its archive's encrypted manifest.
+///
+/// `offset` and `length` locate the file and its revisions within the
+/// tarball, and `secret` and `entry` are what it takes to restore it.
+///
+#[derive(Deserialize, Serialize)]
+struct Member {
+    name: String,
+    size: u64,
+    hash: String,
+    offset: u64,
+    length: u64,
+    secret: Secret,
+    entry: Entry,
+}
+
+/// Summary of a stashed file, as listed by `stash list`.
+///
+/// `size` is that of the plaintext, and `revisions` counts only prior
+/// revisions. `note` is empty unless one was set. Every file is sealed
+/// with `cipher`, and may also be chunked or compressed before that.
+///
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct EntryInfo {
+    pub name: String,
+    pub original: String,
+    pub size: u64,
+    pub stashed: u64,
+    pub modified: u64,
+    pub cipher: String,
+    pub chunked: bool,
+    pub compression: Option<Compression>,
+    pub symlink: bool,
+    pub revisions: usize,
+    pub tags: Vec<String>,
+    pub note: String,
+}
+
+/// Everything known about one file, as shown by `stash info`.
+///
+/// `size` is that of the plaintext and `stored` what its ciphertext takes
+/// up, counting every chunk of a chunked file. `created` is when its first
+/// revision was stashed, and `accessed` when its ciphertext was last read,
+/// if the filesystem keeps track. A file that was moved into an archive is
+/// described as of the newest archive holding it, with `in_stash` unset.
+///
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FileDetails {
+    pub name: String,
+    pub original: String,
+    pub size: u64,
+    pub stored: u64,
+    pub hash: String,
+    pub cipher: String,
+    pub format: u32,
+    pub chunked: bool,
+    pub compression: Option<Compression>,
+    pub symlink: bool,
+    pub mode: u32,
+    pub created: u64,
+    pub stashed: u64,
+    pub modified: u64,
+    pub accessed: Option<u64>,
+    pub revisions: Vec<RevisionInfo>,
+    pub tags: Vec<String>,
+    pub note: String,
+    pub cached: bool,
+    pub in_stash: bool,
+    pub archives: Vec<String>,
+}
+
+/// A line found by `stash grep`, numbered from 1.
+///
+/// The line is plaintext, so it's wiped once dropped.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GrepMatch {
+    pub name: String,
+    pub line: usize,
+    pub text: Zeroizing<String>,
+}
+
+/// The lines of one file found by `stash grep`, or why it couldn't be
+/// searched.
+///
+pub type GrepResult = (String, Result<Vec<GrepMatch>, Error>);
+
+/// What `stash list` orders files by.
+///
+/// Names sort alphabetically, sizes largest first, and times newest first.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Sort {
+    #[default]
+    Name,
+    Size,
+    Stashed,
+    Modified,
+}
+
+impl FromStr for Sort {
+    type Err = Error;
+
+    fn from_str(key: &str) -> Result<Self, Error> {
+        match key {
+            "name" => Ok(Sort::Name),
+            "size" => Ok(Sort::Size),
+            "stashed" => Ok(Sort::Stashed),
+            "modified" => Ok(Sort::Modified),
+            _ => Err(Error::new(
                 ErrorKind::InvalidInput,
-                "File not found in stash",
-            ));
+                format!("Unknown sort key: {}", key),
+            )),
         }
-        fs::remove_file(
-            target_path
-                .to_str()
-                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Failed to parse file path"))?,
-        )?;
+    }
+}
 
-        //  Remove file secret from database
-        //
-        self.db.remove(file).map_err(|err| {
+/// Summary of a named archive, as listed by `stash list --archives`.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveInfo {
+    pub name: String,
+    pub created: u64,
+    pub files: usize,
+    pub incremental: bool,
+    pub parent: Option<String>,
+}
+
+/// How `stash archive` builds an archive.
+///
+/// `level` defaults to one suited to the chosen compression. With a
+/// `volume_size`, the archive is split into volumes of at most that many
+/// bytes. `incremental` archives leave the stash as it is, and only hold
+/// what changed since the last incremental archive.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ArchiveOptions {
+    pub compression: Compression,
+    pub level: Option<u32>,
+    pub volume_size: Option<u64>,
+    pub incremental: bool,
+}
+
+/// An archived file, as listed by `stash list --archive <name>`.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemberInfo {
+    pub name: String,
+    pub size: u64,
+    pub hash: String,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// One file in a bundle, with what it takes to restore it elsewhere.
+///
+#[derive(Deserialize, Serialize)]
+struct Bundled {
+    name: String,
+    secret: Secret,
+    entry: Entry,
+    #[serde(default)]
+    labels: Labels,
+}
+
+/// The index at the start of a bundle, holding every file's secret and
+/// metadata, each project's stored environment as it is, and the key of
+/// each chunk that follows it.
+///
+#[derive(Default, Deserialize, Serialize)]
+struct BundleIndex {
+    files: Vec<Bundled>,
+    envs: BTreeMap<String, Vec<u8>>,
+    #[serde(default)]
+    chunk_keys: BTreeMap<String, Vec<u8>>,
+}
+
+/// What went into or came out of a bundle.
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BundleContents {
+    pub files: Vec<String>,
+    pub envs: Vec<String>,
+}
+
+/// One file copied into a backup, by its place in the stash.
+///
+#[derive(Deserialize, Serialize)]
+struct Blob {
+    path: String,
+    hash: String,
+    mode: u32,
+}
+
+/// A key and value from the database, as kept in a backup.
+///
+type Pair = (Vec<u8>, Vec<u8>);
+
+/// The record of one backup: every file it holds, the whole database (but
+/// for open workspaces) as it was, tree by tree, and the stash's master
+/// secret.
+///
+#[derive(Default, Deserialize, Serialize)]
+struct Snapshot {
+    created: u64,
+    files: Vec<Blob>,
+    trees: BTreeMap<String, Vec<Pair>>,
+    #[serde(default)]
+    secret: Vec<u8>,
+}
+impl Drop for Snapshot {
+    ///
+    /// Zeroes every key and value before they are freed.
+    ///
+    fn drop(&mut self) {
+        for (key, value) in self.trees.values_mut().flatten() {
+            zeroize_all!(key, value);
+        }
+        self.secret.zeroize();
+    }
+}
+
+/// How many older backups to keep, by the most recent of each period.
+///
+/// `None` for both keeps every backup.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Retention {
+    pub daily: Option<usize>,
+    pub weekly: Option<usize>,
+}
+
+/// What a backup did.
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackupReport {
+    pub snapshot: String,
+    pub files: usize,
+    pub copied: usize,
+    pub removed: Vec<String>,
+}
+
+/// A backup in a repository, as shown by `stash backup list`.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    pub id: String,
+    pub created: u64,
+    pub files: usize,
+}
+
+/// The files decrypted into an open workspace, with their hashes at the time.
+///
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct Workspace {
+    opened: u64,
+    files: BTreeMap<String, String>,
+}
+
+/// A difference between an open workspace and the stash.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Modified(String),
+    Added(String),
+    Deleted(String),
+}
+
+/// An open workspace, as shown by `stash status`.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WorkspaceStatus {
+    pub path: PathBuf,
+    pub opened: u64,
+    pub missing: bool,
+    pub changes: Vec<Change>,
+}
+
+/// Decrypted environment variables of one project, wiped when dropped.
+///
+#[derive(Default, Deserialize, Serialize)]
+struct EnvVars(BTreeMap<String, String>);
+impl Drop for EnvVars {
+    ///
+    /// Zeroes every value before it is freed.
+    ///
+    fn drop(&mut self) {
+        for value in self.0.values_mut() {
+            value.zeroize();
+        }
+    }
+}
+
+/// Parses `KEY=VALUE` lines in the style of a `.env` file.
+///
+/// Blank lines and `#` comments are skipped, a leading `export` is allowed,
+/// and values may be wrapped in single or double quotes.
+///
+fn parse_dotenv(text: &str) -> Result<Vec<(String, String)>, Error> {
+    //
+    let mut vars = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line.split_once('=').ok_or_else(|| {
             Error::new(
-                ErrorKind::Other,
-                format!("Failed to remove file from database: {}", err),
+                ErrorKind::InvalidData,
+                format!("Expected KEY=VALUE on line {}", n + 1),
             )
         })?;
 
-        //  Remove file secret from keyring if cached
+        //  Quotes are stripped, and only double quotes take escapes
         //
-        if let Ok(key) = self.keyring.search(file) {
-            key.invalidate().map_err(|err| {
-                Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to remove key from cache: {}", err),
-                )
-            })?;
+        let value = value.trim();
+        let value = if let Some(inner) = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+        {
+            inner
+                .replace("\\n", "\n")
+                .replace("\\\"", "\"")
+                .replace("\\\\", "\\")
+        } else if let Some(inner) = value
+            .strip_prefix('\'')
+            .and_then(|value| value.strip_suffix('\''))
+        {
+            inner.to_string()
+        } else {
+            value.to_string()
+        };
+        vars.push((key.trim().to_string(), value));
+    }
+
+    Ok(vars)
+}
+
+/// Checks that `key` is usable as an environment variable name.
+///
+fn check_env_key(key: &str) -> Result<(), Error> {
+    //
+    let mut chars = key.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid variable name: {}", key),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Version of the layout of stored files: a whole file sealed with
+/// AES-256-GCM under its own key, or a stub standing in for chunks.
+///
+const FORMAT: u32 = 1;
+
+/// Largest stored file `stash grep` decrypts alongside others. Files sealed
+/// whole have to be decrypted whole, so larger ones are searched one by one.
+///
+const GREP_WHOLE: u64 = 4 * 1024 * 1024;
+
+/// Settings kept per stash, with their defaults.
+///
+/// `compress` is `manual` to compress only files added with `--compress`,
+/// an algorithm to compress every file with it unless told otherwise, or
+/// `off` to never compress, for when sizes mustn't give contents away.
+///
+const CONFIG: &[(&str, &str)] = &[("compress", "manual")];
+
+/// Represents a stash that holds encrypted files.
+///
+#[derive(Debug, Clone)]
+pub struct Stash {
+    path: PathBuf,
+    keyring: KeyRing,
+    db: Db,
+    entries: Tree,
+    keys: Tree,
+    envs: Tree,
+    workspaces: Tree,
+    archives: Tree,
+    chunks: Tree,
+    config: Tree,
+    chunking: bool,
+    compression: Option<Compression>,
+}
+
+impl Default for Stash {
+    ///
+    /// Creates a new `Stash` with default configuration.
+    ///
+    fn default() -> Self {
+        Self::new().unwrap()
+    }
+}
+
+impl Stash {
+    ///
+    /// Creates a new instance of the `Stash` struct.
+    ///
+    pub fn new() -> Result<Self, Error> {
+        //
+        let home = env::var("HOME").map_err(|err| {
+            Error::other(format!(
+                "Failed to get `HOME` environment variable: {}",
+                err
+            ))
+        })?;
+        let path = PathBuf::from(&home);
+
+        //  Bail if stash path doesn't exist
+        //
+        if !path.exists() {
+            return Err(Error::new(ErrorKind::NotFound, "Stash path does not exist"));
         }
-        //  End archive mode if tarball deleted
+
+        Self::load(path)
+    }
+
+    #[cfg(test)]
+    ///
+    /// Creates a new test stash at a specified directory.
+    ///
+    pub fn test(dir: &Path) -> Self {
+        //
+        let path = dir.join("test_stash");
+        fs::create_dir(&path).unwrap();
+
+        Self::load(path).unwrap()
+    }
+
+    /// Opens the stash at `path`, first finishing any rewrite of its
+    /// database that was cut short.
+    ///
+    fn load(path: PathBuf) -> Result<Self, Error> {
         //
-        if file == "contents" {
-            self.is_archived = false;
+        let fresh_path = path.join(".db.new");
+        let old_path = path.join(".db.old");
+        if !path.join(".db").exists() && fresh_path.is_dir() {
+            fs::rename(&fresh_path, path.join(".db"))?;
+        }
+        if old_path.exists() {
+            Self::shred_dir(&old_path)?;
         }
 
-        Ok(())
+        Self::open_db(path)
     }
 
-    /// List all files in the stash directory.
+    /// Opens the database and keyring of the stash at `path`.
     ///
-    pub fn list(&self) -> Result<String, Error> {
+    fn open_db(path: PathBuf) -> Result<Self, Error> {
         //
-        //  Call `ls` command at the stash path
+        let keyring = KeyRing::from_special_id(KeyRingIdentifier::Session, false).unwrap();
+        let db = Self::get_db(&path.join(".db"))?;
+        let entries = Self::get_tree(&db, "entries")?;
+        let keys = Self::get_tree(&db, "keys")?;
+        let envs = Self::get_tree(&db, "envs")?;
+        let workspaces = Self::get_tree(&db, "workspaces")?;
+        let archives = Self::get_tree(&db, "archives")?;
+        let chunks = Self::get_tree(&db, "chunks")?;
+        let config = Self::get_tree(&db, "config")?;
+
+        Ok(Stash {
+            path,
+            keyring,
+            db,
+            entries,
+            keys,
+            envs,
+            workspaces,
+            archives,
+            chunks,
+            config,
+            chunking: false,
+            compression: None,
+        })
+    }
+
+    /// Retrieves or creates a `sled` database at the specified path.
+    ///
+    fn get_db(db_path: &Path) -> Result<Db, Error> {
         //
-        let ls_output =
-            Command::new("ls")
-                .arg(self.path.to_str().ok_or_else(|| {
-                    Error::new(ErrorKind::Other, "Failed to convert path to string")
-                })?)
-                .output()
-                .map_err(|err| {
-                    Error::new(
-                        ErrorKind::Other,
-                        format!("Failed to execute `ls` command: {}", err),
-                    )
-                })?
-                .stdout;
+        let db_str = db_path
+            .to_str()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Invalid database path"))?;
 
-        let contents = String::from_utf8_lossy(&ls_output).trim().to_string();
+        //  Open the database if it exists
+        //
+        if db_path.exists() {
+            sled::open(db_str)
+                .map_err(|err| Error::other(format!("Failed to open database: {}", err)))
+            //  Otherwise create it
+            //
+        } else {
+            let config = Config::new().path(db_str);
+            config
+                .open()
+                .map_err(|err| Error::other(format!("Failed to create database: {}", err)))
+        }
+    }
 
-        Ok(contents)
+    /// Opens a named tree within the `sled` database.
+    ///
+    fn get_tree(db: &Db, name: &str) -> Result<Tree, Error> {
+        //
+        db.open_tree(name).map_err(|err| {
+            Error::other(format!("Failed to open `{}` database tree: {}", name, err))
+        })
     }
 
-    /// Create a tarball from the current stash contents.
+    /// Add a file to the stash, optionally as a copy.
+    ///
+    /// Returns the name the file was stashed under, which only differs from
+    /// the file name when `conflict` is `Conflict::Rename`.
     ///
-    pub fn archive(&mut self) -> Result<(), Error> {
+    pub fn add(&mut self, file: &str, copy: bool, conflict: Conflict) -> Result<String, Error> {
         //
-        //  Bail if already archived
+        //  Refuse to add a directory
         //
-        if self.is_archived {
+        let src_path = Path::new(file);
+        if src_path.is_dir() {
             return Err(Error::new(
                 ErrorKind::InvalidInput,
-                "Archive already exists",
+                "Source file is a directory. Use `stash add -r` to add it",
             ));
-            //  Or bail if the database is empty
-            //
-        } else if self.db.is_empty() {
+        }
+        //  Take file name of target file
+        //
+        let name = src_path
+            .file_name()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Failed to resolve new file path"))?
+            .to_string_lossy()
+            .to_string();
+
+        self.stash_file(src_path, &name, copy, Symlinks::Follow, conflict)
+    }
+
+    /// Add every file under a directory to the stash, optionally as copies.
+    ///
+    /// Each regular file becomes its own entry named `<dir>/<relative path>`.
+    /// Paths matched by a `.stashignore` file at the top of `dir` are skipped.
+    ///
+    pub fn add_dir(
+        &mut self,
+        dir: &str,
+        copy: bool,
+        links: Symlinks,
+        conflict: Conflict,
+    ) -> Result<Vec<String>, Error> {
+        //
+        let src_path = Path::new(dir);
+        if !src_path.is_dir() {
             return Err(Error::new(
                 ErrorKind::InvalidInput,
-                "No files in stash: .db is empty",
+                "Source file is not a directory",
             ));
         }
+        //  Name entries after the directory itself
+        //
+        let base = fs::canonicalize(src_path)?
+            .file_name()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Failed to resolve directory name"))?
+            .to_string_lossy()
+            .to_string();
 
-        //  Get file name of tarball
+        //  Collect files to stash, honoring `.stashignore`
         //
-        let file_name = self.contents.file_name().ok_or(Error::new(
-            ErrorKind::InvalidData,
-            "Failed to get file name",
-        ))?;
+        let ignore = Ignore::load(src_path)?;
+        let mut visited = HashSet::from([fs::canonicalize(src_path)?]);
+        let mut files = Vec::new();
+        Self::walk(src_path, "", &ignore, links, &mut visited, &mut files)?;
 
-        let description = file_name.to_string_lossy().to_string();
-        let secret = Secret::new();
+        //  Refuse to add anything if any entry is already taken
+        //
+        let files: Vec<(PathBuf, String)> = files
+            .into_iter()
+            .map(|(path, rel)| (path, format!("{}/{}", base, rel)))
+            .collect();
+
+        if conflict == Conflict::Error {
+            if let Some((_, name)) = files.iter().find(|(_, name)| self.exists(name)) {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("File already in stash: {}", name),
+                ));
+            }
+        }
+
+        let mut names = Vec::with_capacity(files.len());
+        for (path, name) in &files {
+            names.push(self.stash_file(path, name, copy, links, conflict)?);
+        }
 
-        //  Create tarball `contents` and remove original files
+        //  Clean up directories emptied by moving files out
         //
-        self.create_tarball().map_err(|err| {
-            Error::new(
-                ErrorKind::Other,
-                format!("Failed to create tarball: {}", err),
-            )
-        })?;
+        if !copy {
+            Self::remove_empty_dirs(src_path);
+        }
+
+        Ok(names)
+    }
+
+    /// Recursively collects files under `dir` as (path, relative name) pairs.
+    ///
+    fn walk(
+        dir: &Path,
+        rel: &str,
+        ignore: &Ignore,
+        links: Symlinks,
+        visited: &mut HashSet<PathBuf>,
+        files: &mut Vec<(PathBuf, String)>,
+    ) -> Result<(), Error> {
+        //
+        let mut children = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        children.sort_by_key(|child| child.file_name());
+
+        for child in children {
+            let name = child.file_name().to_string_lossy().to_string();
+            let child_rel = if rel.is_empty() {
+                name.clone()
+            } else {
+                format!("{}/{}", rel, name)
+            };
+            //  Skip the ignore file itself
+            //
+            if rel.is_empty() && name == ".stashignore" {
+                continue;
+            }
+            let path = child.path();
+            let meta = fs::symlink_metadata(&path)?;
+            let is_link = meta.file_type().is_symlink();
+
+            if is_link && links == Symlinks::Skip {
+                continue;
+            }
+            let is_dir = if is_link && links == Symlinks::Follow {
+                path.is_dir()
+            } else {
+                meta.is_dir()
+            };
+            if ignore.matches(&child_rel, is_dir) {
+                continue;
+            }
+
+            //  Descend into directories, but never twice into the same one
+            //
+            if is_dir {
+                if visited.insert(fs::canonicalize(&path)?) {
+                    Self::walk(&path, &child_rel, ignore, links, visited, files)?;
+                }
+            } else if (is_link && (links == Symlinks::Preserve || path.is_file())) || meta.is_file()
+            {
+                files.push((path, child_rel));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes empty directories under and including `dir`, bottom-up.
+    ///
+    fn remove_empty_dirs(dir: &Path) {
+        //
+        if let Ok(children) = fs::read_dir(dir) {
+            for child in children.flatten() {
+                if child.file_type().is_ok_and(|kind| kind.is_dir()) {
+                    Self::remove_empty_dirs(&child.path());
+                }
+            }
+        }
+        let _ = fs::remove_dir(dir);
+    }
+
+    /// Removes empty directories inside the stash, from `dir` upward.
+    ///
+    fn prune_dirs(&self, dir: &Path) {
+        //
+        let mut dir = dir.to_path_buf();
+        while dir != self.path && dir.starts_with(&self.path) && fs::remove_dir(&dir).is_ok() {
+            if !dir.pop() {
+                break;
+            }
+        }
+    }
+
+    /// Checks whether `name` is taken in the stash.
+    ///
+    fn exists(&self, name: &str) -> bool {
+        self.path.join(name).exists() || self.db.contains_key(name).unwrap_or(false)
+    }
+
+    /// Encrypts `src` into the stash under the logical `name`.
+    ///
+    /// Returns the name actually used, after applying the `conflict` policy.
+    ///
+    fn stash_file(
+        &mut self,
+        src: &Path,
+        name: &str,
+        copy: bool,
+        links: Symlinks,
+        conflict: Conflict,
+    ) -> Result<String, Error> {
+        //
+        Self::check_name(name)?;
+        if Self::is_reserved(name) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid file name: {}", name),
+            ));
+        }
+
+        //  New revisions of chunked or compressed files are stored the same way
+        //
+        let chunking = self.chunking || chunks::is_stub(&self.path.join(name));
+        let previous = self.get_entry(name)?.and_then(|entry| entry.compression);
+        let (name, revisions) = self.claim(name, conflict)?;
+        let dst_path = self.path.join(&name);
+
+        let secret = Secret::new();
+        let meta = fs::symlink_metadata(src)?;
+        let entry;
+
+        //  Store preserved links as their target path
+        //
+        if meta.file_type().is_symlink() && links == Symlinks::Preserve {
+            entry = Entry::from(src, &meta, true);
+            fs::write(&dst_path, fs::read_link(src)?.as_os_str().as_bytes())?;
+            if !copy {
+                fs::remove_file(src)?;
+            }
+            //  Copy the target of followed links, never the link itself
+            //
+        } else if meta.file_type().is_symlink() {
+            entry = Entry::from(src, &fs::metadata(src)?, false);
+            fs::copy(src, &dst_path)?;
+            if !copy {
+                fs::remove_file(src)?;
+            }
+            //  Copy or move depending on option
+            //
+        } else {
+            entry = Entry::from(src, &meta, false);
+            if copy {
+                fs::copy(src, &dst_path)?;
+            } else {
+                fs::rename(src, &dst_path)?;
+            }
+        }
+        let hash = Self::hash_file(&dst_path)?;
+        let size = fs::metadata(&dst_path)?.len();
+
+        //  Encrypt file in place, or cut it into chunks and leave a stub
+        //
+        let mut chunks = Vec::new();
+        let mut compression = None;
+        if chunking {
+            let plaintext = Zeroizing::new(fs::read(&dst_path)?);
+            chunks = self.chunk_store()?.put(&plaintext)?;
+            fs::write(&dst_path, chunks::STUB)?;
+        } else if let Some(wanted) =
+            self.pick_compression(&Self::read_head(&dst_path)?, previous)?
+        {
+            //  Compress before sealing, unless that gains nothing
+            //
+            let plaintext = Zeroizing::new(fs::read(&dst_path)?);
+            let packed = Self::compress(&plaintext, wanted)?;
+            compression = packed.as_ref().map(|_| wanted);
+            let ciphertext =
+                Self::encrypt_bytes(packed.as_deref().map_or(&plaintext[..], |p| p), &secret)?;
+            fs::write(&dst_path, ciphertext)
+                .map_err(|err| Error::other(format!("Failed to encrypt file: {}", err)))?;
+        } else {
+            Self::encrypt(&dst_path, &secret)
+                .map_err(|err| Error::other(format!("Failed to encrypt file: {}", err)))?;
+        }
+
+        self.record(
+            &name,
+            &secret,
+            Entry {
+                hash,
+                size,
+                chunks,
+                compression,
+                ..entry
+            },
+            revisions,
+        )?;
+
+        Ok(name)
+    }
+
+    /// Encrypts everything read from `reader` into the stash as `name`.
+    ///
+    /// The plaintext is held in memory until it has been sealed, and never
+    /// touches the disk. Returns the name actually used, after applying the
+    /// `conflict` policy.
+    ///
+    pub fn add_reader<R: Read>(
+        &mut self,
+        reader: &mut R,
+        name: &str,
+        conflict: Conflict,
+    ) -> Result<String, Error> {
+        //
+        Self::check_name(name)?;
+        if name.ends_with('/') || Self::is_reserved(name) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid file name: {}", name),
+            ));
+        }
+
+        //  Read plaintext into memory that is wiped once dropped
+        //
+        let mut plaintext = Zeroizing::new(Vec::new());
+        reader
+            .read_to_end(&mut plaintext)
+            .map_err(|err| Error::other(format!("Failed to read input: {}", err)))?;
+
+        let entry = Entry {
+            original: "-".to_string(),
+            mode: 0o600,
+            ..Entry::default()
+        };
+        self.store_bytes(&plaintext, name, entry, conflict)
+    }
+
+    /// Encrypts `plaintext` from memory into the stash as `name`.
+    ///
+    /// Fills in the times, size and hash of `entry` before recording it.
+    ///
+    fn store_bytes(
+        &mut self,
+        plaintext: &[u8],
+        name: &str,
+        entry: Entry,
+        conflict: Conflict,
+    ) -> Result<String, Error> {
+        //
+        let secret = Secret::new();
+        let hash = format!("{:x}", Sha256::digest(plaintext));
+        let chunking = self.chunking || chunks::is_stub(&self.path.join(name));
+        let mut compression = None;
+        let (ciphertext, chunks) = if chunking {
+            (chunks::STUB.to_vec(), self.chunk_store()?.put(plaintext)?)
+        } else if let Some(wanted) = self.pick_compression(plaintext, entry.compression)? {
+            let packed = Self::compress(plaintext, wanted)?;
+            compression = packed.as_ref().map(|_| wanted);
+            let sealed = packed.as_deref().map_or(plaintext, |p| p);
+            (Self::encrypt_bytes(sealed, &secret)?, Vec::new())
+        } else {
+            (Self::encrypt_bytes(plaintext, &secret)?, Vec::new())
+        };
+
+        //  Only claim the name once there is something to store
+        //
+        let (name, revisions) = self.claim(name, conflict)?;
+        fs::write(self.path.join(&name), ciphertext)
+            .map_err(|err| Error::other(format!("Failed to write encrypted file: {}", err)))?;
+
+        let entry = Entry {
+            modified: now(),
+            stashed: now(),
+            hash,
+            size: plaintext.len() as u64,
+            chunks,
+            compression,
+            ..entry
+        };
+        self.record(&name, &secret, entry, revisions)?;
+
+        Ok(name)
+    }
+
+    /// Resolves a collision on `name` according to `conflict`.
+    ///
+    /// Returns the name to use, along with the revisions it should carry
+    /// over, and makes sure its parent directory exists.
+    ///
+    fn claim(&mut self, name: &str, conflict: Conflict) -> Result<(String, Vec<Revision>), Error> {
+        //
+        let mut name = name.to_string();
+        let mut revisions = Vec::new();
+
+        if self.exists(&name) {
+            match conflict {
+                Conflict::Error => {
+                    return Err(Error::new(
+                        ErrorKind::AlreadyExists,
+                        "File already in stash",
+                    ));
+                }
+                Conflict::Overwrite => self.discard(&name)?,
+                Conflict::Rename => {
+                    name = (1..)
+                        .map(|n| numbered(&name, n))
+                        .find(|candidate| !self.exists(candidate))
+                        .unwrap();
+                }
+                Conflict::Version => revisions = self.retire(&name)?,
+            }
+        }
+        if let Some(parent) = self.path.join(&name).parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        Ok((name, revisions))
+    }
+
+    /// Records the secret and metadata of a newly encrypted `name`.
+    ///
+    fn record(
+        &mut self,
+        name: &str,
+        secret: &Secret,
+        entry: Entry,
+        revisions: Vec<Revision>,
+    ) -> Result<(), Error> {
+        //
+        //  Number this revision after the ones it replaces
+        //
+        let revision = revisions.last().map_or(1, |rev| rev.number + 1);
+
+        //  A new revision keeps the tags and note of the one it replaces
+        //
+        let labels = match (entry.labels.is_empty(), revisions.is_empty()) {
+            (true, false) => self.get_entry(name)?.unwrap_or_default().labels,
+            _ => entry.labels,
+        };
+        let entry = Entry {
+            revision,
+            revisions,
+            labels,
+            ..entry
+        };
+
+        self.register(name, secret, &entry)
+    }
+
+    /// Records the secret and metadata of `name` in the database and keyring.
+    ///
+    fn register(&mut self, name: &str, secret: &Secret, entry: &Entry) -> Result<(), Error> {
+        //
+        //  Add filename and secret to database
+        //
+        self.db
+            .insert(name.as_bytes(), secret.join())
+            .map_err(|err| {
+                Error::other(format!("Failed to add encryption key to database: {}", err))
+            })?;
+
+        //  Record original path and file metadata
+        //
+        self.put_entry(name, entry)?;
+
+        //  Cache filename and secret in keyring
+        //
+        self.keyring
+            .add_key(name, &secret.join())
+            .map_err(|err| Error::other(format!("Failed to cache encryption key: {}", err)))?;
+
+        Ok(())
+    }
+
+    /// Keeps the current ciphertext of `name` as its latest prior revision.
+    ///
+    /// Returns the entry's revisions, ready to carry over to its replacement.
+    ///
+    fn retire(&mut self, name: &str) -> Result<Vec<Revision>, Error> {
+        //
+        let secret = self.get_secret(name, true)?;
+        let entry = self.get_entry(name)?.unwrap_or_default();
+        let mut revisions = entry.revisions;
+        let number = match entry.revision {
+            0 => revisions.last().map_or(1, |rev| rev.number + 1),
+            number => number,
+        };
+
+        //  Move ciphertext out of the way
+        //
+        let rev_path = self.revision_path(name, number);
+        if let Some(parent) = rev_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(self.path.join(name), &rev_path)
+            .map_err(|err| Error::other(format!("Failed to keep previous revision: {}", err)))?;
+
+        revisions.push(Revision {
+            number,
+            stashed: entry.stashed,
+            modified: entry.modified,
+            size: entry.size,
+            hash: entry.hash,
+            chunks: entry.chunks,
+            compression: entry.compression,
+            secret,
+            sealed: Vec::new(),
+        });
+
+        Ok(revisions)
+    }
+
+    /// Returns the path holding revision `number` of `name`.
+    ///
+    fn revision_path(&self, name: &str, number: u32) -> PathBuf {
+        self.path.join(".revs").join(name).join(number.to_string())
+    }
+
+    /// Removes `name` from the stash entirely, along with its revisions.
+    ///
+    fn discard(&mut self, name: &str) -> Result<(), Error> {
+        //
+        let target_path = self.path.join(name);
+        if target_path.is_file() {
+            fs::remove_file(&target_path)?;
+        }
+        if let Some(parent) = target_path.parent() {
+            self.prune_dirs(parent);
+        }
+        self.remove_revisions(name)?;
+
+        //  Remove file secret and metadata from database
+        //
+        self.db
+            .remove(name)
+            .map_err(|err| Error::other(format!("Failed to remove file from database: {}", err)))?;
+        self.remove_entry(name)?;
+
+        //  Remove file secret from keyring if cached
+        //
+        if let Ok(key) = self.keyring.search(name) {
+            key.invalidate()
+                .map_err(|err| Error::other(format!("Failed to remove key from cache: {}", err)))?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the stored revisions of `name`, if any.
+    ///
+    fn remove_revisions(&self, name: &str) -> Result<(), Error> {
+        //
+        let revs_path = self.path.join(".revs").join(name);
+        if revs_path.is_dir() {
+            fs::remove_dir_all(&revs_path)?;
+            if let Some(parent) = revs_path.parent() {
+                self.prune_dirs(parent);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Move a file from the stash into the current directory.
+    ///
+    /// Returns the name the file was dropped under, which only differs from
+    /// `file` when `conflict` is `Conflict::Rename`.
+    ///
+    pub fn grab(&mut self, file: &str, copy: bool, conflict: Conflict) -> Result<String, Error> {
+        let cwd = env::current_dir()?;
+        self.grab_to(file, &cwd, copy, conflict)
+    }
+
+    /// Move a file from the stash to `dst`, optionally as a copy.
+    ///
+    /// If `dst` is a directory, the file is dropped inside it under its
+    /// stashed name. Returns the name the file was dropped under within
+    /// that directory.
+    ///
+    pub fn grab_to(
+        &mut self,
+        file: &str,
+        dst: &Path,
+        copy: bool,
+        conflict: Conflict,
+    ) -> Result<String, Error> {
+        //
+        Self::check_name(file)?;
+
+        //  Make sure the file is stashed before touching the destination
+        //
+        if !self.path.join(file).is_file() {
+            return Err(Error::new(ErrorKind::NotFound, "File not found in stash"));
+        }
+        let (dir, name) = Self::split_dst(file, dst)?;
+        let dst = Self::resolve_dst(&dir, &name, conflict)?;
+        self.release_file(file, &dir.join(&dst), copy)?;
+
+        Ok(dst)
+    }
+
+    /// Move every file under a stashed directory into the current directory.
+    ///
+    /// The directory tree is rebuilt along with each file's recorded metadata.
+    ///
+    pub fn grab_dir(
+        &mut self,
+        dir: &str,
+        copy: bool,
+        conflict: Conflict,
+    ) -> Result<Vec<String>, Error> {
+        //
+        let prefix = format!("{}/", dir.trim_end_matches('/'));
+        Self::check_name(&prefix)?;
+
+        //  Find all entries under the logical directory
+        //
+        let names: Vec<String> = self
+            .names()?
+            .into_iter()
+            .filter(|name| name.starts_with(&prefix))
+            .collect();
+        if names.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "Directory not found in stash",
+            ));
+        }
+
+        //  Refuse to overwrite any existing file
+        //
+        let cwd = env::current_dir()?;
+        if conflict == Conflict::Error {
+            if let Some(name) = names.iter().find(|name| cwd.join(name).exists()) {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("File already exists in current directory: {}", name),
+                ));
+            }
+        }
+
+        let mut dsts = Vec::with_capacity(names.len());
+        for name in &names {
+            let dst = Self::resolve_dst(&cwd, name, conflict)?;
+            self.release_file(name, &cwd.join(&dst), copy)?;
+            dsts.push(dst);
+        }
+
+        Ok(dsts)
+    }
+
+    /// Splits a destination into a directory and a file name within it.
+    ///
+    /// Directories keep the stashed name of `file`; anything else is taken
+    /// as the path of the file itself.
+    ///
+    fn split_dst(file: &str, dst: &Path) -> Result<(PathBuf, String), Error> {
+        //
+        if dst.is_dir() {
+            return Ok((dst.to_path_buf(), file.to_string()));
+        }
+        let name = dst
+            .file_name()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Invalid destination path"))?
+            .to_string_lossy()
+            .to_string();
+        let dir = match dst.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+
+        Ok((dir, name))
+    }
+
+    /// Writes the decrypted contents of `file` to `out`.
+    ///
+    /// The plaintext only ever exists in memory, and is wiped once written.
+    ///
+    pub fn cat<W: Write>(&self, file: &str, out: &mut W) -> Result<(), Error> {
+        //
+        Self::check_name(file)?;
+        let src_path = self.path.join(file);
+        if !src_path.is_file() {
+            return Err(Error::new(ErrorKind::NotFound, "File not found in stash"));
+        }
+        let secret = self.get_secret(file, false)?;
+        let entry = self.get_entry(file)?.unwrap_or_default();
+        let plaintext = self.read_stored(&src_path, &secret, Some(&entry))?;
+
+        out.write_all(&plaintext)?;
+        out.flush()
+    }
+
+    /// Opens a decrypted copy of `file` in `editor`, and stashes any changes.
+    ///
+    /// The copy lives in a private directory on tmpfs, which is wiped once
+    /// the editor exits, however it exits. Changes are stashed as a new
+    /// revision of `file`. With `new`, `file` must not be stashed yet and the
+    /// editor starts from an empty file. Returns whether anything changed.
+    ///
+    pub fn edit(&mut self, file: &str, editor: &str, new: bool) -> Result<bool, Error> {
+        //
+        Self::check_name(file)?;
+        if file.ends_with('/') || Self::is_reserved(file) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid file name: {}", file),
+            ));
+        }
+
+        //  Start from the current plaintext, or from nothing
+        //
+        let exists = self.path.join(file).is_file();
+        let (plaintext, entry) = match (exists, new) {
+            (true, true) => {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    "File already in stash",
+                ));
+            }
+            (false, false) => {
+                return Err(Error::new(ErrorKind::NotFound, "File not found in stash"));
+            }
+            (true, false) => {
+                let entry = self.get_entry(file)?.unwrap_or_default();
+                if entry.symlink {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "Cannot edit a stashed symbolic link",
+                    ));
+                }
+                let secret = self.get_secret(file, false)?;
+                let plaintext = self.read_stored(&self.path.join(file), &secret, Some(&entry))?;
+                (plaintext, entry)
+            }
+            (false, true) => (
+                Zeroizing::new(Vec::new()),
+                Entry {
+                    original: "-".to_string(),
+                    mode: 0o600,
+                    ..Entry::default()
+                },
+            ),
+        };
+        let before = Sha256::digest(&*plaintext);
+
+        //  Keep the file name so editors can pick a syntax
+        //
+        Scratch::sweep("stash-edit");
+        let scratch = Scratch::new("stash-edit")?;
+        let base = file.rsplit('/').next().unwrap_or(file);
+        let path = scratch.write(base, &plaintext)?;
+        drop(plaintext);
+
+        //  Let the shell split `editor` so it can carry its own arguments, and
+        //  outlast it however it's stopped, so the plaintext is always wiped
+        //
+        let shield = Shield::new();
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$1\"", editor))
+            .arg("sh")
+            .arg(&path)
+            .status()
+            .map_err(|err| Error::other(format!("Failed to launch editor: {}", err)))?;
+        drop(shield);
+        if !status.success() {
+            return Err(Error::other(format!(
+                "Editor exited unsuccessfully ({}), changes discarded",
+                status
+            )));
+        }
+
+        //  Only stash a new revision if the contents changed
+        //
+        let edited = Zeroizing::new(
+            fs::read(&path)
+                .map_err(|err| Error::other(format!("Failed to read edited file: {}", err)))?,
+        );
+        if Sha256::digest(&*edited) == before {
+            return Ok(false);
+        }
+        self.store_bytes(&edited, file, entry, Conflict::Version)?;
+
+        Ok(true)
+    }
+
+    /// Runs `cmd` with stashed files available as paths in its environment.
+    ///
+    /// Each `(var, file)` pair decrypts `file` into a sealed in-memory file
+    /// and sets `var` to a `/proc/self/fd/N` path the child can open. Nothing
+    /// is written to disk, and the plaintext is wiped once the child exits.
+    ///
+    pub fn exec(&self, files: &[(String, String)], cmd: &[String]) -> Result<ExitStatus, Error> {
+        //
+        let (program, args) = cmd
+            .split_first()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "No command given"))?;
+        let mut command = Command::new(program);
+        command.args(args);
+
+        //  Decrypt every file before starting anything
+        //
+        let mut sealed = Vec::new();
+        for (var, file) in files {
+            Self::check_name(file)?;
+            let src_path = self.path.join(file);
+            if !src_path.is_file() {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("File not found in stash: {}", file),
+                ));
+            }
+            let secret = self.get_secret(file, false)?;
+            let entry = self.get_entry(file)?.unwrap_or_default();
+            let plaintext = self.read_stored(&src_path, &secret, Some(&entry))?;
+            let memfd = Sealed::new(sealed.len(), &plaintext)?;
+
+            command.env(var, memfd.path());
+            sealed.push(memfd);
+        }
+
+        //  Descriptors stay open until the child is done with them
+        //
+        let status = command
+            .status()
+            .map_err(|err| Error::other(format!("Failed to run `{}`: {}", program, err)));
+        drop(sealed);
+
+        status
+    }
+
+    /// Mounts a read-only, decrypted view of the stash at `dir`.
+    ///
+    /// Blocks serving the view until it is unmounted, either by `umount` or
+    /// because the login session was locked. The stash itself is closed
+    /// first so other commands can still use it, which means the view shows
+    /// the stash as it was when mounted. Only the user who mounted the view
+    /// can see into it.
+    ///
+    /// Chunked files are decrypted a chunk at a time as they are read. Any
+    /// other file is sealed as a whole, so it is decrypted into memory when
+    /// opened, since none of it can be trusted before all of it is checked.
+    ///
+    pub fn mount(self, dir: &Path) -> Result<(), Error> {
+        //
+        if !dir.is_dir() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "Mount point is not a directory",
+            ));
+        }
+        let dir = std::path::absolute(dir)?;
+
+        //  Gather everything needed to decrypt before letting go of the database
+        //
+        let mut files = Vec::new();
+        let mut secrets = HashMap::new();
+        let mut chunked = HashMap::new();
+        let mut compressed = HashMap::new();
+        let store = self.chunk_store()?;
+        for name in self.names()? {
+            let Ok(meta) = fs::metadata(self.path.join(&name)) else {
+                continue;
+            };
+            let entry = self.get_entry(&name)?.unwrap_or_default();
+            let modified = match entry.modified {
+                0 => meta.mtime().max(0) as u64,
+                modified => modified,
+            };
+            if let Some(compression) = entry.compression {
+                compressed.insert(name.clone(), compression);
+            }
+            let size = if chunks::is_stub(&self.path.join(&name)) {
+                chunked.insert(name.clone(), entry.chunks);
+                entry.size
+            } else if entry.compression.is_some() {
+                entry.size
+            } else {
+                meta.len().saturating_sub(16)
+            };
+            secrets.insert(name.clone(), self.get_secret(&name, false)?);
+            files.push(fuse::FileInfo {
+                name,
+                size,
+                modified,
+                symlink: entry.symlink,
+            });
+        }
+        let store = store.detach(chunked.values().flatten())?;
+        let root = self.path.clone();
+        drop(self);
+
+        let owner = Self::owner();
+        let mount = fuse::mount(&dir, files, owner, move |name| {
+            let compression = compressed.get(name).copied();
+            let contents: Box<dyn fuse::Contents> = match chunked.get(name) {
+                Some(chunks) if compression.is_none() => Box::new(store.reader(chunks)?),
+                Some(chunks) => Box::new(Self::decompress(store.get(chunks)?, compression)?),
+                None => {
+                    let secret = secrets
+                        .get(name)
+                        .ok_or_else(|| Error::new(ErrorKind::NotFound, "Secret not found"))?;
+                    let plaintext = Self::decrypt_bytes(&root.join(name), secret)?;
+                    Box::new(Self::decompress(plaintext, compression)?)
+                }
+            };
+            Ok(contents)
+        })?;
+        Self::watch_session(&dir, owner.0);
+        let result = mount.serve();
+
+        //  Don't leave a dead mount behind if serving failed
+        //
+        if fuse::mounts().contains(&dir) {
+            let _ = fuse::unmount(&dir);
+        }
+        result
+    }
+
+    /// Unmounts the view of the stash at `dir`, or every view if `None`.
+    ///
+    /// Returns the mount points that were unmounted.
+    ///
+    pub fn umount(dir: Option<&Path>) -> Result<Vec<PathBuf>, Error> {
+        //
+        let mounted = fuse::mounts();
+        let targets = match dir {
+            Some(dir) => {
+                let dir = std::path::absolute(dir)?;
+                if !mounted.contains(&dir) {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!("No stash mounted at {}", dir.display()),
+                    ));
+                }
+                vec![dir]
+            }
+            None => mounted,
+        };
+        for target in &targets {
+            fuse::unmount(target)?;
+        }
+
+        Ok(targets)
+    }
+
+    /// Returns the user and group a mount should belong to.
+    ///
+    /// That's whoever ran `sudo` to become the `stash` user, if anyone.
+    ///
+    fn owner() -> (u32, u32) {
+        //
+        let id = |var: &str| env::var(var).ok().and_then(|id| id.parse().ok());
+        // SAFETY: `getuid` and `getgid` can't fail.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+
+        (id("SUDO_UID").unwrap_or(uid), id("SUDO_GID").unwrap_or(gid))
+    }
+
+    /// Unmounts `dir` once the login session of `uid` is locked.
+    ///
+    /// Asks `loginctl` every few seconds, so does nothing without systemd.
+    ///
+    fn watch_session(dir: &Path, uid: u32) {
+        //
+        let loginctl = |args: &[&str]| {
+            Command::new("loginctl")
+                .args(args)
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        };
+        let session = env::var("XDG_SESSION_ID")
+            .ok()
+            .or_else(|| loginctl(&["show-user", &uid.to_string(), "-p", "Display", "--value"]));
+        let Some(session) = session.filter(|session| !session.is_empty()) else {
+            return;
+        };
+
+        let dir = dir.to_path_buf();
+        thread::spawn(move || {
+            while fuse::mounts().contains(&dir) {
+                let locked = loginctl(&["show-session", &session, "-p", "LockedHint", "--value"]);
+                if locked.as_deref() == Some("yes") {
+                    let _ = fuse::unmount(&dir);
+                    break;
+                }
+                thread::sleep(Duration::from_secs(5));
+            }
+        });
+    }
+
+    /// Decrypts the files matching `patterns` into a new workspace on tmpfs.
+    ///
+    /// Every file is decrypted when `patterns` is empty. The workspace is
+    /// recorded in the database, so it stays open until `close` is called.
+    /// Returns its path.
+    ///
+    pub fn open(&mut self, patterns: &[String]) -> Result<PathBuf, Error> {
+        //
+        //  Collect the selected names, once each
+        //
+        let mut names = Vec::new();
+        if patterns.is_empty() {
+            names = self.names()?;
+        }
+        for pattern in patterns {
+            for name in self.find(pattern)? {
+                if !self.path.join(&name).is_file() {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!("File not found in stash: {}", name),
+                    ));
+                }
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+
+        //  Decrypt each file, skipping links and anything not really there
+        //
+        let scratch = Scratch::new("stash")?;
+        let mut workspace = Workspace {
+            opened: now(),
+            ..Workspace::default()
+        };
+        for name in names {
+            let src_path = self.path.join(&name);
+            let entry = self.get_entry(&name)?.unwrap_or_default();
+            if entry.symlink || !src_path.is_file() {
+                continue;
+            }
+            let secret = self.get_secret(&name, false)?;
+            let plaintext = self.read_stored(&src_path, &secret, Some(&entry))?;
+            scratch.write(&name, &plaintext)?;
+            workspace
+                .files
+                .insert(name, format!("{:x}", Sha256::digest(&*plaintext)));
+        }
+
+        //  Only keep the workspace once it's on record
+        //
+        let key = scratch.path.to_string_lossy().to_string();
+        self.put_workspace(&key, &workspace)?;
+
+        Ok(scratch.keep())
+    }
+
+    /// Stashes the changes made in a workspace, then wipes it.
+    ///
+    /// Changed files become new revisions, new files are added, and files
+    /// removed from the workspace are deleted from the stash. With `discard`,
+    /// the workspace is wiped without stashing anything. `dir` may be left
+    /// out when only one workspace is open. Returns what changed.
+    ///
+    pub fn close(&mut self, dir: Option<&Path>, discard: bool) -> Result<Vec<Change>, Error> {
+        //
+        let (key, workspace) = self.get_workspace(dir)?;
+        let path = PathBuf::from(&key);
+
+        //  A workspace that's gone (after a reboot, say) holds no changes
+        //
+        let changes = if discard || !path.is_dir() {
+            Vec::new()
+        } else {
+            Self::diff(&path, &workspace)?
+        };
+        for change in &changes {
+            match change {
+                Change::Modified(name) | Change::Added(name) => {
+                    let plaintext = Zeroizing::new(fs::read(path.join(name))?);
+                    let entry = match self.get_entry(name)? {
+                        Some(entry) => entry,
+                        None => Entry {
+                            original: "-".to_string(),
+                            mode: 0o600,
+                            ..Entry::default()
+                        },
+                    };
+                    self.store_bytes(&plaintext, name, entry, Conflict::Version)?;
+                }
+                Change::Deleted(name) => {
+                    if self.exists(name) {
+                        self.delete(name)?;
+                    }
+                }
+            }
+        }
+
+        //  Wipe the workspace, then forget it
+        //
+        drop(Scratch { path });
+        self.workspaces.remove(&key).map_err(|err| {
+            Error::other(format!("Failed to remove workspace from database: {}", err))
+        })?;
+
+        Ok(changes)
+    }
+
+    /// Lists open workspaces, along with their changes so far.
+    ///
+    pub fn status(&self) -> Result<Vec<WorkspaceStatus>, Error> {
+        //
+        let mut status = Vec::new();
+        for item in self.workspaces.iter() {
+            let (key, value) =
+                item.map_err(|err| Error::other(format!("Failed to read workspaces: {}", err)))?;
+            let workspace = Self::parse_workspace(&value)?;
+            let path = PathBuf::from(String::from_utf8_lossy(&key).to_string());
+            let missing = !path.is_dir();
+            let changes = if missing {
+                Vec::new()
+            } else {
+                Self::diff(&path, &workspace)?
+            };
+
+            status.push(WorkspaceStatus {
+                path,
+                opened: workspace.opened,
+                missing,
+                changes,
+            });
+        }
+
+        Ok(status)
+    }
+
+    /// Compares the files in the workspace at `path` with its record.
+    ///
+    fn diff(path: &Path, workspace: &Workspace) -> Result<Vec<Change>, Error> {
+        //
+        let mut found = Vec::new();
+        Self::walk_workspace(path, "", &mut found)?;
+
+        let mut changes = Vec::new();
+        for name in &found {
+            match workspace.files.get(name) {
+                Some(hash) if *hash == Self::hash_file(&path.join(name))? => {}
+                Some(_) => changes.push(Change::Modified(name.clone())),
+                None => changes.push(Change::Added(name.clone())),
+            }
+        }
+        for name in workspace.files.keys() {
+            if !found.contains(name) {
+                changes.push(Change::Deleted(name.clone()));
+            }
+        }
+
+        Ok(changes)
+    }
+
+    /// Collects the logical names of the regular files under `dir`.
+    ///
+    fn walk_workspace(dir: &Path, prefix: &str, found: &mut Vec<String>) -> Result<(), Error> {
+        //
+        let mut items: Vec<_> = fs::read_dir(dir)?.collect::<Result<_, _>>()?;
+        items.sort_by_key(|item| item.file_name());
+
+        for item in items {
+            let name = format!("{}{}", prefix, item.file_name().to_string_lossy());
+            let kind = item.file_type()?;
+            if kind.is_dir() {
+                Self::walk_workspace(&item.path(), &format!("{}/", name), found)?;
+            } else if kind.is_file() && !Self::is_reserved(&name) {
+                found.push(name);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Looks up the workspace at `dir`, or the only one open if `None`.
+    ///
+    fn get_workspace(&self, dir: Option<&Path>) -> Result<(String, Workspace), Error> {
+        //
+        let value =
+            match dir {
+                Some(dir) => {
+                    let key = std::path::absolute(dir)?.to_string_lossy().to_string();
+                    self.workspaces
+                        .get(&key)
+                        .map_err(|err| Error::other(format!("Failed to read workspace: {}", err)))?
+                        .map(|value| (key, value))
+                }
+                None => {
+                    let mut all = self.workspaces.iter();
+                    match (all.next(), all.next()) {
+                        (Some(item), None) => Some(item.map(|(key, value)| {
+                            (String::from_utf8_lossy(&key).to_string(), value)
+                        })?),
+                        (Some(_), Some(_)) => {
+                            return Err(Error::new(
+                                ErrorKind::InvalidInput,
+                                "Several workspaces are open. Say which one to close",
+                            ));
+                        }
+                        _ => None,
+                    }
+                }
+            };
+        let (key, value) =
+            value.ok_or_else(|| Error::new(ErrorKind::NotFound, "No such workspace is open"))?;
+
+        Ok((key, Self::parse_workspace(&value)?))
+    }
+
+    /// Parses a workspace record.
+    ///
+    fn parse_workspace(value: &[u8]) -> Result<Workspace, Error> {
+        serde_json::from_slice(value).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Failed to parse workspace: {}", err),
+            )
+        })
+    }
+
+    /// Records the workspace at `key`.
+    ///
+    fn put_workspace(&self, key: &str, workspace: &Workspace) -> Result<(), Error> {
+        //
+        let value = serde_json::to_vec(workspace)
+            .map_err(|err| Error::other(format!("Failed to serialize workspace: {}", err)))?;
+        self.workspaces
+            .insert(key, value)
+            .map_err(|err| Error::other(format!("Failed to add workspace to database: {}", err)))?;
+
+        Ok(())
+    }
+
+    /// Sets variables in the environment of `project`, creating it if needed.
+    ///
+    pub fn env_set(&mut self, project: &str, vars: &[(String, String)]) -> Result<(), Error> {
+        //
+        if project.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid project name"));
+        }
+        for (key, _) in vars {
+            check_env_key(key)?;
+        }
+        let mut env = self.get_env(project)?.unwrap_or_default();
+        for (key, value) in vars {
+            env.0.insert(key.clone(), value.clone());
+        }
+
+        self.put_env(project, &env)
+    }
+
+    /// Reads variables from the `.env`-style file at `path` into `project`.
+    ///
+    /// Returns the number of variables imported.
+    ///
+    pub fn env_import(&mut self, project: &str, path: &Path) -> Result<usize, Error> {
+        //
+        let text =
+            Zeroizing::new(fs::read_to_string(path).map_err(|err| {
+                Error::other(format!("Failed to read environment file: {}", err))
+            })?);
+        let mut vars = parse_dotenv(&text)?;
+        let count = vars.len();
+        let result = self.env_set(project, &vars);
+
+        for (_, value) in vars.iter_mut() {
+            value.zeroize();
+        }
+        result.map(|_| count)
+    }
+
+    /// Removes variables from `project`, or the whole project if `keys` is empty.
+    ///
+    pub fn env_unset(&mut self, project: &str, keys: &[String]) -> Result<(), Error> {
+        //
+        let mut env = self
+            .get_env(project)?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Project not found in stash"))?;
+        for key in keys {
+            if env.0.remove(key).is_none() {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("Variable not found: {}", key),
+                ));
+            }
+        }
+
+        if keys.is_empty() || env.0.is_empty() {
+            self.envs.remove(project).map_err(|err| {
+                Error::other(format!(
+                    "Failed to remove environment from database: {}",
+                    err
+                ))
+            })?;
+            Ok(())
+        } else {
+            self.put_env(project, &env)
+        }
+    }
+
+    /// Lists the variable names of `project`, or the projects themselves.
+    ///
+    /// Values are never returned.
+    ///
+    pub fn env_list(&self, project: Option<&str>) -> Result<Vec<String>, Error> {
+        //
+        match project {
+            Some(project) => {
+                let env = self
+                    .get_env(project)?
+                    .ok_or_else(|| Error::new(ErrorKind::NotFound, "Project not found in stash"))?;
+                Ok(env.0.keys().cloned().collect())
+            }
+            None => self
+                .envs
+                .iter()
+                .keys()
+                .map(|key| {
+                    key.map(|key| String::from_utf8_lossy(&key).to_string())
+                        .map_err(|err| {
+                            Error::other(format!("Failed to read environments: {}", err))
+                        })
+                })
+                .collect(),
+        }
+    }
+
+    /// Runs `cmd` with the variables of `project` added to its environment.
+    ///
+    pub fn run(&self, project: &str, cmd: &[String]) -> Result<ExitStatus, Error> {
+        //
+        let (program, args) = cmd
+            .split_first()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "No command given"))?;
+        let env = self
+            .get_env(project)?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Project not found in stash"))?;
+
+        Command::new(program)
+            .args(args)
+            .envs(env.0.iter())
+            .status()
+            .map_err(|err| Error::other(format!("Failed to run `{}`: {}", program, err)))
+    }
+
+    /// Decrypts the variables stored for `project`, if any.
+    ///
+    fn get_env(&self, project: &str) -> Result<Option<EnvVars>, Error> {
+        //
+        let value = self
+            .envs
+            .get(project)
+            .map_err(|err| Error::other(format!("Failed to read environment: {}", err)))?;
+        let Some(value) = value else {
+            return Ok(None);
+        };
+        if value.len() < 44 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Stored environment is corrupt",
+            ));
+        }
+
+        //  Secret is stored ahead of the ciphertext it opens
+        //
+        let (secret, ciphertext) = value.split_at(44);
+        let (key, nonce) = Secret::from(secret).split();
+        let mut buffer = Zeroizing::new(ciphertext.to_vec());
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(&key));
+        cipher
+            .decrypt_in_place(GenericArray::from_slice(&nonce), b"", &mut *buffer)
+            .map_err(|err| Error::other(format!("Failed to decrypt environment: {}", err)))?;
+
+        serde_json::from_slice(&buffer).map(Some).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Failed to parse environment: {}", err),
+            )
+        })
+    }
+
+    /// Encrypts `env` under a fresh secret and stores it for `project`.
+    ///
+    fn put_env(&self, project: &str, env: &EnvVars) -> Result<(), Error> {
+        //
+        let plaintext =
+            Zeroizing::new(serde_json::to_vec(env).map_err(|err| {
+                Error::other(format!("Failed to serialize environment: {}", err))
+            })?);
+        let secret = Secret::new();
+        let mut value = secret.join();
+        value.extend(Self::encrypt_bytes(&plaintext, &secret)?);
+
+        self.envs.insert(project, value).map_err(|err| {
+            Error::other(format!("Failed to add environment to database: {}", err))
+        })?;
+
+        Ok(())
+    }
+
+    /// Picks where `name` should land inside `dir` according to `conflict`.
+    ///
+    /// With `Conflict::Version`, an existing file is first moved aside to a
+    /// numbered backup such as `name.~1~`.
+    ///
+    fn resolve_dst(dir: &Path, name: &str, conflict: Conflict) -> Result<String, Error> {
+        //
+        let taken = |name: &str| fs::symlink_metadata(dir.join(name)).is_ok();
+        if !taken(name) {
+            return Ok(name.to_string());
+        }
+
+        match conflict {
+            Conflict::Error => Err(Error::new(
+                ErrorKind::AlreadyExists,
+                "File already exists in current directory",
+            )),
+            Conflict::Overwrite => Ok(name.to_string()),
+            Conflict::Rename => Ok((1..)
+                .map(|n| numbered(name, n))
+                .find(|candidate| !taken(candidate))
+                .unwrap()),
+            Conflict::Version => {
+                let backup = (1..)
+                    .map(|n| format!("{}.~{}~", name, n))
+                    .find(|candidate| !taken(candidate))
+                    .unwrap();
+                fs::rename(dir.join(name), dir.join(backup)).map_err(|err| {
+                    Error::other(format!("Failed to back up existing file: {}", err))
+                })?;
+                Ok(name.to_string())
+            }
+        }
+    }
+
+    /// Decrypts the stashed entry `name` to `dst`, optionally as a copy.
+    ///
+    /// The file is decrypted next to `dst` and renamed into place, so an
+    /// existing destination is only ever replaced whole.
+    ///
+    fn release_file(&mut self, name: &str, dst: &Path, copy: bool) -> Result<(), Error> {
+        //
+        let src_path = self.path.join(name);
+        let secret = self.get_secret(name, !copy)?;
+        let entry = self.get_entry(name)?;
+
+        //  Decrypt a copy, leaving the stashed file untouched until done
+        //
+        self.decrypt_to(&src_path, dst, &secret, entry.as_ref())
+            .map_err(|err| Error::other(format!("Failed to decrypt file: {}", err)))?;
+
+        //  Remove stashed file, encryption secrets and metadata
+        //
+        if !copy {
+            self.discard(name)?;
+        }
+
+        Ok(())
+    }
+
+    /// Decrypts a copy of `src` next to `dst` and renames it into place.
+    ///
+    fn decrypt_to(
+        &self,
+        src: &Path,
+        dst: &Path,
+        secret: &Secret,
+        entry: Option<&Entry>,
+    ) -> Result<(), Error> {
+        //
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file_name = dst.file_name().unwrap_or_default().to_string_lossy();
+        let tmp_path = dst.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+
+        let result = self
+            .decrypt_copy(src, &tmp_path, secret, entry)
+            .and_then(|_| fs::rename(&tmp_path, dst));
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+
+        result
+    }
+
+    /// Decrypts a copy of `src` to `dst`, recreating links and metadata.
+    ///
+    fn decrypt_copy(
+        &self,
+        src: &Path,
+        dst: &Path,
+        secret: &Secret,
+        entry: Option<&Entry>,
+    ) -> Result<(), Error> {
+        //
+        if chunks::is_stub(src) || entry.is_some_and(|entry| entry.compression.is_some()) {
+            let plaintext = self.read_stored(src, secret, entry)?;
+            fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(dst)?
+                .write_all(&plaintext)?;
+        } else {
+            fs::copy(src, dst)?;
+            Self::decrypt(dst, secret)?;
+        }
+
+        //  Recreate links and restore original metadata
+        //
+        if let Some(entry) = entry {
+            if entry.symlink {
+                let target = fs::read(dst)?;
+                fs::remove_file(dst)?;
+                symlink(OsStr::from_bytes(&target), dst)?;
+            } else {
+                entry.restore(dst)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Looks up the secret for `name`, optionally removing it from the keyring.
+    ///
+    fn get_secret(&self, name: &str, uncache: bool) -> Result<Secret, Error> {
+        //
+        //  Get secret from keyring if it's there
+        //
+        if let Ok(key) = self.keyring.search(name) {
+            let secret =
+                Secret::from(&key.read_to_vec().map_err(|err| {
+                    Error::other(format!("Failed to read encryption key: {}", err))
+                })?);
+            //      Remove secret if requested
+            //
+            if uncache {
+                key.invalidate()
+                    .map_err(|err| Error::other(format!("Failed to remove cached key: {}", err)))?;
+            }
+            Ok(secret)
+            //  Or check database for file secrets
+            //
+        } else if let Some(value) = self.db.get(name)? {
+            Ok(Secret::from(&value))
+            //
+            //      Or throw
+        } else {
+            Err(Error::new(ErrorKind::NotFound, "Secret not found"))
+        }
+    }
+
+    /// Retrieves the recorded metadata for `name`, if any.
+    ///
+    fn get_entry(&self, name: &str) -> Result<Option<Entry>, Error> {
+        //
+        let value = self
+            .entries
+            .get(name)
+            .map_err(|err| Error::other(format!("Failed to read file metadata: {}", err)))?;
+
+        let Some(bytes) = value else {
+            return Ok(None);
+        };
+        let mut entry: Entry = serde_json::from_slice(&bytes).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Failed to parse file metadata: {}", err),
+            )
+        })?;
+
+        //  Unseal the secrets of prior revisions
+        //
+        if entry.revisions.iter().any(|rev| !rev.sealed.is_empty()) {
+            let kek = self.revision_key(name, false)?;
+            for rev in &mut entry.revisions {
+                rev.secret = Self::open_secret(&kek, rev)?;
+                rev.sealed.clear();
+            }
+        }
+
+        Ok(Some(entry))
+    }
+
+    /// Records metadata for `name`, sealing the secrets of its prior
+    /// revisions under its key-encryption key.
+    ///
+    fn put_entry(&self, name: &str, entry: &Entry) -> Result<(), Error> {
+        //
+        let mut entry = entry.clone();
+        if entry.revisions.is_empty() {
+            self.remove_revision_key(name)?;
+        } else {
+            let kek = self.revision_key(name, true)?;
+            for rev in &mut entry.revisions {
+                if !rev.secret.is_empty() {
+                    rev.sealed = Self::seal_secret(&kek, rev)?;
+                    rev.secret.key.zeroize();
+                    rev.secret.nonce.zeroize();
+                    rev.secret = Secret::default();
+                }
+            }
+        }
+        let value = serde_json::to_vec(&entry)
+            .map_err(|err| Error::other(format!("Failed to serialize file metadata: {}", err)))?;
+        self.entries.insert(name, value).map_err(|err| {
+            Error::other(format!("Failed to add file metadata to database: {}", err))
+        })?;
+
+        Ok(())
+    }
+
+    /// Removes recorded metadata for `name`, along with its key-encryption
+    /// key.
+    ///
+    fn remove_entry(&self, name: &str) -> Result<(), Error> {
+        //
+        self.entries.remove(name).map_err(|err| {
+            Error::other(format!(
+                "Failed to remove file metadata from database: {}",
+                err
+            ))
+        })?;
+
+        self.remove_revision_key(name)
+    }
+
+    /// Returns the key-encryption key the secrets of the prior revisions of
+    /// `name` are sealed under, creating it if asked to.
+    ///
+    fn revision_key(&self, name: &str, create: bool) -> Result<Zeroizing<Vec<u8>>, Error> {
+        //
+        let key = self
+            .keys
+            .get(name)
+            .map_err(|err| Error::other(format!("Failed to read revision key: {}", err)))?;
+        match key {
+            Some(key) => Ok(Zeroizing::new(key.to_vec())),
+            None if create => {
+                let key = Zeroizing::new(Aes256Gcm::generate_key(OsRng).to_vec());
+                self.keys.insert(name, key.as_slice()).map_err(|err| {
+                    Error::other(format!("Failed to add revision key to database: {}", err))
+                })?;
+                Ok(key)
+            }
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Revision key of {} not found", name),
+            )),
+        }
+    }
+
+    /// Removes the key-encryption key of `name`, if it has one.
+    ///
+    fn remove_revision_key(&self, name: &str) -> Result<(), Error> {
+        //
+        self.keys.remove(name).map_err(|err| {
+            Error::other(format!(
+                "Failed to remove revision key from database: {}",
+                err
+            ))
+        })?;
+
+        Ok(())
+    }
+
+    /// Seals the secret of `rev` under `kek`, bound to the revision's number.
+    ///
+    fn seal_secret(kek: &[u8], rev: &Revision) -> Result<Vec<u8>, Error> {
+        //
+        let nonce = Aes256Gcm::generate_nonce(OsRng);
+        let mut buffer = Zeroizing::new(rev.secret.join());
+        Aes256Gcm::new(GenericArray::from_slice(kek))
+            .encrypt_in_place(&nonce, &rev.number.to_be_bytes(), &mut *buffer)
+            .map_err(|err| Error::other(format!("Failed to seal revision key: {}", err)))?;
+
+        Ok([&nonce[..], &buffer[..]].concat())
+    }
+
+    /// Unseals the secret of `rev` with `kek`.
+    ///
+    fn open_secret(kek: &[u8], rev: &Revision) -> Result<Secret, Error> {
+        //
+        if rev.sealed.len() < 12 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Failed to unseal revision key: truncated",
+            ));
+        }
+        let (nonce, ciphertext) = rev.sealed.split_at(12);
+        let mut buffer = Zeroizing::new(ciphertext.to_vec());
+        Aes256Gcm::new(GenericArray::from_slice(kek))
+            .decrypt_in_place(
+                GenericArray::from_slice(nonce),
+                &rev.number.to_be_bytes(),
+                &mut *buffer,
+            )
+            .map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Failed to unseal revision key: {}", err),
+                )
+            })?;
+
+        Ok(Secret::from(&buffer))
+    }
+
+    /// Lists the revisions of `file`, newest first.
+    ///
+    pub fn log(&self, file: &str) -> Result<Vec<RevisionInfo>, Error> {
+        //
+        let entry = self
+            .get_entry(file)?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "File not found in stash"))?;
+
+        Ok(Self::revision_log(&entry))
+    }
+
+    /// Summarises the revisions of `entry`, newest first.
+    ///
+    fn revision_log(entry: &Entry) -> Vec<RevisionInfo> {
+        //
+        //  Current ciphertext first, then prior revisions
+        //
+        let mut log = vec![RevisionInfo {
+            number: entry.revision,
+            stashed: entry.stashed,
+            hash: entry.hash.clone(),
+            current: true,
+        }];
+        log.extend(entry.revisions.iter().rev().map(|rev| RevisionInfo {
+            number: rev.number,
+            stashed: rev.stashed,
+            hash: rev.hash.clone(),
+            current: false,
+        }));
+
+        log
+    }
+
+    /// Describes `file` from its metadata, without decrypting it.
+    ///
+    /// Files that have been moved into an archive are described from the
+    /// newest archive holding them. Archives only name their files in their
+    /// encrypted manifests, so finding those means decrypting every one.
+    ///
+    pub fn info(&self, file: &str) -> Result<FileDetails, Error> {
+        //
+        Self::check_name(file)?;
+        let mut archives = Vec::new();
+        for item in self.archives.iter() {
+            let (name, value) = item?;
+            let name = String::from_utf8_lossy(&name).to_string();
+            let archive = Self::parse_archive(&value)?;
+            let member = self
+                .read_manifest(&name, &archive)?
+                .files
+                .into_iter()
+                .find(|member| member.name == file);
+            if let Some(member) = member {
+                archives.push((archive.created, name, member));
+            }
+        }
+        archives.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+        let names: Vec<String> = archives.iter().map(|(_, name, _)| name.clone()).collect();
+
+        //  Fall back on the newest archive for files no longer in the stash
+        //
+        let path = self.path.join(file);
+        let in_stash = self.db.contains_key(file)? && path.is_file();
+        let (entry, stored, accessed) = if in_stash {
+            let meta = fs::metadata(&path)?;
+            let entry = self.get_entry(file)?.unwrap_or_default();
+            let stored = if chunks::is_stub(&path) {
+                let store = self.chunk_store()?;
+                let ids: BTreeSet<&String> = entry.chunks.iter().collect();
+                ids.into_iter()
+                    .map(|id| Ok(fs::metadata(store.chunk_path(id)?).map_or(0, |meta| meta.len())))
+                    .sum::<Result<u64, Error>>()?
+            } else {
+                meta.len()
+            };
+            let accessed = meta.atime().max(0) as u64;
+            (entry, stored, Some(accessed))
+        } else if let Some((_, _, member)) = archives.pop() {
+            (member.entry, member.length, None)
+        } else {
+            return Err(Error::new(ErrorKind::NotFound, "File not found in stash"));
+        };
+
+        let revisions = Self::revision_log(&entry);
+        let created = entry
+            .revisions
+            .iter()
+            .map(|rev| rev.stashed)
+            .chain([entry.stashed])
+            .min()
+            .unwrap_or_default();
+        let labels = self.open_labels(&entry)?;
+
+        Ok(FileDetails {
+            name: file.to_string(),
+            original: entry.original,
+            size: entry.size,
+            stored,
+            hash: entry.hash,
+            cipher: "aes-256-gcm".to_string(),
+            format: FORMAT,
+            chunked: !entry.chunks.is_empty(),
+            compression: entry.compression,
+            symlink: entry.symlink,
+            mode: entry.mode,
+            created,
+            stashed: entry.stashed,
+            modified: entry.modified,
+            accessed,
+            revisions,
+            tags: labels.tags.into_iter().collect(),
+            note: labels.note,
+            cached: in_stash && self.keyring.search(file).is_ok(),
+            in_stash,
+            archives: names,
+        })
+    }
+
+    /// Decrypts a copy of revision `number` of `file` to `dst`.
+    ///
+    /// As with `grab_to`, `dst` may be a directory or a file path. Returns the
+    /// name the copy was dropped under.
+    ///
+    pub fn grab_rev(
+        &mut self,
+        file: &str,
+        number: u32,
+        dst: &Path,
+        conflict: Conflict,
+    ) -> Result<String, Error> {
+        //
+        let entry = self
+            .get_entry(file)?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "File not found in stash"))?;
+
+        //  The current revision is just a regular copy
+        //
+        if number == entry.revision {
+            return self.grab_to(file, dst, true, conflict);
+        }
+        let rev = entry
+            .revisions
+            .iter()
+            .find(|rev| rev.number == number)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("Revision {} of {} not found", number, file),
+                )
+            })?;
+
+        //  Restore the revision's own modification time
+        //
+        let (dir, name) = Self::split_dst(file, dst)?;
+        let dst = Self::resolve_dst(&dir, &name, conflict)?;
+        let dst_path = dir.join(&dst);
+        let meta = Entry {
+            modified: rev.modified,
+            chunks: rev.chunks.clone(),
+            compression: rev.compression,
+            ..entry.clone()
+        };
+
+        let src_path = self.revision_path(file, number);
+        self.decrypt_to(&src_path, &dst_path, &rev.secret, Some(&meta))
+            .map_err(|err| Error::other(format!("Failed to decrypt revision: {}", err)))?;
+
+        Ok(dst)
+    }
+
+    /// Drops prior revisions of `file`, shredding their keys.
+    ///
+    /// A revision is dropped if it isn't among the newest `keep`, or if it
+    /// was stashed more than `older_than` seconds ago. The current revision
+    /// is never dropped. Returns the numbers of the dropped revisions.
+    ///
+    /// The revisions that are kept are sealed again under a new
+    /// key-encryption key, and the old one is dropped, along with the keys
+    /// of any chunks only the dropped revisions held. The database is then
+    /// rewritten, so no old copy of those keys is left in it. Any backup or
+    /// bundle made earlier still holds both keys and ciphertext.
+    ///
+    pub fn prune(
+        &mut self,
+        file: &str,
+        keep: Option<usize>,
+        older_than: Option<u64>,
+    ) -> Result<Vec<u32>, Error> {
+        //
+        let mut entry = self
+            .get_entry(file)?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "File not found in stash"))?;
+
+        let cutoff = older_than.map(|age| now().saturating_sub(age));
+        let total = entry.revisions.len();
+        let mut dropped = Vec::new();
+        let mut kept = Vec::new();
+        let mut chunked = false;
+
+        //  Revisions are stored oldest first
+        //
+        for (i, mut rev) in entry.revisions.drain(..).enumerate() {
+            let beyond_keep = keep.is_some_and(|keep| total - i > keep);
+            let too_old = cutoff.is_some_and(|cutoff| rev.stashed < cutoff);
+
+            if beyond_keep || too_old {
+                rev.secret.key.zeroize();
+                rev.secret.nonce.zeroize();
+                chunked |= !rev.chunks.is_empty();
+                dropped.push(rev.number);
+            } else {
+                kept.push(rev);
+            }
+        }
+        if dropped.is_empty() {
+            return Ok(dropped);
+        }
+
+        //  Forget the revisions before removing their ciphertext, so none is
+        //  ever listed without it, sealing what's kept under a new key
+        //
+        entry.revisions = kept;
+        self.remove_revision_key(file)?;
+        self.put_entry(file, &entry)?;
+        self.db
+            .flush()
+            .map_err(|err| Error::other(format!("Failed to flush database: {}", err)))?;
+
+        for number in &dropped {
+            let rev_path = self.revision_path(file, *number);
+            if rev_path.exists() {
+                fs::remove_file(&rev_path)?;
+            }
+        }
+        if entry.revisions.is_empty() {
+            self.remove_revisions(file)?;
+        }
+        if chunked {
+            self.gc()?;
+        }
+
+        //  Then rewrite the database without the old keys
+        //
+        self.compact()?;
+
+        Ok(dropped)
+    }
+
+    /// Rewrites the database into a fresh directory and shreds the old one,
+    /// so nothing that was removed from it lingers on disk.
+    ///
+    /// `sled` only ever appends, leaving old values in place until it gets
+    /// round to reclaiming them, so this is the only way to be sure.
+    ///
+    fn compact(&mut self) -> Result<(), Error> {
+        //
+        let db_path = self.path.join(".db");
+        let fresh_path = self.path.join(".db.new");
+        let old_path = self.path.join(".db.old");
+        if fresh_path.exists() {
+            fs::remove_dir_all(&fresh_path)?;
+        }
+
+        //  Copy everything over, and only swap once it's safely on disk
+        //
+        let fresh = Self::get_db(&fresh_path)?;
+        fresh.import(self.db.export());
+        fresh
+            .flush()
+            .map_err(|err| Error::other(format!("Failed to flush database: {}", err)))?;
+        drop(fresh);
+        self.db
+            .flush()
+            .map_err(|err| Error::other(format!("Failed to flush database: {}", err)))?;
+        fs::rename(&db_path, &old_path)?;
+        fs::rename(&fresh_path, &db_path)?;
+
+        //  Let go of the old database before shredding it
+        //
+        let stash = Self::open_db(self.path.clone())?;
+        drop(mem::replace(
+            self,
+            Stash {
+                chunking: self.chunking,
+                compression: self.compression,
+                ..stash
+            },
+        ));
+
+        Self::shred_dir(&old_path)
+    }
+
+    /// Overwrites every file under `dir` with zeros, then removes it.
+    ///
+    /// Filesystems that copy on write, and flash storage, may still keep the
+    /// old blocks, but nothing is left for a plain read of the disk.
+    ///
+    fn shred_dir(dir: &Path) -> Result<(), Error> {
+        //
+        let mut files = Vec::new();
+        Self::walk_files(dir, "", &mut files)?;
+        for name in files {
+            let path = dir.join(name);
+            let len = fs::metadata(&path)?.len();
+            let mut file = fs::OpenOptions::new().write(true).open(&path)?;
+            io::copy(&mut io::repeat(0).take(len), &mut file)?;
+            file.sync_all()?;
+        }
+
+        fs::remove_dir_all(dir)
+    }
+
+    /// Lists the logical names of all files in the stash.
+    ///
+    pub fn names(&self) -> Result<Vec<String>, Error> {
+        //
+        let mut names = Vec::new();
+        for key in self.db.iter().keys() {
+            names.push(String::from_utf8_lossy(&key?).to_string());
+        }
+
+        Ok(names)
+    }
+
+    /// Expands a glob `pattern` against the logical names in the stash.
+    ///
+    /// Names without glob characters are returned as they are, so that
+    /// lookups of missing files fail with the usual error. A pattern of the
+    /// form `tag:<tag>` selects every file with that tag instead.
+    ///
+    pub fn find(&self, pattern: &str) -> Result<Vec<String>, Error> {
+        //
+        if let Some(tag) = pattern.strip_prefix("tag:") {
+            let names = self.tagged(tag)?;
+            if names.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("No files in stash are tagged `{}`", tag),
+                ));
+            }
+            return Ok(names);
+        }
+        if Pattern::escape(pattern) == pattern {
+            return Ok(vec![pattern.to_string()]);
+        }
+        let glob = Pattern::new(pattern).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid pattern `{}`: {}", pattern, err),
+            )
+        })?;
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+
+        //  Match against every entry in the database
+        //
+        let names: Vec<String> = self
+            .names()?
+            .into_iter()
+            .filter(|name| glob.matches_with(name, options))
+            .collect();
+        if names.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No files in stash match `{}`", pattern),
+            ));
+        }
+
+        Ok(names)
+    }
+
+    /// Rejects logical names that could point outside the stash, or that
+    /// `find` would take for a tag.
+    ///
+    fn check_name(name: &str) -> Result<(), Error> {
+        //
+        let path = Path::new(name);
+        if name.is_empty()
+            || path.is_absolute()
+            || path.components().any(|part| part == Component::ParentDir)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid file name: {}", name),
+            ));
+        } else if name.starts_with("tag:") {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid file name: {} (`tag:` selects tagged files)", name),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Checks whether `name` lies within one of the stash's own files.
+    ///
+    fn is_reserved(name: &str) -> bool {
+        let top = name.split('/').next().unwrap_or(name);
+        matches!(
+            top,
+            ".db"
+                | ".db.new"
+                | ".db.old"
+                | ".secret"
+                | ".secret.new"
+                | ".revs"
+                | ".archives"
+                | ".chunks"
+        )
+    }
+
+    /// Delete `file` in the stash.
+    ///
+    pub fn delete(&mut self, file: &str) -> Result<(), Error> {
+        //
+        //  Bail if deleting program files
+        //
+        if Self::is_reserved(file) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Cannot delete program file {}", file),
+            ));
+        }
+        Self::check_name(file)?;
+
+        //  Make sure specified file exists
+        //
+        let target_path = self.path.join(file);
+        if !target_path.is_file() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "File not found in stash",
+            ));
+        }
+
+        //  Remove the file along with its secrets, metadata and revisions
+        //
+        self.discard(file)?;
+
+        Ok(())
+    }
+
+    /// Lists the files in the stash, ordered by `sort`.
+    ///
+    /// Everything comes from the database, so internal files never show up
+    /// and nothing but tags and notes is decrypted. Names whose ciphertext
+    /// is missing are left out. With a `filter`, only names matching that
+    /// glob are listed, and with a `tag`, only files tagged with it.
+    ///
+    pub fn list(
+        &self,
+        sort: Sort,
+        filter: Option<&str>,
+        tag: Option<&str>,
+    ) -> Result<Vec<EntryInfo>, Error> {
+        //
+        let glob = filter
+            .map(|filter| {
+                Pattern::new(filter).map_err(|err| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Invalid pattern `{}`: {}", filter, err),
+                    )
+                })
+            })
+            .transpose()?;
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+
+        let mut infos = Vec::new();
+        for name in self.names()? {
+            if glob
+                .as_ref()
+                .is_some_and(|glob| !glob.matches_with(&name, options))
+            {
+                continue;
+            }
+            let path = self.path.join(&name);
+            let Ok(meta) = fs::metadata(&path) else {
+                continue;
+            };
+            let chunked = chunks::is_stub(&path);
+
+            //  Files stashed before sizes were recorded fall back on their
+            //  ciphertext, less its tag
+            //
+            let entry = match self.get_entry(&name)? {
+                Some(entry) => entry,
+                None => Entry {
+                    size: meta.len().saturating_sub(16),
+                    ..Entry::default()
+                },
+            };
+            let labels = self.open_labels(&entry)?;
+            if tag.is_some_and(|tag| !labels.tags.contains(tag)) {
+                continue;
+            }
+            infos.push(EntryInfo {
+                name,
+                original: entry.original,
+                size: entry.size,
+                stashed: entry.stashed,
+                modified: entry.modified,
+                cipher: "aes-256-gcm".to_string(),
+                chunked,
+                compression: entry.compression,
+                symlink: entry.symlink,
+                revisions: entry.revisions.len(),
+                tags: labels.tags.into_iter().collect(),
+                note: labels.note,
+            });
+        }
+
+        //  Ties fall back on the name, so the order is always the same
+        //
+        infos.sort_by(|a, b| {
+            let order = match sort {
+                Sort::Name => std::cmp::Ordering::Equal,
+                Sort::Size => b.size.cmp(&a.size),
+                Sort::Stashed => b.stashed.cmp(&a.stashed),
+                Sort::Modified => b.modified.cmp(&a.modified),
+            };
+            order.then_with(|| a.name.cmp(&b.name))
+        });
+
+        Ok(infos)
+    }
+
+    /// Searches the files matching `patterns`, or every file, for lines
+    /// matching `regex`, returning each file's matches, or why it couldn't
+    /// be searched, in order.
+    ///
+    /// Chunked files are decrypted a chunk at a time. Other files are sealed
+    /// whole, so they're decrypted whole, and past `GREP_WHOLE` bytes only
+    /// one at a time; smaller ones are searched several at once. Plaintext
+    /// stays in memory and is wiped once searched. Files that look binary,
+    /// holding a NUL byte near the start, are skipped unless `binary` is
+    /// set, as are links.
+    ///
+    pub fn grep(
+        &self,
+        regex: &str,
+        patterns: &[String],
+        binary: bool,
+    ) -> Result<Vec<GrepResult>, Error> {
+        //
+        let regex = Regex::new(regex).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid regex `{}`: {}", regex, err),
+            )
+        })?;
+        let mut names = Vec::new();
+        if patterns.is_empty() {
+            names = self.names()?;
+            names.retain(|name| self.path.join(name).is_file());
+        }
+        for pattern in patterns {
+            for name in self.find(pattern)? {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+
+        //  Search large whole files after the rest, one by one
+        //
+        let large = |name: &String| {
+            let path = self.path.join(name);
+            !chunks::is_stub(&path) && fs::metadata(&path).is_ok_and(|meta| meta.len() > GREP_WHOLE)
+        };
+        let (large, small): (Vec<_>, Vec<_>) = names.iter().partition(|name| large(name));
+        let mut found: HashMap<&String, _> = small
+            .into_par_iter()
+            .map(|name| (name, self.grep_file(name, &regex, binary)))
+            .collect();
+        for name in large {
+            found.insert(name, self.grep_file(name, &regex, binary));
+        }
+
+        Ok(names
+            .iter()
+            .filter_map(|name| Some((name.clone(), found.remove(name)?)))
+            .collect())
+    }
+
+    /// Searches the single file `name` for lines matching `regex`.
+    ///
+    fn grep_file(&self, name: &str, regex: &Regex, binary: bool) -> Result<Vec<GrepMatch>, Error> {
+        //
+        let path = self.path.join(name);
+        if !path.is_file() {
+            return Err(Error::new(ErrorKind::NotFound, "File not found in stash"));
+        }
+        let entry = self.get_entry(name)?.unwrap_or_default();
+        if entry.symlink {
+            return Ok(Vec::new());
+        }
+        let compression = entry.compression.unwrap_or(Compression::None);
+        if chunks::is_stub(&path) {
+            let reader = self.chunk_store()?.reader(&entry.chunks)?;
+            Self::grep_lines(name, decoder(reader, compression)?, regex, binary)
+        } else {
+            let secret = self.get_secret(name, false)?;
+            let plaintext = Self::decrypt_bytes(&path, &secret)?;
+            let reader = decoder(&plaintext[..], compression)?;
+            Self::grep_lines(name, reader, regex, binary)
+        }
+    }
+
+    /// Searches the plaintext `reader` gives, a block at a time, for lines
+    /// matching `regex`, holding no more than a block and the line it ends in.
+    ///
+    fn grep_lines(
+        name: &str,
+        mut reader: impl Read,
+        regex: &Regex,
+        binary: bool,
+    ) -> Result<Vec<GrepMatch>, Error> {
+        //
+        let mut found = Vec::new();
+        let mut block = Zeroizing::new(vec![0; 64 * 1024]);
+        let mut line = Zeroizing::new(Vec::new());
+        let mut number = 0;
+        let mut read = 0;
+        let mut check = |line: &[u8], number: usize| {
+            if regex.is_match(line) {
+                found.push(GrepMatch {
+                    name: name.to_string(),
+                    line: number,
+                    text: Zeroizing::new(String::from_utf8_lossy(line).into_owned()),
+                });
+            }
+        };
+        loop {
+            let len = reader.read(&mut block)?;
+            if len == 0 {
+                break;
+            }
+            let bytes = &block[..len];
+            if !binary && read < 8192 {
+                let head = &bytes[..len.min(8192 - read)];
+                if head.contains(&0) {
+                    return Ok(Vec::new());
+                }
+            }
+            read += len;
+
+            //  Lines may run across blocks, so keep the unfinished one
+            //
+            let mut rest = bytes;
+            while let Some(end) = rest.iter().position(|&byte| byte == b'\n') {
+                line.extend_from_slice(&rest[..end]);
+                number += 1;
+                check(&line, number);
+                line.clear();
+                rest = &rest[end + 1..];
+            }
+            line.extend_from_slice(rest);
+        }
+
+        //  A final newline ends the last line rather than starting another
+        //
+        if !line.is_empty() {
+            check(&line, number + 1);
+        }
+
+        Ok(found)
+    }
+
+    /// Adds each of `tags` to `file`.
+    ///
+    pub fn tag(&self, file: &str, tags: &[String]) -> Result<(), Error> {
+        //
+        for tag in tags {
+            if tag.is_empty() || tag.contains(|c: char| c == ',' || c.is_whitespace()) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid tag: `{}`", tag),
+                ));
+            }
+        }
+        self.update_labels(file, |labels| labels.tags.extend(tags.iter().cloned()))
+    }
+
+    /// Removes each of `tags` from `file`, if it has them.
+    ///
+    pub fn untag(&self, file: &str, tags: &[String]) -> Result<(), Error> {
+        self.update_labels(file, |labels| {
+            labels.tags.retain(|tag| !tags.contains(tag));
+        })
+    }
+
+    /// Returns the tags of `file`, in order.
+    ///
+    pub fn tags(&self, file: &str) -> Result<Vec<String>, Error> {
+        let labels = self.open_labels(&self.labelled_entry(file)?)?;
+        Ok(labels.tags.into_iter().collect())
+    }
+
+    /// Lists the files tagged with `tag`.
+    ///
+    pub fn tagged(&self, tag: &str) -> Result<Vec<String>, Error> {
+        //
+        let mut names = Vec::new();
+        for name in self.names()? {
+            let entry = self.get_entry(&name)?.unwrap_or_default();
+            if self.open_labels(&entry)?.tags.contains(tag) {
+                names.push(name);
+            }
+        }
+
+        Ok(names)
+    }
+
+    /// Sets the note on `file`, or clears it if `note` is empty.
+    ///
+    pub fn set_note(&self, file: &str, note: &str) -> Result<(), Error> {
+        self.update_labels(file, |labels| labels.note = note.to_string())
+    }
+
+    /// Returns the note on `file`, which is empty if there is none.
+    ///
+    pub fn note(&self, file: &str) -> Result<String, Error> {
+        Ok(self.open_labels(&self.labelled_entry(file)?)?.note)
+    }
+
+    /// Looks up the metadata of `file`, which has to be in the stash.
+    ///
+    fn labelled_entry(&self, file: &str) -> Result<Entry, Error> {
+        //
+        Self::check_name(file)?;
+        if !self.db.contains_key(file)? {
+            return Err(Error::new(ErrorKind::NotFound, "File not found in stash"));
+        }
+
+        Ok(self.get_entry(file)?.unwrap_or_default())
+    }
+
+    /// Changes the labels of `file` with `change`, and seals them again.
+    ///
+    fn update_labels<F: FnOnce(&mut Labels)>(&self, file: &str, change: F) -> Result<(), Error> {
+        //
+        let entry = self.labelled_entry(file)?;
+        let mut labels = self.open_labels(&entry)?;
+        change(&mut labels);
+        let entry = Entry {
+            labels: self.seal_labels(&labels)?,
+            ..entry
+        };
+
+        self.put_entry(file, &entry)
+    }
+
+    /// Decrypts the labels of `entry`, which may have none.
+    ///
+    fn open_labels(&self, entry: &Entry) -> Result<Labels, Error> {
+        //
+        if entry.labels.is_empty() {
+            return Ok(Labels::default());
+        } else if entry.labels.len() < 12 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Failed to decrypt tags: truncated",
+            ));
+        }
+        let (nonce, ciphertext) = entry.labels.split_at(12);
+        let mut buffer = Zeroizing::new(ciphertext.to_vec());
+        Aes256Gcm::new(GenericArray::from_slice(&self.labels_key()?))
+            .decrypt_in_place(GenericArray::from_slice(nonce), b"", &mut *buffer)
+            .map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Failed to decrypt tags: {}", err),
+                )
+            })?;
+
+        Ok(serde_json::from_slice(&buffer)?)
+    }
+
+    /// Encrypts `labels` under a fresh nonce, or returns nothing if they're
+    /// empty.
+    ///
+    fn seal_labels(&self, labels: &Labels) -> Result<Vec<u8>, Error> {
+        //
+        if labels.tags.is_empty() && labels.note.is_empty() {
+            return Ok(Vec::new());
+        }
+        let nonce = Aes256Gcm::generate_nonce(OsRng);
+        let mut buffer = Zeroizing::new(serde_json::to_vec(labels)?);
+        Aes256Gcm::new(GenericArray::from_slice(&self.labels_key()?))
+            .encrypt_in_place(&nonce, b"", &mut *buffer)
+            .map_err(|err| Error::other(format!("Failed to encrypt tags: {}", err)))?;
+
+        Ok([&nonce[..], &buffer[..]].concat())
+    }
+
+    /// Returns the key tags and notes are sealed under.
+    ///
+    /// It is derived from the stash's master secret, which is kept outside
+    /// the database, so reading the database alone doesn't reveal labels.
+    ///
+    fn labels_key(&self) -> Result<Zeroizing<Vec<u8>>, Error> {
+        //
+        let secret = self.master_secret()?;
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&secret)
+            .map_err(|err| Error::new(ErrorKind::InvalidInput, err.to_string()))?;
+        mac.update(b"stash labels");
+
+        Ok(Zeroizing::new(mac.finalize().into_bytes().to_vec()))
+    }
+
+    /// Returns the stash's master secret, creating it on first use.
+    ///
+    /// It sits in `.secret`, readable by the stash user only, rather than in
+    /// the database, and is only used to derive keys from.
+    ///
+    fn master_secret(&self) -> Result<Zeroizing<Vec<u8>>, Error> {
+        //
+        let path = self.path.join(".secret");
+        match fs::read(&path) {
+            Ok(secret) if secret.len() == 32 => return Ok(Zeroizing::new(secret)),
+            Ok(_) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Master secret is damaged",
+                ))
+            }
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            Err(_) => {}
+        }
+        let secret = Zeroizing::new(Aes256Gcm::generate_key(OsRng).to_vec());
+        Self::write_master_secret(&path, &secret)?;
+
+        Ok(secret)
+    }
+
+    /// Writes `secret` to `path` in one go, readable by the owner only.
+    ///
+    fn write_master_secret(path: &Path, secret: &[u8]) -> Result<(), Error> {
+        //
+        let tmp_path = path.with_extension("new");
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        file.write_all(secret)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Moves the files matching `patterns` into a new archive called `name`.
+    ///
+    /// Every file is archived when `patterns` is empty. The archive is a
+    /// tarball of the files' ciphertext and revisions, compressed as
+    /// `options` says and encrypted under its own secret, alongside a
+    /// separately encrypted manifest describing each file and holding its
+    /// secret. Archives are kept apart from the rest of the stash, which
+    /// stays usable. Returns the names of the archived files.
+    ///
+    /// With `options.incremental`, the files are copied rather than moved,
+    /// and only those that are new or changed since the last incremental
+    /// archive go in, along with markers for any since deleted.
+    ///
+    pub fn archive(
+        &mut self,
+        name: &str,
+        patterns: &[String],
+        options: &ArchiveOptions,
+    ) -> Result<Vec<String>, Error> {
+        //
+        //  Archive names live in their own flat namespace
+        //
+        if name.is_empty() || name.contains('/') || name.starts_with('.') {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid archive name: {}", name),
+            ));
+        } else if self.get_archive(name)?.is_some() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                "Archive already exists",
+            ));
+        }
+        let compression = options.compression;
+        let level = options.level.unwrap_or(compression.default_level());
+        if level > compression.max_level() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Compression level for {} must be at most {}",
+                    compression,
+                    compression.max_level()
+                ),
+            ));
+        }
+
+        //  Collect the selected names, once each
+        //
+        let mut names = Vec::new();
+        if patterns.is_empty() {
+            names = self.names()?;
+        }
+        for pattern in patterns {
+            for file in self.find(pattern)? {
+                if !self.path.join(&file).is_file() {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!("File not found in stash: {}", file),
+                    ));
+                }
+                if !names.contains(&file) {
+                    names.push(file);
+                }
+            }
+        }
+        if names.is_empty() && !options.incremental {
+            return Err(Error::new(ErrorKind::InvalidInput, "No files in stash"));
+        }
+
+        //  Incremental archives only take what changed since the last one
+        //
+        let mut parent = None;
+        let mut baseline = None;
+        let mut deleted = Vec::new();
+        if options.incremental {
+            let tip = self.chain_tip()?;
+            let mut hashes = match &tip {
+                Some((tip, archive)) => self.read_manifest(tip, archive)?.baseline,
+                None => BTreeMap::new(),
+            };
+            deleted = hashes
+                .keys()
+                .filter(|file| !self.exists(file))
+                .cloned()
+                .collect();
+            for file in &deleted {
+                hashes.remove(file);
+            }
+
+            let mut changed = Vec::new();
+            for file in names {
+                let hash = self.get_entry(&file)?.unwrap_or_default().hash;
+                if hash.is_empty() || hashes.get(&file) != Some(&hash) {
+                    hashes.insert(file.clone(), hash);
+                    changed.push(file);
+                }
+            }
+            if changed.is_empty() && deleted.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    match &tip {
+                        Some((tip, _)) => format!("Nothing has changed since archive {}", tip),
+                        None => "No files in stash".to_string(),
+                    },
+                ));
+            }
+            names = changed;
+            parent = tip.map(|(tip, _)| tip);
+            baseline = Some(hashes);
+        }
+
+        //  Describe each file, along with what it takes to restore it later
+        //
+        let mut members = Vec::new();
+        for file in &names {
+            let entry = self.get_entry(file)?.unwrap_or_default();
+            members.push(Member {
+                name: file.clone(),
+                size: entry.size,
+                hash: entry.hash.clone(),
+                offset: 0,
+                length: 0,
+                secret: self.get_secret(file, false)?,
+                entry,
+            });
+        }
+
+        //  Stream the tarball through encryption, then seal the manifest
+        //
+        let archive_dir = self.archive_dir(name);
+        fs::create_dir_all(&archive_dir)?;
+        let secret = Secret::new();
+        let manifest = Secret::new();
+        let header = Header {
+            compression,
+            level: level as u8,
+            volume: 1,
+        };
+        let result = self
+            .write_archive(
+                &archive_dir,
+                name,
+                &mut members,
+                &secret,
+                header,
+                options.volume_size,
+            )
+            .and_then(|volumes| {
+                let json = Zeroizing::new(serde_json::to_vec(&Manifest {
+                    volumes,
+                    files: members,
+                    deleted,
+                    baseline: baseline.unwrap_or_default(),
+                })?);
+                let ciphertext = Self::encrypt_bytes(&json, &manifest)?;
+                fs::write(archive_dir.join("manifest"), ciphertext)
+            });
+        if let Err(err) = result {
+            let _ = fs::remove_dir_all(&archive_dir);
+            return Err(Error::other(format!("Failed to write archive: {}", err)));
+        }
+        self.put_archive(
+            name,
+            &Archive {
+                secret,
+                manifest,
+                created: now(),
+                count: names.len(),
+                parent,
+                incremental: options.incremental,
+            },
+        )?;
+
+        //  Only remove the originals once the archive is safely recorded
+        //
+        if !options.incremental {
+            for file in &names {
+                self.discard(file)?;
+            }
+        }
+
+        Ok(names)
+    }
+
+    /// Restores files from the archive called `name`.
+    ///
+    /// `name` may be left out when there is only one archive. With no
+    /// `files`, every file is restored and the archive is removed. Otherwise
+    /// only the files matching `files` are extracted, and the archive is left
+    /// as it was. Refuses to replace any file already in the stash. Returns
+    /// the restored names.
+    ///
+    pub fn unpack(&mut self, name: Option<&str>, files: &[String]) -> Result<Vec<String>, Error> {
+        //
+        //  Stashes archived before archives had names hold a lone `contents`
+        //
+        if name.is_none() && files.is_empty() && self.is_legacy_archive()? {
+            return self.unpack_legacy();
+        }
+        let name = match name {
+            Some(name) => name.to_string(),
+            None => {
+                let archives = self.archives()?;
+                match archives.as_slice() {
+                    [archive] => archive.name.clone(),
+                    [] => {
+                        return Err(Error::new(ErrorKind::NotFound, "No archive exists"));
+                    }
+                    _ => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            "Several archives exist. Say which one to unpack",
+                        ));
+                    }
+                }
+            }
+        };
+        let archive = self
+            .get_archive(&name)?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Archive not found"))?;
+        if archive.incremental && files.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is an incremental archive. Unpack it with --chain", name),
+            ));
+        }
+
+        //  Pick out the requested files using the manifest alone
+        //
+        let manifest = self.read_manifest(&name, &archive)?;
+        let mut members = manifest.files;
+        if !files.is_empty() {
+            members = Self::select_members(members, files)?;
+        }
+        if let Some(member) = members.iter().find(|member| self.exists(&member.name)) {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!(
+                    "{} is already in stash. Move it aside before unpacking",
+                    member.name
+                ),
+            ));
+        }
+        let names: Vec<String> = members.iter().map(|member| member.name.clone()).collect();
+        self.restore(
+            &name,
+            &archive,
+            &manifest.volumes,
+            &members,
+            files.is_empty(),
+        )?;
+
+        //  Once everything is out, remove the archive and forget its secrets
+        //
+        if files.is_empty() {
+            fs::remove_dir_all(self.archive_dir(&name))?;
+            self.archives.remove(&name).map_err(|err| {
+                Error::other(format!("Failed to remove archive from database: {}", err))
+            })?;
+        }
+
+        Ok(names)
+    }
+
+    /// Rebuilds the state captured by a chain of incremental archives.
+    ///
+    /// The chain runs from its first archive up to the one called `name`,
+    /// or its latest archive. Each file is restored from the last archive
+    /// holding it, unless a later one marks it deleted, and the archives
+    /// are left as they were. Refuses to replace any file already in the
+    /// stash. Returns the restored names.
+    ///
+    pub fn unpack_chain(&mut self, name: Option<&str>) -> Result<Vec<String>, Error> {
+        //
+        let tip = match name {
+            Some(name) => name.to_string(),
+            None => match self.chain_tip()? {
+                Some((tip, _)) => tip,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        "No incremental archive exists",
+                    ));
+                }
+            },
+        };
+
+        //  Follow the chain back to where it starts
+        //
+        let mut chain = Vec::new();
+        let mut next = Some(tip);
+        while let Some(name) = next {
+            let archive = self.get_archive(&name)?.ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("Archive {} is missing from the chain", name),
+                )
+            })?;
+            if !archive.incremental {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} is not an incremental archive", name),
+                ));
+            }
+            next = archive.parent.clone();
+            chain.push((name, archive));
+        }
+        chain.reverse();
+
+        //  Apply each manifest in order, to find where each file ends up
+        //
+        let mut manifests = Vec::new();
+        let mut state = BTreeMap::new();
+        for (index, (name, archive)) in chain.iter().enumerate() {
+            let manifest = self.read_manifest(name, archive)?;
+            for file in &manifest.deleted {
+                state.remove(file);
+            }
+            for member in &manifest.files {
+                state.insert(member.name.clone(), index);
+            }
+            manifests.push(manifest);
+        }
+        if let Some(file) = state.keys().find(|file| self.exists(file)) {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!(
+                    "{} is already in stash. Move it aside before unpacking",
+                    file
+                ),
+            ));
+        }
+
+        //  Restore each file from the archive holding its last version
+        //
+        for (index, ((name, archive), manifest)) in chain.iter().zip(manifests).enumerate() {
+            let members: Vec<Member> = manifest
+                .files
+                .into_iter()
+                .filter(|member| state.get(&member.name) == Some(&index))
+                .collect();
+            if !members.is_empty() {
+                self.restore(name, archive, &manifest.volumes, &members, false)?;
+            }
+        }
+
+        Ok(state.into_keys().collect())
+    }
+
+    /// Lists the files in the archive called `name`.
+    ///
+    /// Only the manifest is decrypted, however large the archive.
+    ///
+    pub fn manifest(&self, name: &str) -> Result<Vec<MemberInfo>, Error> {
+        //
+        let archive = self
+            .get_archive(name)?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Archive not found"))?;
+        let manifest = self.read_manifest(name, &archive)?;
+
+        Ok(manifest
+            .files
+            .into_iter()
+            .map(|member| MemberInfo {
+                name: member.name,
+                size: member.size,
+                hash: member.hash,
+                offset: member.offset,
+                length: member.length,
+            })
+            .collect())
+    }
+
+    /// Lists the named archives in the stash.
+    ///
+    pub fn archives(&self) -> Result<Vec<ArchiveInfo>, Error> {
+        //
+        let mut archives = Vec::new();
+        for item in self.archives.iter() {
+            let (key, value) =
+                item.map_err(|err| Error::other(format!("Failed to read archives: {}", err)))?;
+            let archive = Self::parse_archive(&value)?;
+            archives.push(ArchiveInfo {
+                name: String::from_utf8_lossy(&key).to_string(),
+                created: archive.created,
+                files: archive.count,
+                incremental: archive.incremental,
+                parent: archive.parent,
+            });
+        }
+
+        Ok(archives)
+    }
+
+    /// Finds the latest incremental archive, which no other follows on from.
+    ///
+    fn chain_tip(&self) -> Result<Option<(String, Archive)>, Error> {
+        //
+        let mut archives = Vec::new();
+        for item in self.archives.iter() {
+            let (key, value) =
+                item.map_err(|err| Error::other(format!("Failed to read archives: {}", err)))?;
+            let archive = Self::parse_archive(&value)?;
+            if archive.incremental {
+                archives.push((String::from_utf8_lossy(&key).to_string(), archive));
+            }
+        }
+        let parents: HashSet<String> = archives
+            .iter()
+            .filter_map(|(_, archive)| archive.parent.clone())
+            .collect();
+
+        Ok(archives
+            .into_iter()
+            .filter(|(name, _)| !parents.contains(name))
+            .max_by_key(|(_, archive)| archive.created))
+    }
+
+    /// Extracts `members` from the archive called `name`, and registers them.
+    ///
+    /// With `whole`, the whole tarball is read and checked, and `members`
+    /// must be every file in it. If anything goes wrong, whatever was
+    /// extracted is removed again.
+    ///
+    fn restore(
+        &mut self,
+        name: &str,
+        archive: &Archive,
+        volumes: &[Volume],
+        members: &[Member],
+        whole: bool,
+    ) -> Result<(), Error> {
+        //
+        let names: Vec<String> = members.iter().map(|member| member.name.clone()).collect();
+        let wanted = (!whole).then_some(&names[..]);
+        let offset = members.iter().map(|member| member.offset).min();
+
+        if let Err(err) = self.read_archive(name, archive, volumes, wanted, offset.unwrap_or(0)) {
+            for file in &names {
+                let target_path = self.path.join(file);
+                let _ = fs::remove_file(&target_path);
+                if let Some(parent) = target_path.parent() {
+                    self.prune_dirs(parent);
+                }
+                let _ = self.remove_revisions(file);
+            }
+            return Err(Error::other(format!("Failed to extract archive: {}", err)));
+        }
+        for member in members {
+            self.register(&member.name, &member.secret, &member.entry)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the directory holding the archive called `name`.
+    ///
+    /// The encrypted tarball is kept in volumes called `<name>.001` and so
+    /// on, and its manifest in `manifest`.
+    ///
+    fn archive_dir(&self, name: &str) -> PathBuf {
+        self.path.join(".archives").join(name)
+    }
+
+    /// Writes a tarball of `members` into `dir`, sealed under `secret`.
+    ///
+    /// The tarball is compressed as `header` says, and split into volumes
+    /// of at most `volume_size` bytes, if given. Records where each of
+    /// `members` sits within the uncompressed tarball, and returns the
+    /// volumes.
+    ///
+    fn write_archive(
+        &self,
+        dir: &Path,
+        name: &str,
+        members: &mut [Member],
+        secret: &Secret,
+        header: Header,
+        volume_size: Option<u64>,
+    ) -> Result<Vec<Volume>, Error> {
+        //
+        let (key, nonce) = secret.split();
+        let names: Vec<String> = members.iter().map(|member| member.name.clone()).collect();
+        let writer = VolumeWriter::new(dir, name, &key, &nonce, header, volume_size)?;
+        let (encoder, spans) = self.create_tarball(&names, Encoder::new(writer, header)?)?;
+        let volumes = encoder.finish()?.finish()?;
+
+        for (member, (offset, length)) in members.iter_mut().zip(spans) {
+            member.offset = offset;
+            member.length = length;
+        }
+
+        Ok(volumes)
+    }
+
+    /// Streams the tarball of the archive called `name` into the stash.
+    ///
+    /// Every one of `volumes` is checked before anything is read. Only the
+    /// files in `wanted` are extracted, if given, starting from `offset`
+    /// when the tarball isn't compressed. Otherwise the whole tarball is
+    /// extracted and checked to its end. The compression is read from the
+    /// archive's header.
+    ///
+    fn read_archive(
+        &self,
+        name: &str,
+        archive: &Archive,
+        volumes: &[Volume],
+        wanted: Option<&[String]>,
+        offset: u64,
+    ) -> Result<Vec<String>, Error> {
+        //
+        let (key, nonce) = archive.secret.split();
+        let dir = self.archive_dir(name);
+        let (mut reader, header) = VolumeReader::new(&dir, volumes, &key, &nonce)?;
+
+        //  Without compression, skip straight to the first wanted member
+        //
+        if wanted.is_some() && header.compression == Compression::None {
+            reader.seek_to(offset)?;
+        }
+
+        self.extract_tarball(decoder(reader, header.compression)?, wanted)
+    }
+
+    /// Decrypts and parses the manifest of the archive called `name`.
+    ///
+    fn read_manifest(&self, name: &str, archive: &Archive) -> Result<Manifest, Error> {
+        //
+        let manifest_path = self.archive_dir(name).join("manifest");
+        let json = Self::decrypt_bytes(&manifest_path, &archive.manifest)?;
+
+        serde_json::from_slice(&json).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Failed to parse archive manifest: {}", err),
+            )
+        })
+    }
+
+    /// Keeps the archived files matching any of `patterns`.
+    ///
+    /// Each pattern has to match at least one file in the archive.
+    ///
+    fn select_members(members: Vec<Member>, patterns: &[String]) -> Result<Vec<Member>, Error> {
+        //
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        let mut globs = Vec::new();
+        for pattern in patterns {
+            let glob = Pattern::new(pattern).map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid pattern `{}`: {}", pattern, err),
+                )
+            })?;
+            let matches = |name: &str| name == pattern || glob.matches_with(name, options);
+            if !members.iter().any(|member| matches(&member.name)) {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("No files in archive match `{}`", pattern),
+                ));
+            }
+            globs.push((pattern, glob));
+        }
+
+        Ok(members
+            .into_iter()
+            .filter(|member| {
+                globs.iter().any(|(pattern, glob)| {
+                    member.name == **pattern || glob.matches_with(&member.name, options)
+                })
+            })
+            .collect())
+    }
+
+    /// Looks up the record of the archive called `name`, if any.
+    ///
+    fn get_archive(&self, name: &str) -> Result<Option<Archive>, Error> {
+        //
+        let value = self
+            .archives
+            .get(name)
+            .map_err(|err| Error::other(format!("Failed to read archive: {}", err)))?;
+
+        value.map(|value| Self::parse_archive(&value)).transpose()
+    }
+
+    /// Parses an archive record.
+    ///
+    fn parse_archive(value: &[u8]) -> Result<Archive, Error> {
+        serde_json::from_slice(value).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Failed to parse archive: {}", err),
+            )
+        })
+    }
+
+    /// Records the archive called `name`.
+    ///
+    fn put_archive(&self, name: &str, archive: &Archive) -> Result<(), Error> {
+        //
+        let value = serde_json::to_vec(archive)
+            .map_err(|err| Error::other(format!("Failed to serialize archive: {}", err)))?;
+        self.archives
+            .insert(name, value)
+            .map_err(|err| Error::other(format!("Failed to add archive to database: {}", err)))?;
+
+        Ok(())
+    }
+
+    /// Checks for an archive made before archives had names.
+    ///
+    /// Those replaced the whole stash with a tarball called `contents`,
+    /// whose secret was recorded without any metadata.
+    ///
+    fn is_legacy_archive(&self) -> Result<bool, Error> {
+        //
+        Ok(self.path.join("contents").is_file()
+            && self.db.contains_key("contents")?
+            && self.get_entry("contents")?.is_none())
+    }
+
+    /// Extracts an archive made before archives had names.
+    ///
+    fn unpack_legacy(&mut self) -> Result<Vec<String>, Error> {
+        //
+        let contents = self.path.join("contents");
+        let secret = self.get_secret("contents", true)?;
+        let tarball = Self::decrypt_bytes(&contents, &secret)?;
+
+        let tarball = decoder(&tarball[..], Compression::Gzip)?;
+        let names = self
+            .extract_tarball(tarball, None)
+            .map_err(|err| Error::other(format!("Failed to extract archive: {}", err)))?;
+        fs::remove_file(&contents)?;
+
+        //  Remove `contents` encryption secrets from database
+        //
+        self.db.remove("contents").map_err(|err| {
+            Error::other(format!(
+                "Failed to remove encryption secrets from database: {}",
+                err
+            ))
+        })?;
+
+        Ok(names)
+    }
+
+    /// Writes every file and environment in the stash to a new bundle at `path`.
+    ///
+    /// The bundle is a single file holding the files' ciphertext, revisions,
+    /// metadata and secrets, sealed under a key derived from `passphrase`,
+    /// so it can be carried to another machine and imported there. The
+    /// stash is left as it is. Returns what was bundled.
+    ///
+    pub fn export_bundle(&self, path: &Path, passphrase: &str) -> Result<BundleContents, Error> {
+        //
+        let names = self.names()?;
+        let mut index = BundleIndex::default();
+        for name in &names {
+            //  Labels travel in the clear inside the bundle, and are sealed
+            //  again by whichever stash imports it
+            //
+            let entry = self.get_entry(name)?.unwrap_or_default();
+            index.files.push(Bundled {
+                name: name.clone(),
+                secret: self.get_secret(name, false)?,
+                labels: self.open_labels(&entry)?,
+                entry: Entry {
+                    labels: Vec::new(),
+                    ..entry
+                },
+            });
+        }
+        for item in self.envs.iter() {
+            let (key, value) =
+                item.map_err(|err| Error::other(format!("Failed to read environment: {}", err)))?;
+            index
+                .envs
+                .insert(String::from_utf8_lossy(&key).to_string(), value.to_vec());
+        }
+        let ids: BTreeSet<String> = index
+            .files
+            .iter()
+            .flat_map(|file| Self::chunk_ids(&file.entry))
+            .collect();
+        if !ids.is_empty() {
+            let store = self.chunk_store()?;
+            for id in ids {
+                let key = store.chunk_key(&id)?;
+                index.chunk_keys.insert(id, key.to_vec());
+            }
+        }
+        let contents = BundleContents {
+            files: names,
+            envs: index.envs.keys().cloned().collect(),
+        };
+
+        //  Stream everything through encryption, removing any partial bundle
+        //
+        let sealer = bundle::create(path, passphrase)?;
+        if let Err(err) = self.write_bundle(sealer, &index) {
+            let _ = fs::remove_file(path);
+            return Err(Error::other(format!("Failed to write bundle: {}", err)));
+        }
+
+        Ok(contents)
+    }
+
+    /// Merges the bundle at `path` into the stash.
+    ///
+    /// The whole bundle is decrypted and checked before anything changes.
+    /// Files and projects already in the stash are dealt with as `conflict`
+    /// says: `Version` keeps an existing file's history ahead of the
+    /// imported one's, while projects, having no history, are replaced.
+    /// Returns the names imported under.
+    ///
+    pub fn import_bundle(
+        &mut self,
+        path: &Path,
+        passphrase: &str,
+        conflict: Conflict,
+    ) -> Result<BundleContents, Error> {
+        //
+        let index = self.check_bundle(path, passphrase)?;
+        for file in &index.files {
+            Self::check_name(&file.name)?;
+            if file.name.ends_with('/') || Self::is_reserved(&file.name) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid file name in bundle: {}", file.name),
+                ));
+            }
+        }
+
+        //  By default, refuse before touching anything
+        //
+        if conflict == Conflict::Error {
+            if let Some(file) = index.files.iter().find(|file| self.exists(&file.name)) {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} is already in stash", file.name),
+                ));
+            }
+            for project in index.envs.keys() {
+                if self.get_env(project)?.is_some() {
+                    return Err(Error::new(
+                        ErrorKind::AlreadyExists,
+                        format!("Environment {} is already in stash", project),
+                    ));
+                }
+            }
+        }
+
+        let mut contents = BundleContents::default();
+        let opener = bundle::open(path, passphrase)?;
+        contents.files = self.read_bundle(opener, &index, conflict)?;
+
+        for (project, value) in &index.envs {
+            let mut target = project.clone();
+            if conflict == Conflict::Rename {
+                let mut n = 1;
+                while self.get_env(&target)?.is_some() {
+                    target = numbered(project, n);
+                    n += 1;
+                }
+            }
+            self.envs
+                .insert(target.as_str(), value.as_slice())
+                .map_err(|err| {
+                    Error::other(format!("Failed to add environment to database: {}", err))
+                })?;
+            contents.envs.push(target);
+        }
+
+        Ok(contents)
+    }
+
+    /// Writes `index` and then each file it lists into a new bundle.
+    ///
+    fn write_bundle(&self, sealer: Sealer<fs::File>, index: &BundleIndex) -> Result<(), Error> {
+        //
+        let mut builder = tar::Builder::new(sealer);
+        builder.follow_symlinks(false);
+
+        let json = Zeroizing::new(serde_json::to_vec(index)?);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(json.len() as u64);
+        header.set_mode(0o600);
+        header.set_mtime(now());
+        builder.append_data(&mut header, "bundle.json", &json[..])?;
+
+        //  Chunks go first, so they're in place before any file needs them
+        //
+        let ids: BTreeSet<String> = index
+            .files
+            .iter()
+            .flat_map(|file| Self::chunk_ids(&file.entry))
+            .collect();
+        if !ids.is_empty() {
+            let store = self.chunk_store()?;
+            for id in ids {
+                builder.append_path_with_name(store.chunk_path(&id)?, format!("chunks/{}", id))?;
+            }
+        }
+
+        for file in &index.files {
+            let name = &file.name;
+            builder.append_path_with_name(self.path.join(name), format!("files/{}", name))?;
+            let revs_path = self.path.join(".revs").join(name);
+            if revs_path.is_dir() {
+                builder.append_dir_all(format!("revs/{}", name), &revs_path)?;
+            }
+        }
+        builder.into_inner()?.finish()?.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Decrypts the whole bundle at `path` to check it, and returns its index.
+    ///
+    fn check_bundle(&self, path: &Path, passphrase: &str) -> Result<BundleIndex, Error> {
+        //
+        let opener = bundle::open(path, passphrase)?;
+        let mut tarball = tar::Archive::new(opener);
+        let mut index = None;
+
+        let result = (|| -> Result<(), Error> {
+            for entry in tarball.entries()? {
+                let mut entry = entry?;
+                if index.is_none() && entry.path()?.as_os_str() == "bundle.json" {
+                    let mut json = Zeroizing::new(Vec::new());
+                    entry.read_to_end(&mut json)?;
+                    index = Some(serde_json::from_slice(&json)?);
+                }
+            }
+            Ok(())
+        })();
+        result
+            .and_then(|_| io::copy(&mut tarball.into_inner(), &mut io::sink()))
+            .map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Failed to read bundle: {}", err),
+                )
+            })?;
+
+        index.ok_or_else(|| Error::new(ErrorKind::InvalidData, "Bundle has no index"))
+    }
+
+    /// Places each file from a checked bundle into the stash.
+    ///
+    /// Returns the names the files were placed under.
+    ///
+    fn read_bundle<R: Read>(
+        &mut self,
+        tarball: R,
+        index: &BundleIndex,
+        conflict: Conflict,
+    ) -> Result<Vec<String>, Error> {
+        //
+        let files: HashMap<&str, &Bundled> = index
+            .files
+            .iter()
+            .map(|file| (file.name.as_str(), file))
+            .collect();
+        let mut tarball = tar::Archive::new(tarball);
+        let mut placed = Vec::new();
+        let mut current: Option<(&Bundled, String, Vec<Revision>)> = None;
+
+        //  Chunks are named under the exporting stash's key, so each is
+        //  sealed again here, which may change its id
+        //
+        let mut store = None;
+        let mut renamed = HashMap::new();
+
+        for entry in tarball.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().to_string();
+            if path == "bundle.json" || entry.header().entry_type().is_dir() {
+                continue;
+            }
+            let unexpected = || {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unexpected member in bundle: {}", path),
+                )
+            };
+
+            //  Each file makes room for itself, then its revisions follow it
+            //
+            if let Some(name) = path.strip_prefix("files/") {
+                if let Some((file, target, existing)) = current.take() {
+                    self.adopt(&target, file, existing, &renamed)?;
+                    placed.push(target);
+                }
+                let file = *files.get(name).ok_or_else(unexpected)?;
+                let (target, existing) = self.claim(name, conflict)?;
+                io::copy(&mut entry, &mut fs::File::create(self.path.join(&target))?)?;
+                current = Some((file, target, existing));
+            } else if let Some(rev) = path.strip_prefix("revs/") {
+                let (file, target, existing) = current.as_ref().ok_or_else(unexpected)?;
+                let number = rev
+                    .strip_prefix(file.name.as_str())
+                    .and_then(|rest| rest.strip_prefix('/'))
+                    .and_then(|number| number.parse::<u32>().ok())
+                    .ok_or_else(unexpected)?;
+                let offset = existing.last().map_or(0, |rev| rev.number);
+                let rev_path = self.revision_path(target, number + offset);
+                if let Some(parent) = rev_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                io::copy(&mut entry, &mut fs::File::create(rev_path)?)?;
+            } else if let Some(id) = path.strip_prefix("chunks/") {
+                let key = index.chunk_keys.get(id).ok_or_else(unexpected)?;
+                if current.is_some() {
+                    return Err(unexpected());
+                }
+                let mut ciphertext = Vec::new();
+                entry.read_to_end(&mut ciphertext)?;
+                let plaintext = chunks::open(id, key, ciphertext)?;
+                if store.is_none() {
+                    store = Some(self.chunk_store()?);
+                }
+                let store = store.as_ref().ok_or_else(unexpected)?;
+                renamed.insert(id.to_string(), store.put_chunk(&plaintext)?);
+            } else {
+                return Err(unexpected());
+            }
+        }
+        if let Some((file, target, existing)) = current.take() {
+            self.adopt(&target, file, existing, &renamed)?;
+            placed.push(target);
+        }
+
+        Ok(placed)
+    }
+
+    /// Registers a file placed from a bundle as `target`.
+    ///
+    /// Its revisions are numbered after `existing`, the history kept from
+    /// a file it replaced, and its chunks go by the ids in `renamed`.
+    ///
+    fn adopt(
+        &mut self,
+        target: &str,
+        file: &Bundled,
+        existing: Vec<Revision>,
+        renamed: &HashMap<String, String>,
+    ) -> Result<(), Error> {
+        //
+        let rename = |chunks: &[String]| {
+            chunks
+                .iter()
+                .map(|id| {
+                    renamed.get(id).cloned().ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Bundle is missing chunk {}", id),
+                        )
+                    })
+                })
+                .collect::<Result<Vec<_>, Error>>()
+        };
+        let offset = existing.last().map_or(0, |rev| rev.number);
+        let mut revisions = existing;
+        for rev in &file.entry.revisions {
+            revisions.push(Revision {
+                number: rev.number + offset,
+                chunks: rename(&rev.chunks)?,
+                ..rev.clone()
+            });
+        }
+        let revision = match file.entry.revision {
+            0 => revisions.last().map_or(1, |rev| rev.number + 1),
+            number => number + offset,
+        };
+        let entry = Entry {
+            revision,
+            revisions,
+            chunks: rename(&file.entry.chunks)?,
+            labels: self.seal_labels(&file.labels)?,
+            ..file.entry.clone()
+        };
+
+        self.register(target, &file.secret, &entry)
+    }
+
+    /// Sets whether files added from now on are stored in chunks.
+    ///
+    /// Chunked files are cut into pieces wherever their contents say so, and
+    /// each piece is stored once however many files and revisions hold it,
+    /// which suits near-duplicates such as successive dumps. Each piece is
+    /// sealed under a key of its own rather than one per file.
+    ///
+    pub fn set_chunking(&mut self, chunking: bool) {
+        self.chunking = chunking;
+    }
+
+    /// Removes the chunks that no file, revision or archive refers to any
+    /// more, and their keys, returning how many went and how many bytes they
+    /// took.
+    ///
+    pub fn gc(&self) -> Result<(usize, u64), Error> {
+        //
+        let mut referenced = HashSet::new();
+        for item in self.entries.iter() {
+            let (_, value) = item?;
+            let entry: Entry = serde_json::from_slice(&value)?;
+            referenced.extend(Self::chunk_ids(&entry));
+        }
+        for item in self.archives.iter() {
+            let (name, value) = item?;
+            let name = String::from_utf8_lossy(&name).to_string();
+            let archive = Self::parse_archive(&value)?;
+            for member in self.read_manifest(&name, &archive)?.files {
+                referenced.extend(Self::chunk_ids(&member.entry));
+            }
+        }
+
+        self.chunk_store()?.gc(&referenced)
+    }
+
+    /// Lists the chunks an entry and its revisions refer to.
+    ///
+    fn chunk_ids(entry: &Entry) -> Vec<String> {
+        let revisions = entry.revisions.iter().flat_map(|rev| rev.chunks.iter());
+        entry.chunks.iter().chain(revisions).cloned().collect()
+    }
+
+    /// Opens the stash's chunk store, creating its key on first use.
+    ///
+    fn chunk_store(&self) -> Result<ChunkStore, Error> {
+        //
+        let key = match self.chunks.get("key")? {
+            Some(key) => Zeroizing::new(key.to_vec()),
+            None => {
+                let key = ChunkStore::generate_key();
+                self.chunks.insert("key", key.as_slice()).map_err(|err| {
+                    Error::other(format!("Failed to add chunk key to database: {}", err))
+                })?;
+                key
+            }
+        };
+
+        Ok(ChunkStore::new(
+            &self.path.join(".chunks"),
+            &key,
+            self.chunks.clone(),
+        ))
+    }
+
+    /// Decrypts the stored file at `path` into memory, or reassembles it
+    /// from the chunks of `entry` if it's a stub, and undoes any compression
+    /// `entry` records.
+    ///
+    fn read_stored(
+        &self,
+        path: &Path,
+        secret: &Secret,
+        entry: Option<&Entry>,
+    ) -> Result<Zeroizing<Vec<u8>>, Error> {
+        //
+        let plaintext = if chunks::is_stub(path) {
+            self.chunk_store()?
+                .get(entry.map_or(&[][..], |entry| &entry.chunks))?
+        } else {
+            Self::decrypt_bytes(path, secret)?
+        };
+
+        Self::decompress(plaintext, entry.and_then(|entry| entry.compression))
+    }
+
+    /// Sets how files added from now on are compressed before encryption,
+    /// overriding the `compress` setting for this stash.
+    ///
+    /// Files that already look compressed, such as images or archives, are
+    /// stored as they are. Refuses if compression has been turned off.
+    ///
+    pub fn set_compression(&mut self, compression: Option<Compression>) -> Result<(), Error> {
+        //
+        if compression.is_some() && self.get_config("compress")? == "off" {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "Compression is turned off for this stash (see `stash config compress`)",
+            ));
+        }
+        self.compression = compression;
+
+        Ok(())
+    }
+
+    /// Returns the value of the setting `key`, or its default if unset.
+    ///
+    pub fn get_config(&self, key: &str) -> Result<String, Error> {
+        //
+        let default = Self::config_default(key)?;
+        Ok(self.config.get(key)?.map_or(default.to_string(), |value| {
+            String::from_utf8_lossy(&value).to_string()
+        }))
+    }
+
+    /// Changes the setting `key` to `value`, after checking it's allowed.
+    ///
+    pub fn set_config(&self, key: &str, value: &str) -> Result<(), Error> {
+        //
+        Self::config_default(key)?;
+        let valid = match key {
+            "compress" => {
+                ["off", "manual"].contains(&value) || value.parse::<Compression>().is_ok()
+            }
+            _ => true,
+        };
+        if !valid {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid value for {}: {}", key, value),
+            ));
+        }
+        self.config
+            .insert(key, value.as_bytes())
+            .map_err(|err| Error::other(format!("Failed to add setting to database: {}", err)))?;
+
+        Ok(())
+    }
+
+    /// Lists every setting along with its current value.
+    ///
+    pub fn config(&self) -> Result<Vec<(String, String)>, Error> {
+        CONFIG
+            .iter()
+            .map(|(key, _)| Ok((key.to_string(), self.get_config(key)?)))
+            .collect()
+    }
+
+    /// Returns the default value of the setting `key`, refusing unknown keys.
+    ///
+    fn config_default(key: &str) -> Result<&'static str, Error> {
+        CONFIG
+            .iter()
+            .find(|(name, _)| *name == key)
+            .map(|(_, default)| *default)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("Unknown setting: {}", key)))
+    }
+
+    /// Picks how to compress a file starting with `head`, or `None` to
+    /// store it as it is.
+    ///
+    /// An explicit choice comes first, then how the file's previous revision
+    /// was stored, then the `compress` setting.
+    ///
+    fn pick_compression(
+        &self,
+        head: &[u8],
+        previous: Option<Compression>,
+    ) -> Result<Option<Compression>, Error> {
+        //
+        let setting = self.get_config("compress")?;
+        let wanted = match setting.as_str() {
+            "off" => return Ok(None),
+            "manual" => self.compression.or(previous),
+            default => self
+                .compression
+                .or(previous)
+                .or(default.parse::<Compression>().ok()),
+        };
+
+        Ok(wanted.filter(|wanted| *wanted != Compression::None && !is_compressed(head)))
+    }
+
+    /// Reads the first few bytes of the file at `path`, enough to recognise
+    /// its format.
+    ///
+    fn read_head(path: &Path) -> Result<Vec<u8>, Error> {
+        //
+        let mut head = Vec::with_capacity(16);
+        fs::File::open(path)?.take(16).read_to_end(&mut head)?;
+
+        Ok(head)
+    }
+
+    /// Compresses `plaintext` with `compression`, or returns `None` if that
+    /// doesn't make it any smaller.
+    ///
+    fn compress(
+        plaintext: &[u8],
+        compression: Compression,
+    ) -> Result<Option<Zeroizing<Vec<u8>>>, Error> {
+        //
+        let header = Header {
+            compression,
+            level: compression.default_level() as u8,
+            volume: 0,
+        };
+        let mut encoder = Encoder::new(Vec::new(), header)?;
+        encoder.write_all(plaintext)?;
+        let packed = Zeroizing::new(
+            encoder
+                .finish()
+                .map_err(|err| Error::other(format!("Failed to compress file: {}", err)))?,
+        );
+
+        Ok(Some(packed).filter(|packed| packed.len() < plaintext.len()))
+    }
+
+    /// Undoes `compression`, if any, on `plaintext`.
+    ///
+    fn decompress(
+        plaintext: Zeroizing<Vec<u8>>,
+        compression: Option<Compression>,
+    ) -> Result<Zeroizing<Vec<u8>>, Error> {
+        //
+        let Some(compression) = compression else {
+            return Ok(plaintext);
+        };
+        let mut unpacked = Zeroizing::new(Vec::new());
+        decoder(&plaintext[..], compression)?
+            .read_to_end(&mut unpacked)
+            .map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Failed to decompress file: {}", err),
+                )
+            })?;
+
+        Ok(unpacked)
+    }
+
+    /// Checks whether `target` already holds a backup repository.
+    ///
+    pub fn has_backup(target: &Path) -> bool {
+        Repo::exists(target)
+    }
+
+    /// Backs up the stash into the repository at `target`, which is created
+    /// under `passphrase` if it doesn't exist yet.
+    ///
+    /// The ciphertext of every file, revision and archive is copied in as it
+    /// is, unless the repository already holds it. The database, keys and
+    /// all, is sealed into a new snapshot. Older snapshots are then thinned
+    /// out as `retention` says, along with the blobs only they needed.
+    ///
+    pub fn backup(
+        &self,
+        target: &Path,
+        passphrase: &str,
+        retention: Retention,
+    ) -> Result<BackupReport, Error> {
+        //
+        let repo = Repo::open_or_create(target, passphrase)?;
+        let mut snapshot = Snapshot::default();
+        snapshot.created = now();
+        let mut report = BackupReport::default();
+
+        //  Copy in whatever ciphertext the repository doesn't hold yet
+        //
+        for path in self.backup_files()? {
+            let full_path = self.path.join(&path);
+            let hash = Self::hash_file(&full_path)?;
+            let mode = fs::metadata(&full_path)?.mode() & 0o7777;
+            let copied = repo.put_blob(&full_path, &hash).map_err(|err| {
+                Error::other(format!("Failed to copy {} to backup: {}", path, err))
+            })?;
+            report.copied += copied as usize;
+            snapshot.files.push(Blob { path, hash, mode });
+        }
+
+        //  Then seal the database into the snapshot, leaving out workspaces,
+        //  which don't outlive the machine they're on
+        //
+        for name in self.db.tree_names() {
+            if &*name == b"workspaces" {
+                continue;
+            }
+            let mut items = Vec::new();
+            for item in self.db.open_tree(&name)?.iter() {
+                let (key, value) = item?;
+                items.push((key.to_vec(), value.to_vec()));
+            }
+            snapshot
+                .trees
+                .insert(String::from_utf8_lossy(&name).to_string(), items);
+        }
+        snapshot.secret = self.master_secret()?.to_vec();
+        let json = Zeroizing::new(serde_json::to_vec(&snapshot)?);
+        report.files = snapshot.files.len();
+        report.snapshot = repo.write_snapshot(&json, snapshot.created)?;
+        report.removed = Self::thin_backups(&repo, retention)?;
+
+        Ok(report)
+    }
+
+    /// Lists the backups in the repository at `target`, oldest first.
+    ///
+    pub fn backups(&self, target: &Path, passphrase: &str) -> Result<Vec<SnapshotInfo>, Error> {
+        //
+        let repo = Repo::open(target, passphrase)?;
+        let mut infos = Vec::new();
+        for id in repo.snapshots()? {
+            let snapshot = Self::read_snapshot(&repo, &id)?;
+            infos.push(SnapshotInfo {
+                id,
+                created: snapshot.created,
+                files: snapshot.files.len(),
+            });
+        }
+
+        Ok(infos)
+    }
+
+    /// Restores a backup from the repository at `target` into the stash.
+    ///
+    /// Restores the latest backup unless `snapshot` names one. The stash must
+    /// be empty, as on a new machine, and every blob is checked against its
+    /// hash before anything is written. Returns the names of the files
+    /// restored.
+    ///
+    pub fn restore_backup(
+        &mut self,
+        target: &Path,
+        passphrase: &str,
+        snapshot: Option<&str>,
+    ) -> Result<Vec<String>, Error> {
+        //
+        let repo = Repo::open(target, passphrase)?;
+        let id = match snapshot {
+            Some(id) if repo.snapshots()?.iter().any(|other| other == id) => id.to_string(),
+            Some(id) => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("Backup {} not found", id),
+                ))
+            }
+            None => repo.snapshots()?.pop().ok_or_else(|| {
+                Error::new(ErrorKind::NotFound, "No backups in backup repository")
+            })?,
+        };
+        let snapshot = Self::read_snapshot(&repo, &id)?;
+
+        if !self.names()?.is_empty() || !self.archives.is_empty() || !self.envs.is_empty() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                "Stash is not empty, so a backup can't be restored into it",
+            ));
+        }
+
+        //  Check every blob, and the master secret, before anything is written
+        //
+        if !matches!(snapshot.secret.len(), 0 | 32) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Master secret in backup is damaged",
+            ));
+        }
+        for blob in &snapshot.files {
+            let path = Path::new(&blob.path);
+            let contained = path
+                .components()
+                .all(|part| matches!(part, Component::Normal(_)));
+            let valid = blob.hash.len() == 64 && blob.hash.bytes().all(|b| b.is_ascii_hexdigit());
+            let top = blob.path.split('/').next().unwrap_or_default();
+            if !contained || !valid || matches!(top, ".db" | ".secret") {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Refusing to restore `{}` from backup", blob.path),
+                ));
+            }
+            match Self::hash_file(&repo.blob_path(&blob.hash)) {
+                Ok(hash) if hash == blob.hash => {}
+                Ok(_) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Backup of {} is damaged", blob.path),
+                    ))
+                }
+                Err(err) => {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!("Backup of {} is missing: {}", blob.path, err),
+                    ))
+                }
+            }
+        }
+
+        //  Put the files back, then the database, undoing both on failure
+        //
+        let mut written = Vec::new();
+        let result = (|| -> Result<(), Error> {
+            for blob in &snapshot.files {
+                let dst_path = self.path.join(&blob.path);
+                if let Some(dir) = dst_path.parent() {
+                    fs::DirBuilder::new()
+                        .recursive(true)
+                        .mode(0o700)
+                        .create(dir)?;
+                }
+                fs::copy(repo.blob_path(&blob.hash), &dst_path)?;
+                written.push(dst_path.clone());
+                fs::set_permissions(&dst_path, fs::Permissions::from_mode(blob.mode))?;
+            }
+            for (name, items) in &snapshot.trees {
+                let tree = self.db.open_tree(name)?;
+                for (key, value) in items {
+                    tree.insert(key.as_slice(), value.as_slice())?;
+                }
+            }
+            self.db.flush()?;
+            if !snapshot.secret.is_empty() {
+                Self::write_master_secret(&self.path.join(".secret"), &snapshot.secret)?;
+            }
+            Ok(())
+        })();
+        if let Err(err) = result {
+            for path in written {
+                let _ = fs::remove_file(path);
+            }
+            for name in snapshot.trees.keys() {
+                if let Ok(tree) = self.db.open_tree(name) {
+                    let _ = tree.clear();
+                }
+            }
+            return Err(Error::other(format!(
+                "Failed to restore backup {}: {}",
+                id, err
+            )));
+        }
+
+        self.names()
+    }
+
+    /// Lists the files to back up, relative to the stash: every stashed file
+    /// and its revisions, and everything kept for archives and chunks.
+    ///
+    fn backup_files(&self) -> Result<Vec<String>, Error> {
+        //
+        let mut files = Vec::new();
+        for name in self.names()? {
+            if self.path.join(&name).is_file() {
+                files.push(name.clone());
+            }
+            let revs_path = self.path.join(".revs").join(&name);
+            if let Ok(items) = fs::read_dir(&revs_path) {
+                let mut revs: Vec<_> = items.collect::<Result<_, _>>()?;
+                revs.sort_by_key(|item| item.file_name());
+                for item in revs.into_iter().filter(|item| item.path().is_file()) {
+                    files.push(format!(
+                        ".revs/{}/{}",
+                        name,
+                        item.file_name().to_string_lossy()
+                    ));
+                }
+            }
+        }
+        for dir in [".archives", ".chunks"] {
+            let dir_path = self.path.join(dir);
+            if dir_path.is_dir() {
+                Self::walk_files(&dir_path, &format!("{}/", dir), &mut files)?;
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Collects the paths of every regular file under `dir`, reserved or not.
+    ///
+    fn walk_files(dir: &Path, prefix: &str, found: &mut Vec<String>) -> Result<(), Error> {
+        //
+        let mut items: Vec<_> = fs::read_dir(dir)?.collect::<Result<_, _>>()?;
+        items.sort_by_key(|item| item.file_name());
+
+        for item in items {
+            let name = format!("{}{}", prefix, item.file_name().to_string_lossy());
+            let kind = item.file_type()?;
+            if kind.is_dir() {
+                Self::walk_files(&item.path(), &format!("{}/", name), found)?;
+            } else if kind.is_file() {
+                found.push(name);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decrypts and parses the snapshot `id` from `repo`.
+    ///
+    fn read_snapshot(repo: &Repo, id: &str) -> Result<Snapshot, Error> {
+        //
+        let json = repo.read_snapshot(id)?;
+        serde_json::from_slice(&json).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Failed to parse backup {}: {}", id, err),
+            )
+        })
+    }
+
+    /// Removes the snapshots in `repo` that `retention` doesn't keep, then
+    /// the blobs that no snapshot left needs. Returns the snapshots removed.
+    ///
+    fn thin_backups(repo: &Repo, retention: Retention) -> Result<Vec<String>, Error> {
+        //
+        if retention == Retention::default() {
+            return Ok(Vec::new());
+        }
+        let mut snapshots = Vec::new();
+        let mut needed: HashMap<String, Vec<String>> = HashMap::new();
+        for id in repo.snapshots()? {
+            let snapshot = Self::read_snapshot(repo, &id)?;
+            snapshots.push((id.clone(), snapshot.created));
+            needed.insert(
+                id,
+                snapshot
+                    .files
+                    .iter()
+                    .map(|blob| blob.hash.clone())
+                    .collect(),
+            );
+        }
+        snapshots.sort_by_key(|(_, created)| *created);
+        let keep = backup::retain(&snapshots, retention.daily, retention.weekly);
+
+        let mut removed = Vec::new();
+        for (id, _) in snapshots {
+            if !keep.contains(&id) {
+                repo.remove_snapshot(&id)?;
+                needed.remove(&id);
+                removed.push(id);
+            }
+        }
+        let needed: HashSet<String> = needed.into_values().flatten().collect();
+        for hash in repo.blobs()? {
+            if !needed.contains(&hash) {
+                repo.remove_blob(&hash)?;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Encrypts a specified file in place using the provided secret.
+    ///
+    fn encrypt(path: &Path, secret: &Secret) -> Result<(), Error> {
+        //
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        let (key, nonce) = secret.split();
+        let mut buffer = Vec::new();
+
+        //  Read file contents into buffer
+        //
+        file.read_to_end(&mut buffer)
+            .map_err(|err| io::Error::other(format!("Failed to read file: {}", err)))?;
+
+        //  Create ciphertext using key, encrypt to buffer using nonce
+        //
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(&key));
+        let result = cipher.encrypt_in_place(GenericArray::from_slice(&nonce), b"", &mut buffer);
+
+        if let Err(err) = result {
+            return Err(Error::other(format!(
+                "Failed to encrypt file {}:\n{}",
+                path.to_str().unwrap(),
+                err
+            )));
+        }
+
+        //  Go back to beginning of file
+        //
+        file.seek(io::SeekFrom::Start(0))
+            .map_err(|err| io::Error::other(format!("Failed to seek file: {}", err)))?;
+
+        //  Write encrypted contents to file
+        //
+        file.write_all(&buffer).map_err(|err| {
+            io::Error::other(format!("Failed to write encrypted data to file: {}", err))
+        })?;
+
+        //  Trim file to new length
+        //
+        file.set_len(buffer.len() as u64)
+            .map_err(|err| io::Error::other(format!("Failed to set file length: {}", err)))?;
+
+        Ok(())
+    }
+
+    /// Decrypts a file in place using the provided secret.
+    ///
+    fn decrypt(path: &Path, secret: &Secret) -> Result<(), Error> {
+        //
+        //  Open file at `path` for reading and writing
+        //
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|err| Error::other(format!("Failed to open file: {}", err)))?;
+
+        let (key, nonce) = secret.split();
+        let mut buffer = Vec::new();
+
+        //  Read contents to buffer
+        //
+        file.read_to_end(&mut buffer)
+            .map_err(|err| io::Error::other(format!("Failed to read file: {}", err)))?;
+
+        //  Create ciphertext using key, decrypt to buffer using nonce
+        //
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(&key));
+        let result = cipher.decrypt_in_place(GenericArray::from_slice(&nonce), b"", &mut buffer);
+
+        if let Err(err) = result {
+            return Err(Error::other(format!("Failed to decrypt file: {}", err)));
+        }
+
+        //  Go back to beginning of file
+        //
+        file.seek(io::SeekFrom::Start(0))
+            .map_err(|err| io::Error::other(format!("Failed to seek file: {}", err)))?;
+
+        //  Write encrypted contents to file
+        //
+        file.write_all(&buffer)
+            .map_err(|err| io::Error::other(format!("Failed to write file: {}", err)))?;
+
+        //  Trim file to new length
+        //
+        file.set_len(buffer.len() as u64)
+            .map_err(|err| io::Error::other(format!("Failed to set file length: {}", err)))?;
+
+        Ok(())
+    }
+
+    /// Encrypts `plaintext` in memory, returning the ciphertext.
+    ///
+    fn encrypt_bytes(plaintext: &[u8], secret: &Secret) -> Result<Vec<u8>, Error> {
+        //
+        let (key, nonce) = secret.split();
+        let mut buffer = plaintext.to_vec();
+
+        //  Create ciphertext using key, encrypt to buffer using nonce
+        //
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(&key));
+        if let Err(err) =
+            cipher.encrypt_in_place(GenericArray::from_slice(&nonce), b"", &mut buffer)
+        {
+            buffer.zeroize();
+            return Err(Error::other(format!("Failed to encrypt data: {}", err)));
+        }
+
+        Ok(buffer)
+    }
+
+    /// Decrypts the file at `path` into memory, leaving it untouched on disk.
+    ///
+    fn decrypt_bytes(path: &Path, secret: &Secret) -> Result<Zeroizing<Vec<u8>>, Error> {
+        //
+        let (key, nonce) = secret.split();
+        let mut buffer = Zeroizing::new(
+            fs::read(path).map_err(|err| Error::other(format!("Failed to read file: {}", err)))?,
+        );
+
+        //  Create ciphertext using key, decrypt to buffer using nonce
+        //
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(&key));
+        cipher
+            .decrypt_in_place(GenericArray::from_slice(&nonce), b"", &mut *buffer)
+            .map_err(|err| Error::other(format!("Failed to decrypt file: {}", err)))?;
+
+        Ok(buffer)
+    }
+
+    /// Computes the SHA-256 digest of the file at `path` as a hex string.
+    ///
+    fn hash_file(path: &Path) -> Result<String, Error> {
+        //
+        let mut file = fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        io::copy(&mut file, &mut hasher)
+            .map_err(|err| Error::other(format!("Failed to hash file: {}", err)))?;
+
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Writes a tarball of `names` into `out`.
+    ///
+    /// The ciphertext of each file goes in under its name, followed by its
+    /// prior revisions under `.revs/`. Returns `out`, along with the offset
+    /// and length of each file's members within the tarball.
+    ///
+    fn create_tarball<W: Write>(
+        &self,
+        names: &[String],
+        out: W,
+    ) -> Result<(W, Vec<(u64, u64)>), Error> {
+        //
+        let mut builder = tar::Builder::new(Counter {
+            inner: out,
+            count: 0,
+        });
+        builder.follow_symlinks(false);
+
+        let mut spans = Vec::new();
+        for name in names {
+            let offset = builder.get_ref().count;
+            builder.append_path_with_name(self.path.join(name), name)?;
+            let revs_path = self.path.join(".revs").join(name);
+            if revs_path.is_dir() {
+                builder.append_dir_all(format!(".revs/{}", name), &revs_path)?;
+            }
+            spans.push((offset, builder.get_ref().count - offset));
+        }
+
+        Ok((builder.into_inner()?.inner, spans))
+    }
+
+    /// Extracts a tarball of stash contents into the stash.
+    ///
+    /// Refuses any member that isn't a plain file or directory, or that would
+    /// land outside the stash or among its program files. With `wanted`, only
+    /// those files and their revisions are extracted, and reading stops once
+    /// they're all out. Returns the names of the files extracted, leaving out
+    /// revisions.
+    ///
+    fn extract_tarball<R: Read>(
+        &self,
+        tarball: R,
+        wanted: Option<&[String]>,
+    ) -> Result<Vec<String>, io::Error> {
+        //
+        let mut archive = tar::Archive::new(tarball);
+        archive.set_preserve_permissions(true);
+        let mut names = Vec::new();
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            let kind = entry.header().entry_type();
+
+            //  Check every member before it goes anywhere
+            //
+            let top = path.components().find_map(|part| match part {
+                Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+                _ => None,
+            });
+            let contained = path
+                .components()
+                .all(|part| matches!(part, Component::Normal(_) | Component::CurDir));
+            if !contained
+                || matches!(
+                    top.as_deref(),
+                    Some(".db" | ".secret" | ".archives" | ".chunks")
+                )
+                || !(kind.is_file() || kind.is_dir())
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Refusing to extract `{}` from archive", path.display()),
+                ));
+            }
+
+            //  Skip unwanted members, until past the last wanted file
+            //
+            let name = path.to_string_lossy();
+            let name = name.trim_start_matches("./");
+            if let Some(wanted) = wanted {
+                let is_wanted = wanted.iter().any(|file| {
+                    name == file
+                        || name
+                            .strip_prefix(".revs/")
+                            .and_then(|rev| rev.strip_prefix(file.as_str()))
+                            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+                });
+                if !is_wanted {
+                    if names.len() == wanted.len() {
+                        break;
+                    }
+                    continue;
+                }
+            }
+
+            entry.unpack_in(&self.path)?;
+            if kind.is_file() && top.as_deref() != Some(".revs") {
+                names.push(name.to_string());
+            }
+        }
+
+        //  Read a full extraction to its end, so nothing goes unchecked
+        //
+        if wanted.is_none() {
+            io::copy(&mut archive.into_inner(), &mut io::sink())?;
+        }
+
+        Ok(names)
+    }
+}
+
+//  Unit tests
+//
+#[cfg(test)]
+//
+mod tests {
+    use crate::*;
+    use serial_test::serial;
+    use std::fs::File;
+    use tempfile::TempDir;
+
+    #[allow(dead_code)]
+    //
+    fn setup() {
+        // todo
+    }
+
+    /// Checks whether any file of `stash`'s database holds `bytes`.
+    ///
+    fn db_holds(stash: &Stash, bytes: &[u8]) -> bool {
+        let mut files = Vec::new();
+        let db_path = stash.path.join(".db");
+        Stash::walk_files(&db_path, "", &mut files).unwrap();
+        files.iter().any(|name| {
+            let contents = fs::read(db_path.join(name)).unwrap();
+            contents.windows(bytes.len()).any(|window| window == bytes)
+        })
+    }
+
+    /// Lists the names in `stash`, in order.
+    ///
+    fn listed(stash: &Stash) -> Vec<String> {
+        let infos = stash.list(Sort::Name, None, None).unwrap();
+        infos.into_iter().map(|info| info.name).collect()
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_new() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path();
+        let stash_path = path.join("test_stash");
+        let db_path = stash_path.join(".db");
+
+        let _ = Stash::test(path);
+
+        assert!(stash_path.exists() && stash_path.is_dir());
+        assert!(db_path.exists() && db_path.is_dir());
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_add() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let stash_path = dir_path.join("test_stash");
+        let file_path = dir_path.join("test.txt");
+        let file_os_str = file_path.file_name().unwrap();
+        let file_str = file_os_str.to_str().unwrap();
+        env::set_current_dir(dir_path).unwrap();
+
+        let mut stash = Stash::test(dir_path);
+
+        let mut file = File::create(&file_path).unwrap();
+        let stashed_file = stash_path.join("test.txt");
+        let test_str = "Testing: one, two...";
+        writeln!(file, "{}", test_str).unwrap();
+
+        stash.add(file_str, false, Conflict::Error).unwrap();
+
+        assert!(stashed_file.exists() && !file_path.exists());
+        let encrypted = fs::read(&stashed_file).unwrap();
+        assert_ne!(test_str.as_bytes(), encrypted);
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_add_copy() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let stash_path = dir_path.join("test_stash");
+        let file_path = dir_path.join("test.txt");
+        let file_os_str = file_path.file_name().unwrap();
+        let file_str = file_os_str.to_str().unwrap();
+        env::set_current_dir(dir_path).unwrap();
+
+        let mut stash = Stash::test(dir_path);
+
+        let mut file = File::create(&file_path).unwrap();
+        let stashed_file = stash_path.join("test.txt");
+        let test_str = "Testing: one, two...";
+        writeln!(file, "{}", test_str).unwrap();
+
+        stash.add(file_str, true, Conflict::Error).unwrap();
+
+        assert!(stashed_file.exists() && file_path.exists());
+        let encrypted = fs::read(&stashed_file).unwrap();
+        assert_ne!(test_str.as_bytes(), encrypted);
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_add_reserved() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let stash_path = dir_path.join("test_stash");
+        env::set_current_dir(dir_path).unwrap();
+
+        let mut stash = Stash::test(dir_path);
+        let db = fs::read_dir(stash_path.join(".db")).unwrap().count();
+
+        //  Files and directories named like the stash's own are left alone
+        //
+        fs::write(dir_path.join(".db"), "Not a database").unwrap();
+        assert!(stash.add(".db", false, Conflict::Overwrite).is_err());
+        assert!(dir_path.join(".db").exists());
+
+        fs::create_dir(dir_path.join(".chunks")).unwrap();
+        fs::write(dir_path.join(".chunks/test.txt"), "Not a chunk").unwrap();
+        assert!(stash
+            .add_dir(".chunks", false, Symlinks::Follow, Conflict::Overwrite)
+            .is_err());
+        assert!(dir_path.join(".chunks/test.txt").exists());
+        assert!(!stash_path.join(".chunks/test.txt").exists());
+
+        assert_eq!(fs::read_dir(stash_path.join(".db")).unwrap().count(), db);
+        assert!(listed(&stash).is_empty());
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_grab() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let stash_path = dir_path.join("test_stash");
+        let file_path = dir_path.join("test.txt");
+        let file_os_str = file_path.file_name().unwrap();
+        let file_str = file_os_str.to_str().unwrap();
+        env::set_current_dir(dir_path).unwrap();
+
+        let mut stash = Stash::test(dir_path);
+
+        let mut file = File::create(&file_path).unwrap();
+        let stashed_file = stash_path.join("test.txt");
+        let test_str = "Testing: one, two...";
+        writeln!(file, "{}", test_str).unwrap();
+
+        stash.add(file_str, false, Conflict::Error).unwrap();
+        assert!(stashed_file.exists() && !file_path.exists());
+
+        let encrypted = fs::read(&stashed_file).unwrap();
+        assert_ne!(test_str.as_bytes(), encrypted);
+
+        stash.grab(file_str, false, Conflict::Error).unwrap();
+        assert!(!stashed_file.exists() && file_path.exists());
+        let decrypted = fs::read_to_string(&file_path).unwrap();
+        assert_eq!(test_str, decrypted.trim());
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_grab_copy() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let stash_path = dir_path.join("test_stash");
+        let file_path = dir_path.join("test.txt");
+        let file_os_str = file_path.file_name().unwrap();
+        let file_str = file_os_str.to_str().unwrap();
+        env::set_current_dir(dir_path).unwrap();
+
+        let mut stash = Stash::test(dir_path);
+
+        let mut file = File::create(&file_path).unwrap();
+        let stashed_file = stash_path.join("test.txt");
+        let test_str = "Testing: one, two...";
+        writeln!(file, "{}", test_str).unwrap();
+
+        stash.add(file_str, false, Conflict::Error).unwrap();
+        assert!(stashed_file.exists() && !file_path.exists());
+
+        let encrypted = fs::read(&stashed_file).unwrap();
+        assert_ne!(test_str.as_bytes(), encrypted);
+
+        stash.grab(file_str, true, Conflict::Error).unwrap();
+        assert!(stashed_file.exists() && file_path.exists());
+        let decrypted = fs::read_to_string(&file_path).unwrap();
+        assert_eq!(test_str, decrypted.trim());
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_delete() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let stash_path = dir_path.join("test_stash");
+        let file_path = dir_path.join("test.txt");
+        let file_os_str = file_path.file_name().unwrap();
+        let file_str = file_os_str.to_str().unwrap();
+        env::set_current_dir(dir_path).unwrap();
+
+        let mut stash = Stash::test(dir_path);
+
+        let mut file = File::create(&file_path).unwrap();
+        let stashed_file = stash_path.join("test.txt");
+        let test_str = "Testing: one, two...";
+        writeln!(file, "{}", test_str).unwrap();
+
+        stash.add(file_str, false, Conflict::Error).unwrap();
+        assert!(stashed_file.exists() && !file_path.exists());
+
+        stash.delete(file_str).unwrap();
+        assert!(!stashed_file.exists());
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_list() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        env::set_current_dir(dir_path).unwrap();
+
+        let stash_path = dir_path.join("test_stash");
+        let file_path1 = dir_path.join("test1");
+        let file_path2 = dir_path.join("test2");
+        let file_path3 = dir_path.join("test3");
+
+        let mut stash = Stash::test(dir_path);
+
+        let _ = File::create(&file_path1).unwrap();
+        let _ = File::create(&file_path2).unwrap();
+        let _ = File::create(&file_path3).unwrap();
+        stash.add("test1", false, Conflict::Error).unwrap();
+        stash.add("test2", false, Conflict::Error).unwrap();
+        stash.add("test3", false, Conflict::Error).unwrap();
+
+        let stashed_file1 = stash_path.join("test1");
+        let stashed_file2 = stash_path.join("test2");
+        let stashed_file3 = stash_path.join("test3");
+        assert!(stashed_file1.exists() && !file_path1.exists());
+        assert!(stashed_file2.exists() && !file_path2.exists());
+        assert!(stashed_file3.exists() && !file_path3.exists());
+
+        let names =
+            |infos: Vec<EntryInfo>| infos.into_iter().map(|info| info.name).collect::<Vec<_>>();
+        let actual = names(stash.list(Sort::Name, None, None).unwrap());
+        assert_eq!(actual, ["test1", "test2", "test3"]);
+
+        //  Internal files stay hidden, and sizes are of the plaintext
+        //
+        stash
+            .add_reader(&mut &b"twelve bytes"[..], "docs/big", Conflict::Error)
+            .unwrap();
+        stash
+            .add_reader(&mut &b"longer still"[..], "docs/big", Conflict::Version)
+            .unwrap();
+        let infos = stash.list(Sort::Size, None, None).unwrap();
+        assert_eq!(infos.len(), 4);
+        assert_eq!((infos[0].name.as_str(), infos[0].size), ("docs/big", 12));
+        assert_eq!((infos[0].revisions, infos[1].revisions), (1, 0));
+        assert_eq!(infos[1].name, "test1");
+        assert_eq!(infos[0].original, "-");
+        assert!(infos[1].original.ends_with("/test1"));
+
+        //  Filters are globs over whole names
+        //
+        let actual = names(stash.list(Sort::Name, Some("docs/*"), None).unwrap());
+        assert_eq!(actual, ["docs/big"]);
+        let actual = names(stash.list(Sort::Name, Some("*2"), None).unwrap());
+        assert_eq!(actual, ["test2"]);
+        assert!(stash.list(Sort::Name, Some("["), None).is_err());
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_list_sort() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        env::set_current_dir(dir_path).unwrap();
+
+        let mut stash = Stash::test(dir_path);
+
+        //  Give each file its own size and times, with one tie on size
+        //
+        for (name, contents, stashed, modified) in [
+            ("a", "xxx", 300, 100),
+            ("b", "xxxxxxxxxx", 100, 300),
+            ("c", "x", 200, 200),
+            ("dir/d", "x", 400, 50),
+        ] {
+            stash
+                .add_reader(&mut contents.as_bytes(), name, Conflict::Error)
+                .unwrap();
+            let mut entry = stash.get_entry(name).unwrap().unwrap();
+            entry.stashed = stashed;
+            entry.modified = modified;
+            stash.put_entry(name, &entry).unwrap();
+        }
+
+        let sorted = |key: &str| {
+            let infos = stash.list(key.parse().unwrap(), None, None).unwrap();
+            infos.into_iter().map(|info| info.name).collect::<Vec<_>>()
+        };
+        assert_eq!(sorted("name"), ["a", "b", "c", "dir/d"]);
+        assert_eq!(sorted("size"), ["b", "a", "c", "dir/d"]);
+        assert_eq!(sorted("stashed"), ["dir/d", "a", "c", "b"]);
+        assert_eq!(sorted("modified"), ["b", "c", "a", "dir/d"]);
+        assert!("date".parse::<Sort>().is_err());
+
+        //  Filters match whole names, without `*` crossing directories
+        //
+        let filtered = |filter: &str| {
+            let infos = stash.list(Sort::Name, Some(filter), None).unwrap();
+            infos.into_iter().map(|info| info.name).collect::<Vec<_>>()
+        };
+        assert_eq!(filtered("*"), ["a", "b", "c"]);
+        assert_eq!(filtered("dir/*"), ["dir/d"]);
+        assert_eq!(filtered("[ab]"), ["a", "b"]);
+        assert!(filtered("missing").is_empty());
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_list_hidden() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let stash_path = dir_path.join("test_stash");
+        env::set_current_dir(dir_path).unwrap();
+
+        let mut stash = Stash::test(dir_path);
+
+        //  Fill every internal directory
+        //
+        stash
+            .add_reader(&mut &b"one"[..], "test1", Conflict::Error)
+            .unwrap();
+        stash
+            .add_reader(&mut &b"two"[..], "test1", Conflict::Version)
+            .unwrap();
+        stash.set_chunking(true);
+        stash
+            .add_reader(&mut &b"chunked"[..], "test2", Conflict::Error)
+            .unwrap();
+        stash.set_chunking(false);
+        stash
+            .add_reader(&mut &b"archived"[..], "test3", Conflict::Error)
+            .unwrap();
+        stash
+            .archive("old", &["test3".to_string()], &ArchiveOptions::default())
+            .unwrap();
+        for dir in [".db", ".revs", ".archives", ".chunks"] {
+            assert!(stash_path.join(dir).is_dir(), "{}", dir);
+        }
+
+        assert_eq!(listed(&stash), ["test1", "test2"]);
+        for filter in ["*", ".*", ".*/*", ".revs/*", ".chunks/*/*"] {
+            let infos = stash.list(Sort::Name, Some(filter), None).unwrap();
+            assert!(infos.iter().all(|info| !info.name.starts_with('.')));
+        }
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_list_json() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        env::set_current_dir(dir_path).unwrap();
+
+        let mut stash = Stash::test(dir_path);
+        stash
+            .add_reader(&mut &b"Testing"[..], "dir/test", Conflict::Error)
+            .unwrap();
+        stash.tag("dir/test", &["work".to_string()]).unwrap();
+        stash.set_note("dir/test", "A note").unwrap();
+
+        //  Scripts rely on every field being there, with these types
+        //
+        let infos = stash.list(Sort::Name, None, None).unwrap();
+        let json = serde_json::to_value(&infos).unwrap();
+        let object = json[0].as_object().unwrap();
+        let mut keys: Vec<&str> = object.keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [
+                "chunked",
+                "cipher",
+                "compression",
+                "modified",
+                "name",
+                "note",
+                "original",
+                "revisions",
+                "size",
+                "stashed",
+                "symlink",
+                "tags",
+            ]
+        );
+        assert_eq!(json[0]["name"], "dir/test");
+        assert_eq!(json[0]["original"], "-");
+        assert_eq!(json[0]["size"], 7);
+        assert!(json[0]["stashed"].is_u64() && json[0]["modified"].is_u64());
+        assert_eq!(json[0]["cipher"], "aes-256-gcm");
+        assert_eq!(json[0]["chunked"], false);
+        assert!(json[0]["compression"].is_null());
+        assert_eq!(json[0]["symlink"], false);
+        assert_eq!(json[0]["revisions"], 0);
+        assert_eq!(json[0]["tags"], serde_json::json!(["work"]));
+        assert_eq!(json[0]["note"], "A note");
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_archive() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        env::set_current_dir(dir_path).unwrap();
+
+        let stash_path = dir_path.join("test_stash");
+        let file_path1 = dir_path.join("test1");
+        let file_path2 = dir_path.join("test2");
+        let file_path3 = dir_path.join("test3");
+
+        let mut stash = Stash::test(dir_path);
+        let test_str = "Testing: one, two...";
+
+        let mut file1 = File::create(&file_path1).unwrap();
+        let mut file2 = File::create(&file_path2).unwrap();
+        let mut file3 = File::create(&file_path3).unwrap();
+        writeln!(file1, "{}", test_str).unwrap();
+        writeln!(file2, "{}", test_str).unwrap();
+        writeln!(file3, "{}", test_str).unwrap();
+        stash.add("test1", false, Conflict::Error).unwrap();
+        stash.add("test2", false, Conflict::Error).unwrap();
+        stash.add("test3", false, Conflict::Error).unwrap();
+
+        let stashed_file1 = stash_path.join("test1");
+        let stashed_file2 = stash_path.join("test2");
+        let stashed_file3 = stash_path.join("test3");
+        assert!(stashed_file1.exists() && !file_path1.exists());
+        assert!(stashed_file2.exists() && !file_path2.exists());
+        assert!(stashed_file3.exists() && !file_path3.exists());
+
+        let archived = stash
+            .archive("test", &[], &ArchiveOptions::default())
+            .unwrap();
+        assert_eq!(archived, vec!["test1", "test2", "test3"]);
+        assert!(stash_path.join(".archives/test/test.001").exists());
+        assert!(stash_path.join(".archives/test/manifest").exists());
+        assert!(!stashed_file1.exists());
+        assert!(stash.list(Sort::Name, None, None).unwrap().is_empty());
+
+        let archives = stash.archives().unwrap();
+        assert_eq!(archives.len(), 1);
+        assert_eq!((archives[0].name.as_str(), archives[0].files), ("test", 3));
+
+        //  The stash stays usable, but names stay unique
+        //
+        File::create(&file_path1).unwrap();
+        stash.add("test1", false, Conflict::Error).unwrap();
+        assert!(stash
+            .archive("test", &[], &ArchiveOptions::default())
+            .is_err());
+        assert!(stash
+            .archive("../test", &[], &ArchiveOptions::default())
+            .is_err());
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_unpack() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        env::set_current_dir(dir_path).unwrap();
+
+        let stash_path = dir_path.join("test_stash");
+        let file_path1 = dir_path.join("test1");
+        let file_path2 = dir_path.join("test2");
+        let file_path3 = dir_path.join("test3");
+
+        let mut stash = Stash::test(dir_path);
+        let test_str = "Testing: one, two...";
+
+        let mut file1 = File::create(&file_path1).unwrap();
+        let mut file2 = File::create(&file_path2).unwrap();
+        let mut file3 = File::create(&file_path3).unwrap();
+        writeln!(file1, "{}", test_str).unwrap();
+        writeln!(file2, "{}", test_str).unwrap();
+        writeln!(file3, "{}", test_str).unwrap();
+        stash.add("test1", false, Conflict::Error).unwrap();
+        stash.add("test2", false, Conflict::Error).unwrap();
+        stash.add("test3", false, Conflict::Error).unwrap();
+
+        let stashed_file1 = stash_path.join("test1");
+        let stashed_file2 = stash_path.join("test2");
+        let stashed_file3 = stash_path.join("test3");
+        assert!(stashed_file1.exists() && !file_path1.exists());
+        assert!(stashed_file2.exists() && !file_path2.exists());
+        assert!(stashed_file3.exists() && !file_path3.exists());
+
+        let before_archiving = listed(&stash);
+        stash
+            .archive("one", &["test1".to_string()], &ArchiveOptions::default())
+            .unwrap();
+        assert_eq!(listed(&stash), ["test2", "test3"]);
+        stash
+            .archive("two", &[], &ArchiveOptions::default())
+            .unwrap();
+        assert!(stash.list(Sort::Name, None, None).unwrap().is_empty());
+        assert!(stash.unpack(None, &[]).is_err());
+
+        assert_eq!(stash.unpack(Some("one"), &[]).unwrap(), vec!["test1"]);
+        assert_eq!(stash.unpack(None, &[]).unwrap(), vec!["test2", "test3"]);
+        assert_eq!(listed(&stash), before_archiving);
+        assert!(stash.archives().unwrap().is_empty());
+
+        //  Unpacked files decrypt as before
+        //
+        let mut out = Vec::new();
+        stash.cat("test1", &mut out).unwrap();
+        assert_eq!(out, format!("{}\n", test_str).as_bytes());
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_named_archives() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let stash_path = dir_path.join("test_stash");
+        env::set_current_dir(dir_path).unwrap();
+
+        let mut stash = Stash::test(dir_path);
+
+        //  A file of the user's may share its name with legacy archives
+        //
+        for name in ["contents", "dir/test1", "test1", "test2", "test3"] {
+            stash
+                .add_reader(&mut name.as_bytes(), name, Conflict::Error)
+                .unwrap();
+        }
+        let options = ArchiveOptions::default();
+        stash
+            .archive("one", &["test1".to_string()], &options)
+            .unwrap();
+        stash
+            .archive("two", &["test2".to_string(), "dir/*".to_string()], &options)
+            .unwrap();
+
+        //  Both archives sit alongside what's left in the stash
+        //
+        let archives: Vec<String> = stash
+            .archives()
+            .unwrap()
+            .into_iter()
+            .map(|archive| archive.name)
+            .collect();
+        assert_eq!(archives, ["one", "two"]);
+        assert_eq!(listed(&stash), ["contents", "test3"]);
+
+        //  Archived names only appear in the encrypted manifests
+        //
+        for item in stash.archives.iter() {
+            let (_, value) = item.unwrap();
+            let record = String::from_utf8_lossy(&value);
+            assert!(!record.contains("test1") && !record.contains("test2"));
+        }
+        assert_eq!(stash.info("dir/test1").unwrap().archives, ["two"]);
+        assert!(stash_path.join(".archives/one/manifest").exists());
+        assert!(stash_path.join(".archives/two/manifest").exists());
+
+        //  Adding still works, and `contents` is left as it was
+        //
+        stash
+            .add_reader(&mut &b"test4"[..], "test4", Conflict::Error)
+            .unwrap();
+        assert_eq!(listed(&stash), ["contents", "test3", "test4"]);
+        let mut out = Vec::new();
+        stash.cat("contents", &mut out).unwrap();
+        assert_eq!(out, b"contents");
+
+        //  Unpacking one archive leaves the other alone
+        //
+        assert_eq!(
+            stash.unpack(Some("two"), &[]).unwrap(),
+            ["test2", "dir/test1"]
+        );
+        assert_eq!(
+            listed(&stash),
+            ["contents", "dir/test1", "test2", "test3", "test4"]
+        );
+        assert_eq!(stash.archives().unwrap().len(), 1);
+        assert!(stash_path.join(".archives/one/manifest").exists());
+        assert!(!stash_path.join(".archives/two").exists());
+
+        assert_eq!(stash.unpack(None, &[]).unwrap(), ["test1"]);
+        assert!(stash.archives().unwrap().is_empty());
+        let mut out = Vec::new();
+        stash.cat("contents", &mut out).unwrap();
+        assert_eq!(out, b"contents");
+        let mut out = Vec::new();
+        stash.cat("test1", &mut out).unwrap();
+        assert_eq!(out, b"test1");
+    }
+
+    #[test]
+    #[serial]
+    fn test_valid_encrypt() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+
+        let secret = Secret::new();
+        env::set_current_dir(dir_path).unwrap();
+
+        let file_path = dir_path.join("test");
+        let mut file = File::create(&file_path).unwrap();
+        let test_str = "Testing: one, two...";
+        writeln!(file, "{}", test_str).unwrap();
+
+        let result = Stash::encrypt(&file_path, &secret);
+        assert!(result.is_ok());
+
+        let encrypted = fs::read(&file_path).unwrap();
+        assert_ne!(test_str.as_bytes(), encrypted);
+    }
+
+    #[test]
+    #[serial]
+    fn test_valid_decrypt() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+
+        let secret = Secret::new();
+        env::set_current_dir(dir_path).unwrap();
+
+        let file_path = dir_path.join("test");
+        let mut file = File::create(&file_path).unwrap();
+        let test_str = "Testing: one, two...";
+        writeln!(file, "{}", test_str).unwrap();
+
+        let result = Stash::encrypt(&file_path, &secret);
+        assert!(result.is_ok());
+
+        let encrypted = fs::read(&file_path).unwrap();
+        assert_ne!(test_str.as_bytes(), encrypted);
+
+        let result = Stash::decrypt(&file_path, &secret);
+        assert!(result.is_ok());
+
+        let decrypted = fs::read_to_string(&file_path).unwrap();
+        assert_eq!(test_str, decrypted.trim());
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_create_tarball() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        env::set_current_dir(dir_path).unwrap();
+
+        let stash_path = dir_path.join("test_stash");
+        let file_path1 = dir_path.join("test1");
+        let file_path2 = dir_path.join("test2");
+        let file_path3 = dir_path.join("test3");
+
+        let mut stash = Stash::test(dir_path);
+        let test_str = "Testing: one, two...";
+
+        let mut file1 = File::create(&file_path1).unwrap();
+        let mut file2 = File::create(&file_path2).unwrap();
+        let mut file3 = File::create(&file_path3).unwrap();
+        writeln!(file1, "{}", test_str).unwrap();
+        writeln!(file2, "{}", test_str).unwrap();
+        writeln!(file3, "{}", test_str).unwrap();
+        stash.add("test1", false, Conflict::Error).unwrap();
+        stash.add("test2", false, Conflict::Error).unwrap();
+        stash.add("test3", false, Conflict::Error).unwrap();
+
+        let stashed_file1 = stash_path.join("test1");
+        let stashed_file2 = stash_path.join("test2");
+        let stashed_file3 = stash_path.join("test3");
+        assert!(stashed_file1.exists() && !file_path1.exists());
+        assert!(stashed_file2.exists() && !file_path2.exists());
+        assert!(stashed_file3.exists() && !file_path3.exists());
+
+        let names = stash.names().unwrap();
+        let (tarball, spans) = stash.create_tarball(&names, Vec::new()).unwrap();
+        let members: Vec<String> = tar::Archive::new(&tarball[..])
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(members, vec!["test1", "test2", "test3"]);
+
+        //  Each file can be read straight from its offset
+        //
+        let (offset, length) = spans[1];
+        let member = &tarball[offset as usize..(offset + length) as usize];
+        let mut entries = tar::Archive::new(member);
+        let entry = entries.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(entry.path().unwrap().to_str(), Some("test2"));
+
+        //  Nothing is written or removed until the archive is encrypted
+        //
+        assert!(!stash_path.join(".archives").exists());
+        assert!(stashed_file1.exists());
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_extract_tarball() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        env::set_current_dir(dir_path).unwrap();
+
+        let stash_path = dir_path.join("test_stash");
+        let file_path1 = dir_path.join("test1");
+        let file_path2 = dir_path.join("test2");
+        let file_path3 = dir_path.join("test3");
+
+        let mut stash = Stash::test(dir_path);
+        let test_str = "Testing: one, two...";
+
+        let mut file1 = File::create(&file_path1).unwrap();
+        let mut file2 = File::create(&file_path2).unwrap();
+        let mut file3 = File::create(&file_path3).unwrap();
+        writeln!(file1, "{}", test_str).unwrap();
+        writeln!(file2, "{}", test_str).unwrap();
+        writeln!(file3, "{}", test_str).unwrap();
+        stash.add("test1", false, Conflict::Error).unwrap();
+        stash.add("test2", false, Conflict::Error).unwrap();
+        stash.add("test3", false, Conflict::Error).unwrap();
+
+        let stashed_file1 = stash_path.join("test1");
+        let stashed_file2 = stash_path.join("test2");
+        let stashed_file3 = stash_path.join("test3");
+        assert!(stashed_file1.exists() && !file_path1.exists());
+        assert!(stashed_file2.exists() && !file_path2.exists());
+        assert!(stashed_file3.exists() && !file_path3.exists());
+
+        let before_archiving = listed(&stash);
+        let names = stash.names().unwrap();
+        let (tarball, _) = stash.create_tarball(&names, Vec::new()).unwrap();
+        for file in [&stashed_file1, &stashed_file2, &stashed_file3] {
+            fs::remove_file(file).unwrap();
+        }
+        assert!(stash.list(Sort::Name, None, None).unwrap().is_empty());
+        let wanted = ["test2".to_string()];
+        let extracted = stash.extract_tarball(&tarball[..], Some(&wanted));
+        assert_eq!(extracted.unwrap(), wanted);
+        fs::remove_file(&stashed_file2).unwrap();
+        assert_eq!(stash.extract_tarball(&tarball[..], None).unwrap(), names);
+        assert_eq!(listed(&stash), before_archiving);
+
+        //  Members that would escape the stash are refused
+        //
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..8].copy_from_slice(b"../evil\0");
+        header.set_size(4);
+        header.set_cksum();
+        builder.append(&header, &b"evil"[..]).unwrap();
+        let tarball = builder.into_inner().unwrap();
+        assert!(stash.extract_tarball(&tarball[..], None).is_err());
+        assert!(!dir_path.join("evil").exists());
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_tarball_names() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let stash_path = dir_path.join("test_stash");
+        env::set_current_dir(dir_path).unwrap();
+
+        let mut stash = Stash::test(dir_path);
+
+        //  Awkward names come back out as they went in
+        //
+        let names = [
+            ".hidden",
+            "dir/.dot file",
+            "quote\"s 'and' more.txt",
+            "with space.txt",
+        ];
+        for name in names {
+            stash
+                .add_reader(&mut name.as_bytes(), name, Conflict::Error)
+                .unwrap();
+        }
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let (tarball, _) = stash.create_tarball(&names, Vec::new()).unwrap();
+        for name in &names {
+            fs::remove_file(stash_path.join(name)).unwrap();
+        }
+        assert_eq!(stash.extract_tarball(&tarball[..], None).unwrap(), names);
+        for name in &names {
+            let mut out = Vec::new();
+            stash.cat(name, &mut out).unwrap();
+            assert_eq!(out, name.as_bytes());
+        }
+
+        //  Members outside the stash, or among its program files, are refused
+        //
+        for path in [
+            &b"../evil"[..],
+            b"dir/../../evil",
+            b"/tmp/evil",
+            b".db/evil",
+            b".archives/evil",
+            b".chunks/evil",
+        ] {
+            let mut header = tar::Header::new_old();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path);
+            header.set_size(4);
+            header.set_cksum();
+            let mut builder = tar::Builder::new(Vec::new());
+            builder.append(&header, &b"evil"[..]).unwrap();
+            let tarball = builder.into_inner().unwrap();
+            assert!(stash.extract_tarball(&tarball[..], None).is_err());
+        }
+        assert!(!dir_path.join("evil").exists());
+        assert!(!Path::new("/tmp/evil").exists());
+        assert!(!stash_path.join(".db/evil").exists());
+        assert!(!stash_path.join(".archives/evil").exists());
+        assert!(!stash_path.join(".chunks/evil").exists());
+
+        //  So are links, which could point anywhere
+        //
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        let mut builder = tar::Builder::new(Vec::new());
+        builder
+            .append_link(&mut header, "link", "/etc/passwd")
+            .unwrap();
+        let tarball = builder.into_inner().unwrap();
+        assert!(stash.extract_tarball(&tarball[..], None).is_err());
+        assert!(fs::symlink_metadata(stash_path.join("link")).is_err());
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_add_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let stash_path = dir_path.join("test_stash");
+        env::set_current_dir(dir_path).unwrap();
+
+        let mut stash = Stash::test(dir_path);
+        let test_str = "Testing: one, two...";
+
+        fs::create_dir_all(dir_path.join("secrets/nested")).unwrap();
+        fs::write(dir_path.join("secrets/test1"), test_str).unwrap();
+        fs::write(dir_path.join("secrets/nested/test2"), test_str).unwrap();
+        fs::write(dir_path.join("secrets/skip.log"), test_str).unwrap();
+        fs::write(dir_path.join("secrets/.stashignore"), "*.log\n").unwrap();
+
+        let added = stash
+            .add_dir("secrets", false, Symlinks::Skip, Conflict::Error)
+            .unwrap();
+        assert_eq!(added, vec!["secrets/nested/test2", "secrets/test1"]);
+
+        let stashed_file = stash_path.join("secrets/nested/test2");
+        assert!(stashed_file.exists() && !dir_path.join("secrets/nested").exists());
+        assert!(!stash_path.join("secrets/skip.log").exists());
+        assert!(dir_path.join("secrets/skip.log").exists());
+        assert_ne!(test_str.as_bytes(), fs::read(&stashed_file).unwrap());
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_grab_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let stash_path = dir_path.join("test_stash");
+        env::set_current_dir(dir_path).unwrap();
+
+        let mut stash = Stash::test(dir_path);
+        let test_str = "Testing: one, two...";
+
+        fs::create_dir_all(dir_path.join("secrets/nested")).unwrap();
+        fs::write(dir_path.join("secrets/test1"), test_str).unwrap();
+        fs::write(dir_path.join("secrets/nested/test2"), test_str).unwrap();
+        fs::set_permissions(
+            dir_path.join("secrets/test1"),
+            fs::Permissions::from_mode(0o600),
+        )
+        .unwrap();
+        symlink("test1", dir_path.join("secrets/link")).unwrap();
+
+        stash
+            .add_dir("secrets", false, Symlinks::Preserve, Conflict::Error)
+            .unwrap();
+        assert!(!dir_path.join("secrets").exists());
+
+        stash.grab_dir("secrets", false, Conflict::Error).unwrap();
+        assert!(!stash_path.join("secrets").exists());
+
+        let decrypted = fs::read_to_string(dir_path.join("secrets/nested/test2")).unwrap();
+        assert_eq!(test_str, decrypted);
+        let mode = fs::metadata(dir_path.join("secrets/test1")).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let target = fs::read_link(dir_path.join("secrets/link")).unwrap();
+        assert_eq!(target, Path::new("test1"));
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_find() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        env::set_current_dir(dir_path).unwrap();
+
+        let mut stash = Stash::test(dir_path);
+
+        fs::create_dir_all(dir_path.join("certs/old")).unwrap();
+        fs::write(dir_path.join("certs/a.pem"), "a").unwrap();
+        fs::write(dir_path.join("certs/b.pem"), "b").unwrap();
+        fs::write(dir_path.join("certs/c.key"), "c").unwrap();
+        fs::write(dir_path.join("certs/old/d.pem"), "d").unwrap();
+        stash
+            .add_dir("certs", false, Symlinks::Skip, Conflict::Error)
+            .unwrap();
+
+        let found = stash.find("certs/*.pem").unwrap();
+        assert_eq!(found, vec!["certs/a.pem", "certs/b.pem"]);
+        let found = stash.find("certs/**/*.pem").unwrap();
+        assert_eq!(found, vec!["certs/a.pem", "certs/b.pem", "certs/old/d.pem"]);
+        assert_eq!(stash.find("certs/c.key").unwrap(), vec!["certs/c.key"]);
+        assert!(stash.find("*.txt").is_err());
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_add_conflict() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let stash_path = dir_path.join("test_stash");
+        env::set_current_dir(dir_path).unwrap();
+
+        let mut stash = Stash::test(dir_path);
+
+        fs::write(dir_path.join("test.txt"), "one").unwrap();
+        stash.add("test.txt", true, Conflict::Error).unwrap();
+        assert!(stash.add("test.txt", true, Conflict::Error).is_err());
+
+        let renamed = stash.add("test.txt", true, Conflict::Rename).unwrap();
+        assert_eq!(renamed, "test (1).txt");
+        assert!(stash_path.join("test (1).txt").exists());
+
+        fs::write(dir_path.join("test.txt"), "two").unwrap();
+        stash.add("test.txt", true, Conflict::Version).unwrap();
+        let entry = stash.get_entry("test.txt").unwrap().unwrap();
+        assert_eq!(entry.revisions.len(), 1);
+        assert!(stash.revision_path("test.txt", 1).exists());
+
+        fs::write(dir_path.join("test.txt"), "three").unwrap();
+        stash.add("test.txt", false, Conflict::Overwrite).unwrap();
+        let entry = stash.get_entry("test.txt").unwrap().unwrap();
+        assert!(entry.revisions.is_empty());
+        assert!(!stash.revision_path("test.txt", 1).exists());
+
+        stash.grab("test.txt", false, Conflict::Error).unwrap();
+        assert_eq!(fs::read_to_string("test.txt").unwrap(), "three");
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_grab_conflict() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        env::set_current_dir(dir_path).unwrap();
+
+        let mut stash = Stash::test(dir_path);
+
+        fs::write(dir_path.join("test.txt"), "stashed").unwrap();
+        stash.add("test.txt", true, Conflict::Error).unwrap();
+        fs::write(dir_path.join("test.txt"), "local").unwrap();
+        assert!(stash.grab("test.txt", true, Conflict::Error).is_err());
+
+        let renamed = stash.grab("test.txt", true, Conflict::Rename).unwrap();
+        assert_eq!(renamed, "test (1).txt");
+        assert_eq!(fs::read_to_string("test (1).txt").unwrap(), "stashed");
+
+        stash.grab("test.txt", true, Conflict::Version).unwrap();
+        assert_eq!(fs::read_to_string("test.txt.~1~").unwrap(), "local");
+        assert_eq!(fs::read_to_string("test.txt").unwrap(), "stashed");
+
+        fs::write(dir_path.join("test.txt"), "local").unwrap();
+        stash.grab("test.txt", false, Conflict::Overwrite).unwrap();
+        assert_eq!(fs::read_to_string("test.txt").unwrap(), "stashed");
+        assert!(!stash.exists("test.txt"));
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_log() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        env::set_current_dir(dir_path).unwrap();
 
-        //  Encrypt the new tarball
-        //
-        Self::encrypt(&self.contents, &secret).map_err(|err| {
-            Error::new(ErrorKind::Other, format!("Failed to encrypt file: {}", err))
-        })?;
+        let mut stash = Stash::test(dir_path);
 
-        //  Add its encryption secrets to the database
-        //
-        self.db
-            .insert(description.as_bytes(), secret.join())
-            .map_err(|err| {
-                Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to add encryption secrets to database: {}", err),
-                )
-            })?;
+        for version in ["one", "two", "three"] {
+            fs::write(dir_path.join("test.txt"), version).unwrap();
+            stash.add("test.txt", false, Conflict::Version).unwrap();
+        }
 
-        //  Also cache its secrets in the keyring
-        //
-        self.keyring
-            .add_key(&description, &secret.join())
-            .map_err(|err| {
-                Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to cache encryption secrets: {}", err),
-                )
-            })?;
+        let log = stash.log("test.txt").unwrap();
+        let numbers: Vec<u32> = log.iter().map(|rev| rev.number).collect();
+        assert_eq!(numbers, vec![3, 2, 1]);
+        assert!(log[0].current && !log[1].current);
+        assert_eq!(log[2].hash.len(), 64);
+        assert_ne!(log[0].hash, log[2].hash);
+    }
 
-        self.is_archived = true;
+    #[test]
+    #[serial]
+    //
+    fn test_valid_grab_rev() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        env::set_current_dir(dir_path).unwrap();
 
-        Ok(())
-    }
+        let mut stash = Stash::test(dir_path);
 
-    /// Extract the `contents` file from the stash archive.
-    ///
-    pub fn unpack(&mut self) -> Result<(), Error> {
-        //
-        //  Bail if nothing is archived
-        //
-        if !self.is_archived {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "No archive exists",
-            ));
+        for version in ["one", "two", "three"] {
+            fs::write(dir_path.join("test.txt"), version).unwrap();
+            stash.add("test.txt", false, Conflict::Version).unwrap();
         }
-        //  Get contents file name to use for secret lookup
-        //
-        let file_name = self.contents.file_name().ok_or(Error::new(
-            ErrorKind::InvalidData,
-            "Failed to get `contents` file name",
-        ))?;
 
-        let description = file_name.to_string_lossy().to_string();
-        let secret;
+        stash
+            .grab_rev("test.txt", 2, dir_path, Conflict::Error)
+            .unwrap();
+        assert_eq!(fs::read_to_string("test.txt").unwrap(), "two");
+        stash
+            .grab_rev("test.txt", 1, dir_path, Conflict::Overwrite)
+            .unwrap();
+        assert_eq!(fs::read_to_string("test.txt").unwrap(), "one");
+        stash
+            .grab_rev("test.txt", 3, dir_path, Conflict::Overwrite)
+            .unwrap();
+        assert_eq!(fs::read_to_string("test.txt").unwrap(), "three");
+        assert!(stash
+            .grab_rev("test.txt", 4, dir_path, Conflict::Overwrite)
+            .is_err());
+    }
 
-        //  Look for cached secret on keyring
-        //
-        if let Ok(key) = self.keyring.search(&description) {
-            let key_bytes = key.read_to_vec().map_err(|err| {
-                Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to read encryption secrets: {}", err),
-                )
-            })?;
+    #[test]
+    #[serial]
+    //
+    fn test_valid_prune() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        env::set_current_dir(dir_path).unwrap();
 
-            secret = Secret::from(&key_bytes);
+        let mut stash = Stash::test(dir_path);
 
-            //  Remove key if secret is found
-            //
-            key.invalidate().map_err(|err| {
-                Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to remove encryption secrets from cache: {}", err),
-                )
-            })?;
-            //    Or check database
-            //
-        } else if let Some(value) = self.db.get(&description)? {
-            secret = Secret::from(&value);
-            //
-            //          Or throw
-        } else {
-            return Err(io::Error::new(io::ErrorKind::NotFound, "Secret not found"));
+        for version in ["one", "two", "three", "four"] {
+            fs::write(dir_path.join("test.txt"), version).unwrap();
+            stash.add("test.txt", false, Conflict::Version).unwrap();
         }
 
-        //  Decrypt the tarball in place
+        //  Revision keys are only ever stored sealed
         //
-        Self::decrypt(&self.contents, &secret).map_err(|err| {
-            Error::new(ErrorKind::Other, format!("Failed to decrypt file: {}", err))
-        })?;
-
-        //  Extract its contents into stash
+        let entry = stash.get_entry("test.txt").unwrap().unwrap();
+        let keys: Vec<Vec<u8>> = entry
+            .revisions
+            .iter()
+            .map(|rev| rev.secret.key.clone())
+            .collect();
+        let kek = stash.keys.get("test.txt").unwrap().unwrap().to_vec();
+        stash.db.flush().unwrap();
+        assert!(db_holds(&stash, &kek));
+
+        let dropped = stash.prune("test.txt", Some(1), None).unwrap();
+        assert_eq!(dropped, vec![1, 2]);
+        assert!(!stash.revision_path("test.txt", 1).exists());
+        assert!(stash.revision_path("test.txt", 3).exists());
+
+        //  Nothing left in the database opens the dropped revisions
         //
-        self.extract_tarball().map_err(|err| {
-            Error::new(
-                ErrorKind::Other,
-                format!("Failed to extract archive: {}", err),
-            )
-        })?;
-
-        //  Remove `file` encryption secrets from database
+        assert!(!db_holds(&stash, &kek));
+        assert!(!db_holds(&stash, &keys[0]));
+        assert!(!db_holds(&stash, &keys[1]));
+        assert!(!stash.path.join(".db.old").exists());
+        let name = stash
+            .grab_rev("test.txt", 3, dir_path, Conflict::Overwrite)
+            .unwrap();
+        assert_eq!(fs::read_to_string(dir_path.join(name)).unwrap(), "three");
+
+        let dropped = stash.prune("test.txt", None, Some(3600)).unwrap();
+        assert!(dropped.is_empty());
+
+        let numbers: Vec<u32> = stash
+            .log("test.txt")
+            .unwrap()
+            .iter()
+            .map(|rev| rev.number)
+            .collect();
+        assert_eq!(numbers, vec![4, 3]);
+
+        let dropped = stash.prune("test.txt", Some(0), None).unwrap();
+        assert_eq!(dropped, vec![3]);
+        assert!(!stash.path.join(".revs").exists());
+        assert!(stash.keys.get("test.txt").unwrap().is_none());
+
+        //  Chunks only a dropped revision held go, along with their keys
         //
-        self.db.remove(description).map_err(|err| {
-            Error::new(
-                ErrorKind::Other,
-                format!("Failed to remove encryption secrets from database: {}", err),
-            )
-        })?;
+        stash.set_chunking(true);
+        let old: Vec<u8> = (0..200_000u32).map(|n| (n * 7 % 251) as u8).collect();
+        let new: Vec<u8> = (0..200_000u32).map(|n| (n * 11 % 241) as u8).collect();
+        stash
+            .add_reader(&mut &old[..], "big.bin", Conflict::Error)
+            .unwrap();
+        stash
+            .add_reader(&mut &new[..], "big.bin", Conflict::Version)
+            .unwrap();
+        let entry = stash.get_entry("big.bin").unwrap().unwrap();
+        let store = stash.chunk_store().unwrap();
+        let ids = entry.revisions[0].chunks.clone();
+        let keys: Vec<_> = ids.iter().map(|id| store.chunk_key(id).unwrap()).collect();
+        drop(store);
+
+        assert_eq!(stash.prune("big.bin", Some(0), None).unwrap(), vec![1]);
+        for (id, key) in ids.iter().zip(&keys) {
+            assert!(stash.chunks.get(id).unwrap().is_none());
+            assert!(!db_holds(&stash, key));
+        }
+        let mut out = Vec::new();
+        stash.cat("big.bin", &mut out).unwrap();
+        assert_eq!(out, new);
+    }
+
+    #[test]
+    #[serial]
+    //
+    fn test_valid_grab_to() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        env::set_current_dir(dir_path).unwrap();
 
-        self.is_archived = false;
+        let mut stash = Stash::test(dir_path);
+        let test_str = "Testing: one, two...";
 
-        Ok(())
+        fs::write(dir_path.join("test.txt"), test_str).unwrap();
+        stash.add("test.txt", false, Conflict::Error).unwrap();
+        fs::create_dir(dir_path.join("out")).unwrap();
+
+        let name = stash
+            .grab_to("test.txt", &dir_path.join("out"), true, Conflict::Error)
+            .unwrap();
+        assert_eq!(name, "test.txt");
+        assert_eq!(fs::read_to_string("out/test.txt").unwrap(), test_str);
+
+        let name = stash
+            .grab_to(
+                "test.txt",
+                &dir_path.join("out/renamed.txt"),
+                false,
+                Conflict::Error,
+            )
+            .unwrap();
+        assert_eq!(name, "renamed.txt");
+        assert_eq!(fs::read_to_string("out/renamed.txt").unwrap(), test_str);
+        assert!(!dir_path.join("test.txt").exists());
     }
 
-    /// Encrypts a specified file in place using the provided secret.
-    ///
-    fn encrypt(path: &Path, secret: &Secret) -> Result<(), Error> {
-        //
-        let mut file = fs::OpenOptions::new().read(true).write(true).open(path)?;
-        let (key, nonce) = secret.split();
-        let mut buffer = Vec::new();
+    #[test]
+    #[serial]
+    //
+    fn test_valid_cat() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        env::set_current_dir(dir_path).unwrap();
 
-        //  Read file contents into buffer
-        //
-        file.read_to_end(&mut buffer).map_err(|err| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to read file: {}", err),
-            )
-        })?;
+        let mut stash = Stash::test(dir_path);
+        let test_str = "Testing: one, two...";
 
-        //  Create ciphertext using key, encrypt to buffer using nonce
-        //
-        let cipher = Aes256Gcm::new(GenericArray::from_slice(&key));
-        let result = cipher.encrypt_in_place(GenericArray::from_slice(&nonce), b"", &mut buffer);
+        fs::write(dir_path.join("test.txt"), test_str).unwrap();
+        stash.add("test.txt", false, Conflict::Error).unwrap();
 
-        if let Err(err) = result {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("Failed to encrypt file {}:\n{}", path.to_str().unwrap(), err),
-            ));
-        }
+        let mut out = Vec::new();
+        stash.cat("test.txt", &mut out).unwrap();
+        assert_eq!(out, test_str.as_bytes());
+        assert!(!dir_path.join("test.txt").exists());
 
-        //  Go back to beginning of file
-        //
-        file.seek(io::SeekFrom::Start(0)).map_err(|err| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to seek file: {}", err),
-            )
-        })?;
+        stash.cat("test.txt", &mut out).unwrap();
+        assert_eq!(out.len(), 2 * test_str.len());
+    }
 
-        //  Write encrypted contents to file
-        //
-        file.write_all(&buffer).map_err(|err| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to write encrypted data to file: {}", err),
-            )
-        })?;
+    #[test]
+    #[serial]
+    //
+    fn test_valid_add_reader() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let stash_path = dir_path.join("test_stash");
+        env::set_current_dir(dir_path).unwrap();
 
-        //  Trim file to new length
-        //
-        file.set_len(buffer.len() as u64).map_err(|err| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to set file length: {}", err),
-            )
-        })?;
+        let mut stash = Stash::test(dir_path);
+        let test_str = "Testing: one, two...";
 
-        Ok(())
-    }
+        let name = stash
+            .add_reader(&mut test_str.as_bytes(), "dumps/test.sql", Conflict::Error)
+            .unwrap();
+        assert_eq!(name, "dumps/test.sql");
 
-    /// Decrypts a file in place using the provided secret.
-    ///
-    fn decrypt(path: &Path, secret: &Secret) -> Result<(), Error> {
-        //
-        //  Open file at `path` for reading and writing
-        //
-        let mut file = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(path)
-            .map_err(|err| Error::new(ErrorKind::Other, format!("Failed to open file: {}", err)))?;
+        let encrypted = fs::read(stash_path.join("dumps/test.sql")).unwrap();
+        assert_ne!(test_str.as_bytes(), encrypted);
 
-        let (key, nonce) = secret.split();
-        let mut buffer = Vec::new();
+        let entry = stash.get_entry("dumps/test.sql").unwrap().unwrap();
+        assert_eq!(entry.size, test_str.len() as u64);
+        assert_eq!(entry.hash, format!("{:x}", Sha256::digest(test_str)));
 
-        //  Read contents to buffer
-        //
-        file.read_to_end(&mut buffer).map_err(|err| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to read file: {}", err),
-            )
-        })?;
+        stash
+            .grab("dumps/test.sql", false, Conflict::Error)
+            .unwrap();
+        let decrypted = fs::read_to_string(dir_path.join("dumps/test.sql")).unwrap();
+        assert_eq!(test_str, decrypted);
+    }
 
-        //  Create ciphertext using key, decrypt to buffer using nonce
-        //
-        let cipher = Aes256Gcm::new(GenericArray::from_slice(&key));
-        let result = cipher.decrypt_in_place(GenericArray::from_slice(&nonce), b"", &mut buffer);
+    #[test]
+    #[serial]
+    //
+    fn test_valid_edit() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        env::set_current_dir(dir_path).unwrap();
 
-        if let Err(err) = result {
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("Failed to decrypt file: {}", err),
-            ));
-        }
+        let mut stash = Stash::test(dir_path);
+        let mut out = Vec::new();
 
-        //  Go back to beginning of file
+        //  New files start empty
         //
-        file.seek(io::SeekFrom::Start(0)).map_err(|err| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to seek file: {}", err),
-            )
-        })?;
+        assert!(stash.edit("test.env", "echo 'KEY=one' >", true).unwrap());
+        assert!(stash.edit("test.env", "true", true).is_err());
 
-        //  Write encrypted contents to file
+        //  Unchanged files keep their revision
         //
-        file.write_all(&buffer).map_err(|err| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to write file: {}", err),
-            )
-        })?;
+        assert!(!stash.edit("test.env", "true", false).unwrap());
+        assert_eq!(stash.log("test.env").unwrap().len(), 1);
 
-        //  Trim file to new length
-        //
-        file.set_len(buffer.len() as u64).map_err(|err| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to set file length: {}", err),
-            )
-        })?;
+        assert!(stash.edit("test.env", "sed -i s/one/two/", false).unwrap());
+        stash.cat("test.env", &mut out).unwrap();
+        assert_eq!(out, b"KEY=two\n");
+        assert_eq!(stash.log("test.env").unwrap().len(), 2);
 
-        Ok(())
+        //  Failed editors change nothing
+        //
+        assert!(stash.edit("test.env", "false", false).is_err());
+        assert!(stash.edit("missing.env", "true", false).is_err());
+        assert_eq!(stash.log("test.env").unwrap().len(), 2);
     }
 
-    /// Creates `.tar.gz` archive of stash contents and removes files.
-    ///
-    fn create_tarball(&self) -> Result<(), Error> {
+    #[test]
+    #[serial]
+    //
+    fn test_valid_edit_cleanup() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let runtime = dir_path.join("runtime");
+        fs::create_dir(&runtime).unwrap();
+        env::set_current_dir(dir_path).unwrap();
+        let previous = env::var_os("XDG_RUNTIME_DIR");
+        env::set_var("XDG_RUNTIME_DIR", &runtime);
+
+        let mut stash = Stash::test(dir_path);
+
+        //  Plaintext left by a killed edit is wiped by the next one, while
+        //  live edits and workspaces are left alone
         //
-        //  Change to stash directory and unpack
+        let mut child = Command::new("true").spawn().unwrap();
+        let dead = child.id();
+        child.wait().unwrap();
+        let stale = runtime.join(format!("stash-edit.{}.0", dead));
+        let live = runtime.join(format!("stash-edit.{}.0", std::process::id()));
+        let workspace = runtime.join(format!("stash.{}.0", dead));
+        for dir in [&stale, &live, &workspace] {
+            fs::create_dir(dir).unwrap();
+            fs::write(dir.join("test.env"), "KEY=secret").unwrap();
+        }
+        assert!(stash.edit("test.env", "echo 'KEY=one' >", true).unwrap());
+        assert!(!stale.exists());
+        assert!(live.join("test.env").exists());
+        assert!(workspace.join("test.env").exists());
+
+        //  Signals sent while the editor runs don't stop the edit
         //
-        let tar = Command::new("sh")
-            .arg("-c")
-            .arg(format!("$(cd {} && tar czf contents --remove-files ./*)", self.path.to_str().unwrap()))
-            .output()
-            .map_err(|err| {
-                Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to execute `tar` command: {}", err),
-                )
-            })?;
+        let editor = "kill -INT $PPID; kill -TERM $PPID; kill -HUP $PPID; echo 'KEY=two' >";
+        assert!(stash.edit("test.env", editor, false).unwrap());
+        let mut out = Vec::new();
+        stash.cat("test.env", &mut out).unwrap();
+        assert_eq!(out, b"KEY=two\n");
 
-        //  Throw error on failure
+        //  Nothing is left on tmpfs, and signals are handled as before
         //
-        if !tar.status.success() {
-            let err_msg = String::from_utf8_lossy(&tar.stderr);
-            eprintln!("{}", err_msg);
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("Failed to create tar archive: {}", err_msg),
-            ));
+        let left: Vec<_> = fs::read_dir(&runtime)
+            .unwrap()
+            .map(|item| item.unwrap().path())
+            .collect();
+        assert_eq!(left.len(), 2);
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            // SAFETY: only reads the current action into `action`.
+            let action = unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                libc::sigaction(signal, std::ptr::null(), &mut action);
+                action
+            };
+            assert_eq!(action.sa_sigaction, libc::SIG_DFL);
         }
 
-        Ok(())
+        match previous {
+            Some(dir) => env::set_var("XDG_RUNTIME_DIR", dir),
+            None => env::remove_var("XDG_RUNTIME_DIR"),
+        }
     }
 
-    /// Extracts a `.tar.gz` archive of the stash contents.
-    ///
-    fn extract_tarball(&self) -> Result<(), io::Error> {
+    #[test]
+    #[serial]
+    //
+    fn test_valid_exec() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let file_path = dir_path.join("test.yaml");
+        env::set_current_dir(dir_path).unwrap();
+
+        let mut stash = Stash::test(dir_path);
+        let test_str = "Testing: one, two...";
+        fs::write(&file_path, test_str).unwrap();
+        stash.add("test.yaml", false, Conflict::Error).unwrap();
+
+        //  Child can read the file, but not change it
         //
-        //  Change to stash directory and extract tarball
+        let files = vec![("TEST_FILE".to_string(), "test.yaml".to_string())];
+        let check = format!(
+            "test \"$(cat \"$TEST_FILE\")\" = '{}' && ! echo x 2>/dev/null >> \"$TEST_FILE\"",
+            test_str
+        );
+        let cmd = vec!["sh".to_string(), "-c".to_string(), check];
+        assert!(stash.exec(&files, &cmd).unwrap().success());
+
+        //  Nothing lands in the current directory
         //
-        let tar = Command::new("sh")
-            .arg("-c")
-            .arg(format!("$(cd {} && tar xzf contents && rm contents)", self.path.to_str().unwrap()))
-            .output()
-            .map_err(|err| {
-                Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to execute `tar` command: {}", err),
-                )
-            })?;
+        assert!(!file_path.exists());
 
-        //  Throw error on failure
-        //
-        if !tar.status.success() {
-            let err_msg = String::from_utf8_lossy(&tar.stderr);
-            eprintln!("{}", err_msg);
-            return Err(Error::new(
-                ErrorKind::Other,
-                format!("Failed to unpack tar archive: {}", err_msg),
-            ));
-        }
+        let files = vec![("TEST_FILE".to_string(), "missing.yaml".to_string())];
+        assert!(stash.exec(&files, &cmd).is_err());
 
-        Ok(())
+        //  However long the file's name
+        //
+        let name = format!("{}/{}.yaml", "d".repeat(200), "f".repeat(200));
+        stash
+            .add_reader(&mut test_str.as_bytes(), &name, Conflict::Error)
+            .unwrap();
+        let files = vec![("TEST_FILE".to_string(), name)];
+        assert!(stash.exec(&files, &cmd).unwrap().success());
     }
-}
-
-//  Unit tests
-//
-#[cfg(test)]
-//
-mod tests {
-    use crate::*;
-    use serial_test::serial;
-    use std::fs::File;
-    use tempfile::TempDir;
 
-    #[allow(dead_code)]
+    #[test]
+    #[serial]
     //
-    fn setup() {
-        // todo
+    fn test_valid_env() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir_path = temp_dir.path();
+        let env_path = dir_path.join(".env");
+        env::set_current_dir(dir_path).unwrap();
+
+        let mut stash = Stash::test(dir_path);
+        fs::write(
+            &env_path,
+            "# Test\nexport TEST_ONE=one\nTEST_TWO=\"two words\"\n\nTEST_THREE='three'\n",
+        )
+        .unwrap();
+
+        assert_eq!(stash.env_import("test", &env_path).unwrap(), 3);
+        stash
+            .env_set("test", &[("TEST_ONE".to_string(), "uno".to_string())])
+            .unwrap();
+        assert!(stash
+            .env_set("test", &[("1BAD".to_string(), "x".to_string())])
+            .is_err());
+
+        //  Listing shows names, never values
+        //
+        assert_eq!(stash.env_list(None).unwrap(), vec!["test"]);
+        assert_eq!(
+            stash.env_list(Some("test")).unwrap(),
+            vec!["TEST_ONE", "TEST_THREE", "TEST_TWO"]
+        );
+        let raw = stash.envs.get("test").unwrap().unwrap();
+        assert!(!raw.windows(3).any(|window| window == b"uno"));
+
+        let check = "test \"$TEST_ONE $TEST_TWO $TEST_THREE\" = 'uno two words three'";
+        let cmd = vec!["sh".to_string(), "-c".to_string(), check.to_string()];
+        assert!(stash.run("test", &cmd).unwrap().success());
+
+        stash.env_unset("test", &["TEST_ONE".to_string()]).unwrap();
+        assert_eq!(stash.env_list(Some("test")).unwrap().len(), 2);
+        stash.env_unset("test", &[]).unwrap();
+        assert!(stash.env_list(None).unwrap().is_empty());
+        assert!(stash.run("test", &cmd).is_err());
     }
 
     #[test]
     #[serial]
+    #[ignore = "needs FUSE and permission to mount"]
     //
-    fn test_valid_new() {
+    fn test_valid_mount() {
         let temp_dir = TempDir::new().unwrap();
-        let path = temp_dir.path();
-        let stash_path = path.join("test_stash");
-        let db_path = stash_path.join(".db");
+        let dir_path = temp_dir.path();
+        let mount_path = dir_path.join("mnt");
+        fs::create_dir(&mount_path).unwrap();
+        env::set_current_dir(dir_path).unwrap();
 
-        let _ = Stash::test(path);
+        let mut stash = Stash::test(dir_path);
+        let test_str = "Testing: one, two...";
+        stash
+            .add_reader(&mut test_str.as_bytes(), "dir/test.txt", Conflict::Error)
+            .unwrap();
+        let big: Vec<u8> = (0..1_000_000u32).map(|n| (n * 7 % 251) as u8).collect();
+        stash.set_chunking(true);
+        stash
+            .add_reader(&mut &big[..], "big.bin", Conflict::Error)
+            .unwrap();
+
+        let mount = mount_path.clone();
+        let server = thread::spawn(move || stash.mount(&mount));
+
+        while !fuse::mounts().contains(&mount_path) {
+            if server.is_finished() {
+                panic!("Failed to mount: {:?}", server.join().unwrap());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
 
-        assert!(stash_path.exists() && stash_path.is_dir());
-        assert!(db_path.exists() && db_path.is_dir());
+        let file_path = mount_path.join("dir/test.txt");
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), test_str);
+        assert_eq!(
+            fs::metadata(&file_path).unwrap().len(),
+            test_str.len() as u64
+        );
+        assert_eq!(fs::read(mount_path.join("big.bin")).unwrap(), big);
+        assert!(fs::write(&file_path, "Changed").is_err());
+        assert!(fs::write(mount_path.join("new.txt"), "New").is_err());
+        let names: Vec<_> = fs::read_dir(mount_path.join("dir"))
+            .unwrap()
+            .map(|item| item.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec!["test.txt"]);
+
+        assert_eq!(
+            Stash::umount(Some(&mount_path)).unwrap(),
+            vec![mount_path.clone()]
+        );
+        server.join().unwrap().unwrap();
+        assert!(!fuse::mounts().contains(&mount_path));
     }
 
     #[test]
     #[serial]
     //
-    fn test_valid_add() {
+    fn test_valid_open_close() {
         let temp_dir = TempDir::new().unwrap();
         let dir_path = temp_dir.path();
-        let stash_path = dir_path.join("test_stash");
-        let file_path = dir_path.join("test.txt");
-        let file_os_str = file_path.file_name().unwrap();
-        let file_str = file_os_str.to_str().unwrap();
-        env::set_current_dir(&dir_path).unwrap();
+        env::set_current_dir(dir_path).unwrap();
 
         let mut stash = Stash::test(dir_path);
+        for name in ["keep.txt", "edit.txt", "gone.txt", "other.pem"] {
+            stash
+                .add_reader(&mut name.as_bytes(), name, Conflict::Error)
+                .unwrap();
+        }
 
-        let mut file = File::create(&file_path).unwrap();
-        let stashed_file = stash_path.join("test.txt");
-        let test_str = "Testing: one, two...";
-        writeln!(file, "{}", test_str).unwrap();
-
-        stash.add(&file_str, false).unwrap();
-
-        assert!(stashed_file.exists() && !file_path.exists());
-        let encrypted = fs::read(&stashed_file).unwrap();
-        assert_ne!(test_str.as_bytes(), encrypted);
+        //  Only the selected files are decrypted
+        //
+        let workspace = stash.open(&["*.txt".to_string()]).unwrap();
+        assert_eq!(
+            fs::read_to_string(workspace.join("keep.txt")).unwrap(),
+            "keep.txt"
+        );
+        assert!(!workspace.join("other.pem").exists());
+        assert_eq!(
+            fs::metadata(&workspace).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+
+        fs::write(workspace.join("edit.txt"), "Changed").unwrap();
+        fs::remove_file(workspace.join("gone.txt")).unwrap();
+        fs::create_dir(workspace.join("new")).unwrap();
+        fs::write(workspace.join("new/file.txt"), "New").unwrap();
+
+        let status = stash.status().unwrap();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].path, workspace);
+        let changes = vec![
+            Change::Modified("edit.txt".to_string()),
+            Change::Added("new/file.txt".to_string()),
+            Change::Deleted("gone.txt".to_string()),
+        ];
+        assert_eq!(status[0].changes, changes);
+
+        assert_eq!(stash.close(None, false).unwrap(), changes);
+        assert!(!workspace.exists());
+        assert!(stash.status().unwrap().is_empty());
+
+        let mut out = Vec::new();
+        stash.cat("edit.txt", &mut out).unwrap();
+        assert_eq!(out, b"Changed");
+        assert_eq!(stash.log("edit.txt").unwrap().len(), 2);
+        assert_eq!(stash.log("keep.txt").unwrap().len(), 1);
+        assert!(stash.get_entry("new/file.txt").unwrap().is_some());
+        assert!(stash.get_entry("gone.txt").unwrap().is_none());
+
+        //  Discarding stashes nothing
+        //
+        let workspace = stash.open(&[]).unwrap();
+        fs::write(workspace.join("keep.txt"), "Changed").unwrap();
+        assert!(stash.close(Some(&workspace), true).unwrap().is_empty());
+        assert!(!workspace.exists());
+        assert_eq!(stash.log("keep.txt").unwrap().len(), 1);
     }
 
     #[test]
     #[serial]
     //
-    fn test_valid_add_copy() {
+    fn test_valid_unpack_files() {
         let temp_dir = TempDir::new().unwrap();
         let dir_path = temp_dir.path();
-        let stash_path = dir_path.join("test_stash");
-        let file_path = dir_path.join("test.txt");
-        let file_os_str = file_path.file_name().unwrap();
-        let file_str = file_os_str.to_str().unwrap();
-        env::set_current_dir(&dir_path).unwrap();
+        env::set_current_dir(dir_path).unwrap();
 
+        let stash_path = dir_path.join("test_stash");
         let mut stash = Stash::test(dir_path);
-
-        let mut file = File::create(&file_path).unwrap();
-        let stashed_file = stash_path.join("test.txt");
         let test_str = "Testing: one, two...";
-        writeln!(file, "{}", test_str).unwrap();
 
-        stash.add(&file_str, true).unwrap();
-
-        assert!(stashed_file.exists() && file_path.exists());
-        let encrypted = fs::read(&stashed_file).unwrap();
-        assert_ne!(test_str.as_bytes(), encrypted);
+        for name in ["docs/a.txt", "docs/b.txt", "test3"] {
+            stash
+                .add_reader(&mut test_str.as_bytes(), name, Conflict::Error)
+                .unwrap();
+        }
+        stash
+            .add_reader(&mut &b"Testing: three"[..], "docs/b.txt", Conflict::Version)
+            .unwrap();
+        let big: Vec<u8> = (0..200_000u32).map(|n| (n % 251) as u8).collect();
+        stash
+            .add_reader(&mut &big[..], "zbig", Conflict::Error)
+            .unwrap();
+        stash
+            .archive("test", &[], &ArchiveOptions::default())
+            .unwrap();
+
+        //  The manifest describes each file without touching the tarball
+        //
+        let data_path = stash_path.join(".archives/test/test.001");
+        let data = fs::read(&data_path).unwrap();
+        fs::write(&data_path, b"").unwrap();
+        let members = stash.manifest("test").unwrap();
+        let names: Vec<&str> = members.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, vec!["docs/a.txt", "docs/b.txt", "test3", "zbig"]);
+        assert_eq!(members[0].size, test_str.len() as u64);
+        assert_eq!(members[0].offset, 0);
+        assert!(members[1].offset >= members[0].offset + members[0].length);
+        assert_eq!(members[2].hash, members[0].hash);
+        assert!(stash.manifest("missing").is_err());
+        fs::write(&data_path, &data).unwrap();
+
+        //  Selected files come out with their history, leaving the archive
+        //
+        let files = ["docs/b*".to_string()];
+        assert_eq!(
+            stash.unpack(Some("test"), &files).unwrap(),
+            vec!["docs/b.txt"]
+        );
+        assert_eq!(stash.log("docs/b.txt").unwrap().len(), 2);
+        let mut out = Vec::new();
+        stash.cat("docs/b.txt", &mut out).unwrap();
+        assert_eq!(out, b"Testing: three");
+        assert_eq!(stash.names().unwrap(), vec!["docs/b.txt"]);
+        assert_eq!(fs::read(&data_path).unwrap(), data);
+        assert!(stash
+            .unpack(Some("test"), &["nothing".to_string()])
+            .is_err());
+        assert!(stash.unpack(Some("test"), &files).is_err());
+
+        //  Tampering with the tarball is caught, and leaves nothing behind
+        //
+        let mut tampered = data.clone();
+        tampered[100] ^= 1;
+        fs::write(&data_path, &tampered).unwrap();
+        assert!(stash.unpack(Some("test"), &["test3".to_string()]).is_err());
+        assert!(!stash_path.join("test3").exists());
+        fs::write(&data_path, &data).unwrap();
+
+        //  Files spanning several chunks decrypt as before
+        //
+        let files = ["zbig".to_string()];
+        assert_eq!(stash.unpack(Some("test"), &files).unwrap(), files);
+        let mut out = Vec::new();
+        stash.cat("zbig", &mut out).unwrap();
+        assert_eq!(out, big);
+        assert_eq!(stash.archives().unwrap().len(), 1);
     }
 
     #[test]
     #[serial]
     //
-    fn test_valid_grab() {
+    fn test_valid_archive_compression() {
         let temp_dir = TempDir::new().unwrap();
         let dir_path = temp_dir.path();
-        let stash_path = dir_path.join("test_stash");
-        let file_path = dir_path.join("test.txt");
-        let file_os_str = file_path.file_name().unwrap();
-        let file_str = file_os_str.to_str().unwrap();
-        env::set_current_dir(&dir_path).unwrap();
+        env::set_current_dir(dir_path).unwrap();
 
+        let stash_path = dir_path.join("test_stash");
         let mut stash = Stash::test(dir_path);
+        let big: Vec<u8> = (0..200_000u32).map(|n| (n % 251) as u8).collect();
+
+        for (id, compression) in ["none", "gzip", "zstd", "xz"].iter().enumerate() {
+            stash
+                .add_reader(&mut &big[..], "big", Conflict::Error)
+                .unwrap();
+            stash
+                .add_reader(&mut &b"Testing: one, two..."[..], "small", Conflict::Error)
+                .unwrap();
+            let options = ArchiveOptions {
+                compression: compression.parse().unwrap(),
+                level: (id > 0).then_some(1),
+                ..ArchiveOptions::default()
+            };
+            stash.archive(compression, &[], &options).unwrap();
+
+            //  The header records the compression for unpacking to find
+            //
+            let data = fs::read(
+                stash_path
+                    .join(".archives")
+                    .join(compression)
+                    .join(format!("{}.001", compression)),
+            );
+            assert_eq!(&data.unwrap()[8..11], &[1, id as u8, (id > 0) as u8]);
+
+            let files = ["small".to_string()];
+            assert_eq!(stash.unpack(Some(compression), &files).unwrap(), files);
+            stash.delete("small").unwrap();
+            assert_eq!(
+                stash.unpack(Some(compression), &[]).unwrap(),
+                vec!["big", "small"]
+            );
+            let mut out = Vec::new();
+            stash.cat("big", &mut out).unwrap();
+            assert_eq!(out, big);
+            stash.delete("big").unwrap();
+            stash.delete("small").unwrap();
+        }
 
-        let mut file = File::create(&file_path).unwrap();
-        let stashed_file = stash_path.join("test.txt");
-        let test_str = "Testing: one, two...";
-        writeln!(file, "{}", test_str).unwrap();
-
-        stash.add(&file_str, false).unwrap();
-        assert!(stashed_file.exists() && !file_path.exists());
-
-        let encrypted = fs::read(&stashed_file).unwrap();
-        assert_ne!(test_str.as_bytes(), encrypted);
-
-        stash.grab(&file_str, false).unwrap();
-        assert!(!stashed_file.exists() && file_path.exists());
-        let decrypted = fs::read_to_string(&file_path).unwrap();
-        assert_eq!(test_str, decrypted.trim());
+        //  Levels are checked against the chosen compression
+        //
+        stash
+            .add_reader(&mut &big[..], "big", Conflict::Error)
+            .unwrap();
+        let options = ArchiveOptions {
+            compression: Compression::Gzip,
+            level: Some(19),
+            ..ArchiveOptions::default()
+        };
+        assert!(stash.archive("test", &[], &options).is_err());
+        assert!("lz4".parse::<Compression>().is_err());
     }
 
     #[test]
     #[serial]
     //
-    fn test_valid_grab_copy() {
+    fn test_valid_archive_volumes() {
         let temp_dir = TempDir::new().unwrap();
         let dir_path = temp_dir.path();
-        let stash_path = dir_path.join("test_stash");
-        let file_path = dir_path.join("test.txt");
-        let file_os_str = file_path.file_name().unwrap();
-        let file_str = file_os_str.to_str().unwrap();
-        env::set_current_dir(&dir_path).unwrap();
+        env::set_current_dir(dir_path).unwrap();
 
+        let archive_dir = dir_path.join("test_stash/.archives/test");
         let mut stash = Stash::test(dir_path);
+        let big: Vec<u8> = (0..200_000u32).map(|n| (n % 251) as u8).collect();
+        stash
+            .add_reader(&mut &big[..], "big", Conflict::Error)
+            .unwrap();
+        stash
+            .add_reader(&mut &b"Testing: one, two..."[..], "small", Conflict::Error)
+            .unwrap();
+
+        //  One chunk fits in each volume
+        //
+        let mut options = ArchiveOptions {
+            compression: Compression::None,
+            volume_size: Some(1024),
+            ..ArchiveOptions::default()
+        };
+        assert!(stash.archive("test", &[], &options).is_err());
+        options.volume_size = Some(70_000);
+        stash.archive("test", &[], &options).unwrap();
+
+        let volume = |n: u32| archive_dir.join(format!("test.{:03}", n));
+        assert!(volume(4).exists() && !volume(5).exists());
+        for n in 1..=4 {
+            assert!(fs::metadata(volume(n)).unwrap().len() <= 70_000);
+        }
 
-        let mut file = File::create(&file_path).unwrap();
-        let stashed_file = stash_path.join("test.txt");
-        let test_str = "Testing: one, two...";
-        writeln!(file, "{}", test_str).unwrap();
-
-        stash.add(&file_str, false).unwrap();
-        assert!(stashed_file.exists() && !file_path.exists());
-
-        let encrypted = fs::read(&stashed_file).unwrap();
-        assert_ne!(test_str.as_bytes(), encrypted);
-
-        stash.grab(&file_str, true).unwrap();
-        assert!(stashed_file.exists() && file_path.exists());
-        let decrypted = fs::read_to_string(&file_path).unwrap();
-        assert_eq!(test_str, decrypted.trim());
+        //  Missing, reordered and truncated volumes are named
+        //
+        let unpack_err = |stash: &mut Stash| stash.unpack(None, &[]).unwrap_err().to_string();
+        fs::rename(volume(2), dir_path.join("aside")).unwrap();
+        assert!(unpack_err(&mut stash).contains("test.002 is missing"));
+        fs::rename(volume(3), volume(2)).unwrap();
+        fs::rename(dir_path.join("aside"), volume(3)).unwrap();
+        assert!(unpack_err(&mut stash).contains("test.002 is out of order"));
+        fs::rename(volume(3), dir_path.join("aside")).unwrap();
+        fs::rename(volume(2), volume(3)).unwrap();
+        fs::rename(dir_path.join("aside"), volume(2)).unwrap();
+        let third = fs::read(volume(3)).unwrap();
+        fs::write(volume(3), &third[..1000]).unwrap();
+        assert!(unpack_err(&mut stash).contains("test.003 is truncated"));
+        assert!(stash.names().unwrap().is_empty());
+        fs::write(volume(3), &third).unwrap();
+
+        //  Single files are found in their volume, and the rest follow
+        //
+        let files = ["small".to_string()];
+        assert_eq!(stash.unpack(None, &files).unwrap(), files);
+        stash.delete("small").unwrap();
+        assert_eq!(stash.unpack(None, &[]).unwrap(), vec!["big", "small"]);
+        let mut out = Vec::new();
+        stash.cat("big", &mut out).unwrap();
+        assert_eq!(out, big);
+        assert!(!archive_dir.exists());
     }
 
     #[test]
     #[serial]
     //
-    fn test_valid_delete() {
+    fn test_valid_archive_incremental() {
         let temp_dir = TempDir::new().unwrap();
         let dir_path = temp_dir.path();
-        let stash_path = dir_path.join("test_stash");
-        let file_path = dir_path.join("test.txt");
-        let file_os_str = file_path.file_name().unwrap();
-        let file_str = file_os_str.to_str().unwrap();
-        env::set_current_dir(&dir_path).unwrap();
+        env::set_current_dir(dir_path).unwrap();
 
         let mut stash = Stash::test(dir_path);
+        for name in ["a", "b", "c"] {
+            stash
+                .add_reader(&mut name.as_bytes(), name, Conflict::Error)
+                .unwrap();
+        }
+        let options = ArchiveOptions {
+            incremental: true,
+            ..ArchiveOptions::default()
+        };
 
-        let mut file = File::create(&file_path).unwrap();
-        let stashed_file = stash_path.join("test.txt");
-        let test_str = "Testing: one, two...";
-        writeln!(file, "{}", test_str).unwrap();
+        //  The base takes everything, and leaves the stash as it was
+        //
+        assert_eq!(
+            stash.archive("base", &[], &options).unwrap(),
+            vec!["a", "b", "c"]
+        );
+        assert_eq!(stash.names().unwrap(), vec!["a", "b", "c"]);
+        assert!(stash.archive("same", &[], &options).is_err());
+
+        //  The hashes increments are checked against stay encrypted
+        //
+        let record = stash.archives.get("base").unwrap().unwrap();
+        let record = String::from_utf8_lossy(&record);
+        assert!(!["\"a\"", "\"b\"", "\"c\""]
+            .iter()
+            .any(|name| record.contains(name)));
 
-        stash.add(&file_str, false).unwrap();
-        assert!(stashed_file.exists() && !file_path.exists());
-        
-        stash.delete(&file_str).unwrap();
-        assert!(!stashed_file.exists());
+        //  Increments take only changes, and mark deletions
+        //
+        stash
+            .add_reader(&mut &b"b, again"[..], "b", Conflict::Version)
+            .unwrap();
+        stash.delete("c").unwrap();
+        stash
+            .add_reader(&mut &b"d"[..], "d", Conflict::Error)
+            .unwrap();
+        assert_eq!(
+            stash.archive("next", &[], &options).unwrap(),
+            vec!["b", "d"]
+        );
+        let archives = stash.archives().unwrap();
+        assert_eq!(archives[1].name, "next");
+        assert_eq!(archives[1].parent.as_deref(), Some("base"));
+        assert!(stash.unpack(Some("next"), &[]).is_err());
+
+        //  The chain rebuilds the latest state, and stays put
+        //
+        for name in ["a", "b", "d"] {
+            stash.delete(name).unwrap();
+        }
+        assert_eq!(stash.unpack_chain(None).unwrap(), vec!["a", "b", "d"]);
+        let mut out = Vec::new();
+        stash.cat("b", &mut out).unwrap();
+        assert_eq!(out, b"b, again");
+        assert_eq!(stash.log("b").unwrap().len(), 2);
+        assert_eq!(stash.archives().unwrap().len(), 2);
+        assert!(stash.unpack_chain(Some("next")).is_err());
+
+        //  Or any earlier state along it
+        //
+        for name in ["a", "b", "d"] {
+            stash.delete(name).unwrap();
+        }
+        assert_eq!(
+            stash.unpack_chain(Some("base")).unwrap(),
+            vec!["a", "b", "c"]
+        );
     }
 
     #[test]
     #[serial]
     //
-    fn test_valid_list() {
+    fn test_valid_bundle() {
         let temp_dir = TempDir::new().unwrap();
         let dir_path = temp_dir.path();
-        env::set_current_dir(&dir_path).unwrap();
-
-        let stash_path = dir_path.join("test_stash");
-        let file_path1 = dir_path.join("test1");
-        let file_path2 = dir_path.join("test2");
-        let file_path3 = dir_path.join("test3");
+        env::set_current_dir(dir_path).unwrap();
 
         let mut stash = Stash::test(dir_path);
-
-        let _ = File::create(&file_path1).unwrap();
-        let _ = File::create(&file_path2).unwrap();
-        let _ = File::create(&file_path3).unwrap();
-        stash.add("test1", false).unwrap();
-        stash.add("test2", false).unwrap();
-        stash.add("test3", false).unwrap();
-
-        let stashed_file1 = stash_path.join("test1");
-        let stashed_file2 = stash_path.join("test2");
-        let stashed_file3 = stash_path.join("test3");
-        assert!(stashed_file1.exists() && !file_path1.exists());
-        assert!(stashed_file2.exists() && !file_path2.exists());
-        assert!(stashed_file3.exists() && !file_path3.exists());
-
-        let actual = stash.list().unwrap();
-        let mock = format!("test1\ntest2\ntest3");
-        assert_eq!(actual, mock);
+        stash
+            .add_reader(&mut &b"a"[..], "a", Conflict::Error)
+            .unwrap();
+        stash
+            .add_reader(&mut &b"a, again"[..], "a", Conflict::Version)
+            .unwrap();
+        stash
+            .add_reader(&mut &b"b"[..], "dir/b", Conflict::Error)
+            .unwrap();
+        stash
+            .env_set("web", &[("KEY".to_string(), "value".to_string())])
+            .unwrap();
+        stash.tag("dir/b", &["work".to_string()]).unwrap();
+
+        //  Exporting leaves the stash alone, and never overwrites
+        //
+        let path = dir_path.join("stash.bundle");
+        let contents = stash.export_bundle(&path, "secret").unwrap();
+        assert_eq!(contents.files, vec!["a", "dir/b"]);
+        assert_eq!(contents.envs, vec!["web"]);
+        assert_eq!(stash.names().unwrap(), vec!["a", "dir/b"]);
+        assert!(stash.export_bundle(&path, "secret").is_err());
+
+        //  A wrong passphrase or a clash is refused before anything changes
+        //
+        let err = stash
+            .import_bundle(&path, "wrong", Conflict::Rename)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(stash
+            .import_bundle(&path, "secret", Conflict::Error)
+            .is_err());
+        assert_eq!(stash.names().unwrap(), vec!["a", "dir/b"]);
+
+        //  Importing elsewhere brings back contents, history and environments
+        //
+        let other_dir = TempDir::new().unwrap();
+        let mut other = Stash::test(other_dir.path());
+        let contents = other
+            .import_bundle(&path, "secret", Conflict::Error)
+            .unwrap();
+        assert_eq!(contents.files, vec!["a", "dir/b"]);
+        let mut out = Vec::new();
+        other.cat("a", &mut out).unwrap();
+        assert_eq!(out, b"a, again");
+        assert_eq!(other.log("a").unwrap().len(), 2);
+        assert_eq!(other.env_list(Some("web")).unwrap(), vec!["KEY"]);
+        assert_eq!(other.tagged("work").unwrap(), vec!["dir/b"]);
+
+        //  Renaming keeps both copies
+        //
+        let contents = other
+            .import_bundle(&path, "secret", Conflict::Rename)
+            .unwrap();
+        assert_eq!(contents.files.len(), 2);
+        assert_eq!(other.names().unwrap().len(), 4);
+        assert_eq!(other.env_list(None).unwrap().len(), 2);
+
+        //  A damaged bundle is rejected whole
+        //
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[200] ^= 1;
+        fs::write(&path, bytes).unwrap();
+        assert!(other
+            .import_bundle(&path, "secret", Conflict::Overwrite)
+            .is_err());
+        assert_eq!(other.names().unwrap().len(), 4);
     }
 
     #[test]
     #[serial]
     //
-    fn test_valid_archive() {
+    fn test_valid_backup() {
         let temp_dir = TempDir::new().unwrap();
         let dir_path = temp_dir.path();
-        env::set_current_dir(&dir_path).unwrap();
-
-        let stash_path = dir_path.join("test_stash");
-        let file_path1 = dir_path.join("test1");
-        let file_path2 = dir_path.join("test2");
-        let file_path3 = dir_path.join("test3");
+        env::set_current_dir(dir_path).unwrap();
 
         let mut stash = Stash::test(dir_path);
-        let test_str = "Testing: one, two...";
-
-        let mut file1 = File::create(&file_path1).unwrap();
-        let mut file2 = File::create(&file_path2).unwrap();
-        let mut file3 = File::create(&file_path3).unwrap();
-        writeln!(file1, "{}", test_str).unwrap();
-        writeln!(file2, "{}", test_str).unwrap();
-        writeln!(file3, "{}", test_str).unwrap();
-        stash.add("test1", false).unwrap();
-        stash.add("test2", false).unwrap();
-        stash.add("test3", false).unwrap();
-
-        let stashed_file1 = stash_path.join("test1");
-        let stashed_file2 = stash_path.join("test2");
-        let stashed_file3 = stash_path.join("test3");
-        assert!(stashed_file1.exists() && !file_path1.exists());
-        assert!(stashed_file2.exists() && !file_path2.exists());
-        assert!(stashed_file3.exists() && !file_path3.exists());
-
-        stash.archive().unwrap();
-        assert!(stash.contents.exists());
-        assert_eq!(stash.list().unwrap(), "contents");
+        stash
+            .add_reader(&mut &b"a"[..], "a", Conflict::Error)
+            .unwrap();
+        stash
+            .add_reader(&mut &b"a, again"[..], "a", Conflict::Version)
+            .unwrap();
+        stash
+            .add_reader(&mut &b"b"[..], "dir/b", Conflict::Error)
+            .unwrap();
+        stash
+            .add_reader(&mut &b"c"[..], "c", Conflict::Error)
+            .unwrap();
+        stash
+            .archive("old", &["c".to_string()], &ArchiveOptions::default())
+            .unwrap();
+        stash
+            .env_set("web", &[("KEY".to_string(), "value".to_string())])
+            .unwrap();
+        stash.tag("a", &["work".to_string()]).unwrap();
+
+        //  Only new ciphertext is copied
+        //
+        let target = dir_path.join("backup");
+        let report = stash
+            .backup(&target, "secret", Retention::default())
+            .unwrap();
+        assert_eq!(report.files, 5);
+        assert_eq!(report.copied, 5);
+        let report = stash
+            .backup(&target, "secret", Retention::default())
+            .unwrap();
+        assert_eq!(report.copied, 0);
+        assert!(report.removed.is_empty());
+        assert!(stash
+            .backup(&target, "wrong", Retention::default())
+            .is_err());
+        assert_eq!(stash.backups(&target, "secret").unwrap().len(), 2);
+
+        //  Restoring needs an empty stash
+        //
+        assert!(stash.restore_backup(&target, "secret", None).is_err());
+        let other_dir = TempDir::new().unwrap();
+        let mut other = Stash::test(other_dir.path());
+        assert_eq!(
+            other.restore_backup(&target, "secret", None).unwrap(),
+            vec!["a", "dir/b"]
+        );
+        let mut out = Vec::new();
+        other.cat("a", &mut out).unwrap();
+        assert_eq!(out, b"a, again");
+        assert_eq!(other.log("a").unwrap().len(), 2);
+        assert_eq!(other.tags("a").unwrap(), ["work"]);
+        assert_eq!(other.env_list(Some("web")).unwrap(), vec!["KEY"]);
+        assert_eq!(other.unpack(Some("old"), &[]).unwrap(), vec!["c"]);
+
+        //  Retention thins out older backups, and the blobs only they need
+        //
+        stash.delete("dir/b").unwrap();
+        let retention = Retention {
+            daily: Some(1),
+            weekly: None,
+        };
+        let report = stash.backup(&target, "secret", retention).unwrap();
+        assert_eq!(report.removed.len(), 2);
+        let backups = stash.backups(&target, "secret").unwrap();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(backups[0].id, report.snapshot);
+        let repo = Repo::open(&target, "secret").unwrap();
+        assert_eq!(repo.blobs().unwrap().len(), 4);
     }
 
     #[test]
     #[serial]
     //
-    fn test_valid_unpack() {
+    fn test_valid_chunking() {
         let temp_dir = TempDir::new().unwrap();
         let dir_path = temp_dir.path();
-        env::set_current_dir(&dir_path).unwrap();
-
-        let stash_path = dir_path.join("test_stash");
-        let file_path1 = dir_path.join("test1");
-        let file_path2 = dir_path.join("test2");
-        let file_path3 = dir_path.join("test3");
+        env::set_current_dir(dir_path).unwrap();
 
         let mut stash = Stash::test(dir_path);
-        let test_str = "Testing: one, two...";
-
-        let mut file1 = File::create(&file_path1).unwrap();
-        let mut file2 = File::create(&file_path2).unwrap();
-        let mut file3 = File::create(&file_path3).unwrap();
-        writeln!(file1, "{}", test_str).unwrap();
-        writeln!(file2, "{}", test_str).unwrap();
-        writeln!(file3, "{}", test_str).unwrap();
-        stash.add("test1", false).unwrap();
-        stash.add("test2", false).unwrap();
-        stash.add("test3", false).unwrap();
-
-        let stashed_file1 = stash_path.join("test1");
-        let stashed_file2 = stash_path.join("test2");
-        let stashed_file3 = stash_path.join("test3");
-        assert!(stashed_file1.exists() && !file_path1.exists());
-        assert!(stashed_file2.exists() && !file_path2.exists());
-        assert!(stashed_file3.exists() && !file_path3.exists());
+        stash.set_chunking(true);
+        let chunk_count = |stash: &Stash| {
+            let mut files = Vec::new();
+            Stash::walk_files(&stash.path.join(".chunks"), "", &mut files).unwrap();
+            files.len()
+        };
+
+        //  A megabyte of noise, then the same with a few bytes changed
+        //
+        let mut state = 1u64;
+        let dump: Vec<u8> = (0..1 << 20)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let mut edited = dump.clone();
+        edited[500_000..500_010].copy_from_slice(b"0123456789");
 
-        let before_archiving = stash.list().unwrap();
-        stash.archive().unwrap();
-        assert_eq!(stash.list().unwrap(), "contents");
-        stash.unpack().unwrap();
-        assert_eq!(stash.list().unwrap(), before_archiving);
+        //  Files leave a stub, and identical chunks are only stored once
+        //
+        stash
+            .add_reader(&mut &dump[..], "dump", Conflict::Error)
+            .unwrap();
+        assert_eq!(fs::read(stash.path.join("dump")).unwrap(), chunks::STUB);
+        let first = chunk_count(&stash);
+        assert!(first > 4);
+        stash
+            .add_reader(&mut &dump[..], "copy", Conflict::Error)
+            .unwrap();
+        assert_eq!(chunk_count(&stash), first);
+
+        //  An edit only adds the chunks around it, and stays chunked
+        //
+        stash.set_chunking(false);
+        stash
+            .add_reader(&mut &edited[..], "dump", Conflict::Version)
+            .unwrap();
+        assert!(chunks::is_stub(&stash.path.join("dump")));
+        assert!(chunk_count(&stash) <= first + 2);
+        let mut out = Vec::new();
+        stash.cat("dump", &mut out).unwrap();
+        assert!(out == edited);
+        let name = stash
+            .grab_rev("dump", 1, dir_path, Conflict::Error)
+            .unwrap();
+        assert!(fs::read(dir_path.join(name)).unwrap() == dump);
+
+        //  Chunks travel with bundles, sealed again under the new stash's key
+        //
+        let path = dir_path.join("stash.bundle");
+        stash.export_bundle(&path, "secret").unwrap();
+        let other_dir = TempDir::new().unwrap();
+        let mut other = Stash::test(other_dir.path());
+        other
+            .import_bundle(&path, "secret", Conflict::Error)
+            .unwrap();
+        let mut out = Vec::new();
+        other.cat("copy", &mut out).unwrap();
+        assert!(out == dump);
+
+        //  Chunks are only reclaimed once nothing refers to them
+        //
+        stash.delete("copy").unwrap();
+        assert_eq!(stash.gc().unwrap().0, 0);
+        stash.delete("dump").unwrap();
+        assert_eq!(stash.gc().unwrap().0, chunk_count(&other));
+        assert_eq!(chunk_count(&stash), 0);
     }
 
     #[test]
     #[serial]
-    fn test_valid_encrypt() {
+    //
+    fn test_valid_compression() {
         let temp_dir = TempDir::new().unwrap();
         let dir_path = temp_dir.path();
+        env::set_current_dir(dir_path).unwrap();
 
-        let secret = Secret::new();
-        env::set_current_dir(&dir_path).unwrap();
-
-        let file_path = dir_path.join("test");
-        let mut file = File::create(&file_path).unwrap();
-        let test_str = "Testing: one, two...";
-        writeln!(file, "{}", test_str).unwrap();
+        let mut stash = Stash::test(dir_path);
+        stash.set_compression(Some(Compression::Zstd)).unwrap();
 
-        let result = Stash::encrypt(&file_path, &secret);
-        assert!(result.is_ok());
+        //  Text shrinks on disk, and reads back as it was
+        //
+        let log = "GET /index.html 200\n".repeat(4096);
+        fs::write("server.log", &log).unwrap();
+        stash.add("server.log", true, Conflict::Error).unwrap();
+        let stored = fs::metadata(stash.path.join("server.log")).unwrap().len();
+        assert!(stored < log.len() as u64 / 10);
+        let entry = stash.get_entry("server.log").unwrap().unwrap();
+        assert_eq!(entry.compression, Some(Compression::Zstd));
+        assert_eq!(entry.size, log.len() as u64);
+        let mut out = Vec::new();
+        stash.cat("server.log", &mut out).unwrap();
+        assert_eq!(out, log.as_bytes());
+
+        //  Grabbing decompresses too
+        //
+        fs::remove_file("server.log").unwrap();
+        stash.grab("server.log", false, Conflict::Error).unwrap();
+        assert_eq!(fs::read_to_string("server.log").unwrap(), log);
 
-        let encrypted = fs::read(&file_path).unwrap();
-        assert_ne!(test_str.as_bytes(), encrypted);
-     }
+        //  Data that is compressed already is stored as it is
+        //
+        let mut gzip = b"\x1f\x8b\x08\x00".to_vec();
+        gzip.extend(log.bytes());
+        stash
+            .add_reader(&mut &gzip[..], "logs.gz", Conflict::Error)
+            .unwrap();
+        let entry = stash.get_entry("logs.gz").unwrap().unwrap();
+        assert_eq!(entry.compression, None);
+
+        //  Turning compression off refuses it and stops the default
+        //
+        stash.set_compression(None).unwrap();
+        stash.set_config("compress", "zstd").unwrap();
+        stash
+            .add_reader(&mut log.as_bytes(), "default.log", Conflict::Error)
+            .unwrap();
+        let entry = stash.get_entry("default.log").unwrap().unwrap();
+        assert_eq!(entry.compression, Some(Compression::Zstd));
+        stash.set_config("compress", "off").unwrap();
+        assert!(stash.set_compression(Some(Compression::Xz)).is_err());
+        stash
+            .add_reader(&mut log.as_bytes(), "plain.log", Conflict::Error)
+            .unwrap();
+        let entry = stash.get_entry("plain.log").unwrap().unwrap();
+        assert_eq!(entry.compression, None);
+        assert!(stash.set_config("compress", "brotli").is_err());
+        assert!(stash.set_config("colour", "on").is_err());
+    }
 
     #[test]
     #[serial]
-    fn test_valid_decrypt() {
+    //
+    fn test_valid_tags() {
         let temp_dir = TempDir::new().unwrap();
         let dir_path = temp_dir.path();
+        env::set_current_dir(dir_path).unwrap();
 
-        let secret = Secret::new();
-        env::set_current_dir(&dir_path).unwrap();
-
-        let file_path = dir_path.join("test");
-        let mut file = File::create(&file_path).unwrap();
-        let test_str = "Testing: one, two...";
-        writeln!(file, "{}", test_str).unwrap();
-
-        let result = Stash::encrypt(&file_path, &secret);
-        assert!(result.is_ok());
-
-        let encrypted = fs::read(&file_path).unwrap();
-        assert_ne!(test_str.as_bytes(), encrypted);
+        let mut stash = Stash::test(dir_path);
+        for name in ["id_ed25519", "id_rsa", "notes.txt"] {
+            stash
+                .add_reader(&mut &b"key"[..], name, Conflict::Error)
+                .unwrap();
+        }
+        let tags = |tags: &str| tags.split(',').map(String::from).collect::<Vec<_>>();
 
-        let result = Stash::decrypt(&file_path, &secret);
-        assert!(result.is_ok());
+        //  Tags and notes are sealed, not stored as they are
+        //
+        stash.tag("id_ed25519", &tags("work,ssh")).unwrap();
+        stash.tag("id_rsa", &tags("ssh")).unwrap();
+        stash.set_note("id_ed25519", "rotated 2026-09").unwrap();
+        assert_eq!(stash.tags("id_ed25519").unwrap(), ["ssh", "work"]);
+        assert_eq!(stash.note("id_ed25519").unwrap(), "rotated 2026-09");
+        let raw = stash.entries.get("id_ed25519").unwrap().unwrap();
+        assert!(!String::from_utf8_lossy(&raw).contains("rotated"));
+
+        //  Under a key that the database doesn't hold
+        //
+        stash.db.flush().unwrap();
+        assert!(!db_holds(&stash, &stash.labels_key().unwrap()));
+        assert!(!db_holds(&stash, &stash.master_secret().unwrap()));
+        let secret_path = stash.path.join(".secret");
+        assert_eq!(fs::metadata(&secret_path).unwrap().mode() & 0o777, 0o600);
+        assert!(stash.tag("id_rsa", &tags("two words")).is_err());
+        assert!(stash.tag("missing", &tags("ssh")).is_err());
+
+        //  Tags filter listings and act as selectors
+        //
+        let infos = stash.list(Sort::Name, None, Some("work")).unwrap();
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].note, "rotated 2026-09");
+        assert_eq!(stash.find("tag:ssh").unwrap(), ["id_ed25519", "id_rsa"]);
+        assert!(stash.find("tag:none").is_err());
 
-        let decrypted = fs::read_to_string(&file_path).unwrap();
-        assert_eq!(test_str, decrypted.trim());
+        //  So no file may be named like one
+        //
+        assert!(stash
+            .add_reader(&mut &b"key"[..], "tag:ssh", Conflict::Error)
+            .is_err());
+        fs::write(dir_path.join("tag:prod.env"), "key").unwrap();
+        assert!(stash.add("tag:prod.env", false, Conflict::Error).is_err());
+        assert!(dir_path.join("tag:prod.env").exists());
+        assert_eq!(stash.find("tag:ssh").unwrap(), ["id_ed25519", "id_rsa"]);
+
+        //  New revisions keep their labels, and untagging drops them
+        //
+        stash
+            .add_reader(&mut &b"new key"[..], "id_ed25519", Conflict::Version)
+            .unwrap();
+        assert_eq!(stash.tags("id_ed25519").unwrap(), ["ssh", "work"]);
+        stash.untag("id_ed25519", &tags("work")).unwrap();
+        assert_eq!(stash.tagged("work").unwrap(), Vec::<String>::new());
+        for name in stash.find("tag:ssh").unwrap() {
+            stash.delete(&name).unwrap();
+        }
+        assert_eq!(listed(&stash), ["notes.txt"]);
     }
 
     #[test]
     #[serial]
     //
-    fn test_valid_create_tarball() {
+    fn test_valid_info() {
         let temp_dir = TempDir::new().unwrap();
         let dir_path = temp_dir.path();
-        env::set_current_dir(&dir_path).unwrap();
-
-        let stash_path = dir_path.join("test_stash");
-        let file_path1 = dir_path.join("test1");
-        let file_path2 = dir_path.join("test2");
-        let file_path3 = dir_path.join("test3");
+        env::set_current_dir(dir_path).unwrap();
 
         let mut stash = Stash::test(dir_path);
-        let test_str = "Testing: one, two...";
-
-        let mut file1 = File::create(&file_path1).unwrap();
-        let mut file2 = File::create(&file_path2).unwrap();
-        let mut file3 = File::create(&file_path3).unwrap();
-        writeln!(file1, "{}", test_str).unwrap();
-        writeln!(file2, "{}", test_str).unwrap();
-        writeln!(file3, "{}", test_str).unwrap();
-        stash.add("test1", false).unwrap();
-        stash.add("test2", false).unwrap();
-        stash.add("test3", false).unwrap();
-
-        let stashed_file1 = stash_path.join("test1");
-        let stashed_file2 = stash_path.join("test2");
-        let stashed_file3 = stash_path.join("test3");
-        assert!(stashed_file1.exists() && !file_path1.exists());
-        assert!(stashed_file2.exists() && !file_path2.exists());
-        assert!(stashed_file3.exists() && !file_path3.exists());
-
-        let result = stash.create_tarball();
-        assert!(result.is_ok());
-        assert!(stash.contents.exists());
-        assert_eq!(stash.list().unwrap(), "contents");
+        stash
+            .add_reader(&mut &b"host=db1"[..], "db.env", Conflict::Error)
+            .unwrap();
+        stash
+            .add_reader(&mut &b"host=db2"[..], "db.env", Conflict::Version)
+            .unwrap();
+        stash.tag("db.env", &["work".to_string()]).unwrap();
+
+        //  Metadata comes from the database, sizes from both sides
+        //
+        let info = stash.info("db.env").unwrap();
+        assert_eq!((info.size, info.stored), (8, 24));
+        assert_eq!(info.hash, format!("{:x}", Sha256::digest(b"host=db2")));
+        assert_eq!(info.revisions.len(), 2);
+        assert!(info.revisions[0].current);
+        assert!(info.created <= info.stashed);
+        assert_eq!(info.tags, ["work"]);
+        assert!(info.cached && info.in_stash && info.archives.is_empty());
+        assert!(info.accessed.is_some());
+        assert!(stash.info("missing").is_err());
+
+        //  Archived files are described from their archive
+        //
+        stash
+            .archive("old", &["db.env".to_string()], &ArchiveOptions::default())
+            .unwrap();
+        let info = stash.info("db.env").unwrap();
+        assert!(!info.in_stash && !info.cached);
+        assert_eq!(info.archives, ["old"]);
+        assert_eq!((info.size, info.revisions.len()), (8, 2));
     }
 
     #[test]
     #[serial]
     //
-    fn test_valid_extract_tarball() {
+    fn test_valid_grep() {
         let temp_dir = TempDir::new().unwrap();
         let dir_path = temp_dir.path();
-        env::set_current_dir(&dir_path).unwrap();
-
-        let stash_path = dir_path.join("test_stash");
-        let file_path1 = dir_path.join("test1");
-        let file_path2 = dir_path.join("test2");
-        let file_path3 = dir_path.join("test3");
+        env::set_current_dir(dir_path).unwrap();
 
         let mut stash = Stash::test(dir_path);
-        let test_str = "Testing: one, two...";
-
-        let mut file1 = File::create(&file_path1).unwrap();
-        let mut file2 = File::create(&file_path2).unwrap();
-        let mut file3 = File::create(&file_path3).unwrap();
-        writeln!(file1, "{}", test_str).unwrap();
-        writeln!(file2, "{}", test_str).unwrap();
-        writeln!(file3, "{}", test_str).unwrap();
-        stash.add("test1", false).unwrap();
-        stash.add("test2", false).unwrap();
-        stash.add("test3", false).unwrap();
+        let files: [(&str, &[u8]); 4] = [
+            ("web.env", b"HOST=db1.internal\nPORT=5432\n"),
+            ("jobs.env", b"HOST=db2.internal\nTOKEN=ghp_abc\n"),
+            ("notes.txt", b"nothing here\n"),
+            ("blob.bin", b"\x00\x01HOST=db3.internal"),
+        ];
+        for (name, contents) in files {
+            stash
+                .add_reader(&mut &contents[..], name, Conflict::Error)
+                .unwrap();
+        }
+        stash.set_compression(Some(Compression::Gzip)).unwrap();
+        stash
+            .add_reader(
+                &mut &b"HOST=db4.internal\n"[..],
+                "packed.env",
+                Conflict::Error,
+            )
+            .unwrap();
 
-        let stashed_file1 = stash_path.join("test1");
-        let stashed_file2 = stash_path.join("test2");
-        let stashed_file3 = stash_path.join("test3");
-        assert!(stashed_file1.exists() && !file_path1.exists());
-        assert!(stashed_file2.exists() && !file_path2.exists());
-        assert!(stashed_file3.exists() && !file_path3.exists());
+        //  Matches come back per line and file, skipping binary files
+        //
+        let matches = |found: Vec<GrepResult>| {
+            found
+                .into_iter()
+                .flat_map(|(_, result)| result.unwrap())
+                .collect::<Vec<_>>()
+        };
+        let found = matches(stash.grep(r"db\d\.internal", &[], false).unwrap());
+        let lines: Vec<_> = found
+            .iter()
+            .map(|found| (found.name.as_str(), found.line, found.text.as_str()))
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines.contains(&("web.env", 1, "HOST=db1.internal")));
+        assert!(lines.contains(&("jobs.env", 1, "HOST=db2.internal")));
+        assert!(lines.contains(&("packed.env", 1, "HOST=db4.internal")));
+
+        //  Patterns narrow the search, and `binary` takes in everything
+        //
+        let found = matches(
+            stash
+                .grep("^TOKEN=", &["jobs.env".to_string()], false)
+                .unwrap(),
+        );
+        assert_eq!(found[0].line, 2);
+        assert!(matches(stash.grep("^$", &[], false).unwrap()).is_empty());
+        let found = matches(stash.grep("db3", &["*.bin".to_string()], true).unwrap());
+        assert_eq!(found.len(), 1);
+        assert!(stash.grep("(", &[], false).is_err());
+        let found = stash.grep("x", &["missing".to_string()], false).unwrap();
+        assert!(found[0].1.is_err());
+
+        //  Chunked files are read a chunk at a time, lines running across them
+        //
+        let rows: String = (0..50_000).map(|i| format!("row {}\n", i)).collect();
+        stash.set_chunking(true);
+        stash
+            .add_reader(&mut rows.as_bytes(), "rows.log", Conflict::Error)
+            .unwrap();
+        stash.set_compression(None).unwrap();
+        stash
+            .add_reader(&mut rows.as_bytes(), "rows.txt", Conflict::Error)
+            .unwrap();
+        for name in ["rows.log", "rows.txt"] {
+            assert!(chunks::is_stub(&stash.path.join(name)));
+            let found = matches(
+                stash
+                    .grep(r"^row \d+$", &[name.to_string()], false)
+                    .unwrap(),
+            );
+            assert_eq!(found.len(), 50_000);
+            assert_eq!(
+                (found[4242].line, found[4242].text.as_str()),
+                (4243, "row 4242")
+            );
+        }
 
-        let before_archiving = stash.list().unwrap();
-        let mut result = stash.create_tarball();
-        assert!(result.is_ok());
-        assert_eq!(stash.list().unwrap(), "contents");
-        result = stash.extract_tarball();
-        assert!(result.is_ok());
-        assert_eq!(stash.list().unwrap(), before_archiving);
+        //  And one file that can't be read doesn't stop the others
+        //
+        fs::write(stash.path.join("web.env"), [0; 64]).unwrap();
+        let found = stash.grep("HOST", &["*.env".to_string()], false).unwrap();
+        let names: Vec<_> = found.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["jobs.env", "packed.env", "web.env"]);
+        assert_eq!(found[0].1.as_ref().unwrap().len(), 1);
+        assert_eq!(found[1].1.as_ref().unwrap().len(), 1);
+        assert!(found[2].1.is_err());
     }
- }
+}
@@ -5,23 +5,99 @@
 //! Usage: stash <command> [<args>]
 //!
 //! Available commands:
-//!   - add [-c] <file>: Encrypt a file and add it to the stash (optionally copy it).
-//!   - grab [-c] <file>: Decrypt a file from the stash and drop it in the current directory (optionally copy it).
-//!   - delete <file>: Delete a stashed file.
-//!   - list: List the contents of the stash.
-//!   - archive: Create a compressed tarball from stash contents.
-//!   - unpack: Unpack archive of stash contents.
+//!   - add [-c] [-r] <file>...: Encrypt files and add them to the stash (optionally copy them, or add directories).
+//!   - add --name <name> -: Encrypt stdin and add it to the stash as <name>.
+//!   - add --chunked <file>...: Store files as deduplicated, content-defined chunks.
+//!   - add --compress[=<algorithm>] <file>...: Compress files before encrypting them, unless they're compressed already.
+//!   - config [<key> [<value>]]: Show or change settings, such as `compress` (off, manual, or a default algorithm).
+//!   - grab [-c] [-r] <file>...: Decrypt files from the stash and drop them in the current directory (optionally copy them, or grab directories).
+//!   - cat <file>...: Write decrypted files to stdout without creating any files.
+//!   - edit [--new] <file>: Edit a stashed file in $EDITOR without leaving plaintext behind.
+//!   - exec --file <VAR>=<file>... -- <command>: Run a command with decrypted files passed as in-memory paths.
+//!   - env set|import|unset|list: Manage encrypted environment variables, grouped by project.
+//!   - run <project> -- <command>: Run a command with a project's environment variables.
+//!   - mount <dir>: Mount a read-only, decrypted view of the stash (until unmounted).
+//!   - umount [<dir>]: Unmount views of the stash.
+//!   - open [<pattern>...]: Decrypt files into a private workspace on tmpfs.
+//!   - close [--discard] [<workspace>]: Stash a workspace's changes and wipe it.
+//!   - status: Show open workspaces and their changes.
+//!   - export-bundle <bundle>: Write every file, key and environment to a portable file sealed under a passphrase.
+//!   - import-bundle [--on-conflict=<policy>] <bundle>: Merge a bundle into the stash.
+//!   - backup [--keep-daily <n>] [--keep-weekly <n>] <target>: Copy the stash's ciphertext and an encrypted snapshot of its keys into a deduplicating backup repository.
+//!   - backup list <target>: List the backups in a repository.
+//!   - backup restore <target> [<backup>]: Restore the latest or a given backup into an empty stash.
+//!   - gc: Remove chunks that no file, revision or archive refers to.
+//!   - delete <file>...: Delete stashed files.
+//!   - log <file>: List the revisions of a stashed file.
+//!   - grep [-a] <regex> [<pattern>...]: Search inside stashed files without writing plaintext anywhere (binary files only with -a).
+//!   - info [--json] <file>: Show everything known about a stashed file without decrypting it.
+//!   - tag add|remove <file> <tag>[,<tag>...]: Tag a stashed file, or untag it; `tag:<tag>` then selects its files wherever patterns go.
+//!   - tag <file>: Show a stashed file's tags.
+//!   - note <file> [<note>]: Show or set a short note on a stashed file.
+//!   - prune [--keep <n>] [--older-than <age>] [<file>...]: Drop old revisions and shred their keys.
+//!   - list [-l] [--sort <key>] [--filter <pattern>] [--tag <tag>] [--tree] [--json]: List the files in the stash, optionally with sizes, dates, original paths and how each is stored.
+//!   - list --archives | --archive <name>: List the named archives, or the files in one.
+//!   - archive [--name <name>] [--incremental] [--compression <algorithm>] [--level <n>] [--volume-size <size>] [<pattern>...]: Move files into a named, encrypted tarball (or copy what changed since the last incremental archive).
+//!   - unpack [<name> [<file>...]]: Restore the files of a named archive, or just some of them.
+//!   - unpack --chain [<name>]: Rebuild the files captured by a chain of incremental archives.
 //!
 //! Example usage:
 //! ```shell
 //! $ stash add secret_file.txt
 //! $ stash add -c secret_file.txt
+//! $ stash add -r --symlinks=follow secret_dir
 //! $ stash grab secret_file.txt
 //! $ stash grab -c secret_file.txt
+//! $ stash grab -r secret_dir
+//! $ stash add a.pem b.pem
+//! $ stash grab 'certs/*.pem'
+//! $ stash add --on-conflict=version secret_file.txt
+//! $ stash grab --force secret_file.txt
+//! $ stash add --update secret_file.txt
+//! $ stash log secret_file.txt
+//! $ stash info --json secret_file.txt
+//! $ stash grep 'db[0-9]+\.internal' '*.env'
+//! $ stash grab --rev 1 secret_file.txt
+//! $ stash prune --keep 3
+//! $ stash grab secret_file.txt -o ~/secrets/
+//! $ stash cat db.env | psql
+//! $ pg_dump | stash add --name backup.sql -
+//! $ stash add --chunked --update dump.sql
+//! $ stash gc
+//! $ stash add --compress server.log
+//! $ stash config compress off
+//! $ stash edit db.env
+//! $ stash exec --file KUBECONFIG=kube.yaml -- kubectl get pods
+//! $ stash env import myapp .env
+//! $ stash run myapp -- cargo test
+//! $ stash mount ~/secrets &
+//! $ stash umount ~/secrets
+//! $ stash open 'certs/*'
+//! $ stash status
+//! $ stash close
+//! $ stash export-bundle laptop.stashbundle
+//! $ stash import-bundle --on-conflict=rename laptop.stashbundle
+//! $ stash backup --keep-daily 7 --keep-weekly 4 /mnt/backup/stash
+//! $ stash backup restore /mnt/backup/stash
 //! $ stash delete secret_file.txt
 //! $ stash list
-//! $ stash archive
-//! $ stash unpack
+//! $ stash list -l --sort size --filter 'dumps/*'
+//! $ stash list --tree
+//! $ stash list --json
+//! $ stash tag add id_ed25519 work,ssh
+//! $ stash note id_ed25519 "rotated 2026-09"
+//! $ stash list --tag work
+//! $ stash grab -c tag:ssh
+//! $ stash archive --name old-work tag:work
+//! $ stash archive --name Q3-2026 'reports/*'
+//! $ stash archive --name dumps --compression zstd --level 19 'dumps/*'
+//! $ stash archive --name media --volume-size 4G 'media/*'
+//! $ stash list --archives
+//! $ stash list --archive Q3-2026
+//! $ stash unpack Q3-2026 reports/july.pdf
+//! $ stash unpack Q3-2026
+//! $ stash archive --incremental
+//! $ stash unpack --chain
 //! ```
 //!
 //! For more information, refer to the documentation of each command and its respective functions.
@@ -29,13 +105,16 @@
 //! Authors: Jacob Bentley,
 //!          Richard Duffy
 
+use chrono::{DateTime, Local};
 use stash::*;
 use std::{
     env,
-    io::{self, Error, ErrorKind},
-    path::Path,
+    io::{self, Error},
+    path::{Path, PathBuf},
     process::{exit, Command, Stdio},
+    str::FromStr,
 };
+use zeroize::Zeroizing;
 
 const USAGE: &str = "usage: stash <command> [<args>]";
 const ERR: &str = "stash: error:";
@@ -103,92 +182,725 @@ fn main() {
     match cmd.as_str() {
         //
         "add" => {
-            if args.len() != 1 && args.len() != 2 {
-                eprintln!("usage: stash add [-c] <file>");
-                exit(1);
-            }
-            let (file, option) = match args.len() {
-                1 => (&args[0], false),
-                2 => {
-                    let flag = args[0] == "-c";
-                    (&args[1], flag)
+            //  A bare `--compress` picks the default algorithm
+            //
+            let args: Vec<String> = args
+                .iter()
+                .map(|arg| match arg.as_str() {
+                    "--compress" => "--compress=zstd".to_string(),
+                    _ => arg.clone(),
+                })
+                .collect();
+            let args = match parse_args(
+                &args,
+                &["-c", "-r", "--update", "--chunked", "--fail-fast"],
+                &["--symlinks", "--on-conflict", "--name", "--compress"],
+            ) {
+                //  Stdin needs a name, and nothing else does
+                //
+                Ok(args)
+                    if !args.files.is_empty()
+                        && (args.files == ["-"]) == args.get("--name").is_some() =>
+                {
+                    args
                 }
                 _ => {
-                    eprintln!("{} Unable to parse arguments", ERR);
+                    eprintln!(
+                        "usage: stash add [-c] [-r [--symlinks=skip|follow|preserve]] [--update | --on-conflict=error|overwrite|rename|version] [--chunked | --compress[=zstd|xz|gzip|none]] [--fail-fast] <file>...\n       stash add --name <name> [--update | --on-conflict=...] [--chunked | --compress[=...]] -"
+                    );
+                    exit(1);
+                }
+            };
+            let copy = args.has("-c");
+            let links = parse_policy(args.get("--symlinks").unwrap_or("skip"));
+            stash.set_chunking(args.has("--chunked"));
+            if let Some(compression) = args.get("--compress") {
+                if let Err(msg) = stash.set_compression(Some(parse_policy(compression))) {
+                    eprintln!("{} {}", ERR, msg);
                     exit(1);
                 }
+            }
+
+            //  `--update` is shorthand for keeping versions
+            //
+            let conflict = if args.has("--update") {
+                Conflict::Version
+            } else {
+                parse_policy(args.get("--on-conflict").unwrap_or("error"))
             };
-            //  Encrypt file and add it to stash
+            let targets = args.files.iter().cloned().map(Ok).collect();
+
+            //  Encrypt each file or directory tree and add it to stash
             //
-            match stash.add(file, option) {
-                Ok(_) => {}
-                Err(msg) => eprintln!("{} {}", ERR, msg),
+            let ok = batch(targets, "added", args.has("--fail-fast"), |file| {
+                if let Some(name) = args.get("--name") {
+                    stash.add_reader(&mut io::stdin().lock(), name, conflict)
+                } else if args.has("-r") {
+                    stash
+                        .add_dir(file, copy, links, conflict)
+                        .map(|_| file.to_string())
+                } else {
+                    stash.add(file, copy, conflict)
+                }
+            });
+            if !ok {
+                exit(1);
             }
         }
         "grab" => {
-            if args.len() != 1 && args.len() != 2 {
-                eprintln!("usage: stash grab [-c] <file>");
+            let args = match parse_args(
+                args,
+                &["-c", "-r", "--stdout", "--force", "--fail-fast"],
+                &["-o", "--on-conflict", "--rev"],
+            ) {
+                Ok(args)
+                    if !args.files.is_empty()
+                        && !(args.has("-r")
+                            && (args.has("--stdout") || args.get("-o").is_some())) =>
+                {
+                    args
+                }
+                _ => {
+                    eprintln!(
+                        "usage: stash grab [-c] [-r] [--rev <n>] [-o <path> | --stdout] [--force | --on-conflict=error|overwrite|rename|version] [--fail-fast] <file>..."
+                    );
+                    return;
+                }
+            };
+            let rev = args.get("--rev").map(|rev| match rev.parse::<u32>() {
+                Ok(rev) => rev,
+                Err(_) => {
+                    eprintln!("{} Invalid revision: {}", ERR, rev);
+                    exit(1);
+                }
+            });
+            let copy = args.has("-c");
+
+            //  `--force` is shorthand for overwriting
+            //
+            let conflict = if args.has("--force") {
+                Conflict::Overwrite
+            } else {
+                parse_policy(args.get("--on-conflict").unwrap_or("error"))
+            };
+
+            //  Match patterns against the stash, not the current directory
+            //
+            let targets: Vec<_> = if args.has("-r") {
+                args.files.iter().cloned().map(Ok).collect()
+            } else {
+                expand(&stash, &args.files)
+            };
+
+            //  Stream decrypted bytes instead of creating files
+            //
+            if args.has("--stdout") {
+                if !cat(&stash, targets, args.has("--fail-fast")) {
+                    exit(1);
+                }
                 return;
             }
-            let (file, option) = match args.len() {
-                1 => (&args[0], false),
-                2 => {
-                    let flag = args[0] == "-c";
-                    (&args[1], flag)
+
+            //  Drop files in the current directory unless told otherwise
+            //
+            let dst = match args.get("-o") {
+                Some(path) => PathBuf::from(path),
+                None => match env::current_dir() {
+                    Ok(cwd) => cwd,
+                    Err(msg) => {
+                        eprintln!("{} {}", ERR, msg);
+                        exit(1);
+                    }
+                },
+            };
+            if targets.len() > 1 && !dst.is_dir() {
+                eprintln!("{} Destination must be a directory for several files", ERR);
+                exit(1);
+            }
+
+            //  Decrypt each file or directory tree and drop it at destination
+            //
+            let ok = batch(targets, "grabbed", args.has("--fail-fast"), |file| {
+                if args.has("-r") {
+                    stash
+                        .grab_dir(file, copy, conflict)
+                        .map(|_| file.to_string())
+                } else if let Some(rev) = rev {
+                    stash.grab_rev(file, rev, &dst, conflict)
+                } else {
+                    stash.grab_to(file, &dst, copy, conflict)
                 }
+            });
+            if !ok {
+                exit(1);
+            }
+        }
+        "cat" => {
+            let args = match parse_args(args, &["--fail-fast"], &[]) {
+                Ok(args) if !args.files.is_empty() => args,
                 _ => {
-                    eprintln!("{} Unable to parse arguments", ERR);
+                    eprintln!("usage: stash cat [--fail-fast] <file>...");
                     exit(1);
                 }
             };
-            //  Decrypt file and drop in current directory
+            let targets = expand(&stash, &args.files);
+
+            //  Write decrypted files to stdout, in order
             //
-            match stash.grab(file, option) {
-                Ok(_) => {}
-                Err(msg) => eprintln!("{} {}", ERR, msg),
+            if !cat(&stash, targets, args.has("--fail-fast")) {
+                exit(1);
             }
         }
-        "delete" => {
+        "edit" => {
+            let args = match parse_args(args, &["--new"], &[]) {
+                Ok(args) if args.files.len() == 1 => args,
+                _ => {
+                    eprintln!("usage: stash edit [--new] <file>");
+                    exit(1);
+                }
+            };
+            let editor = env::var("VISUAL")
+                .or_else(|_| env::var("EDITOR"))
+                .unwrap_or_else(|_| "vi".to_string());
+
+            //  Edit a decrypted copy on tmpfs, then stash any changes
+            //
+            match stash.edit(&args.files[0], &editor, args.has("--new")) {
+                Ok(true) => println!("{}: saved", args.files[0]),
+                Ok(false) => println!("{}: unchanged", args.files[0]),
+                Err(msg) => {
+                    eprintln!("{} {}", ERR, msg);
+                    exit(1);
+                }
+            }
+        }
+        "exec" => {
+            let args = match parse_args(args, &[], &["--file"]) {
+                Ok(args)
+                    if !args.files.is_empty()
+                        && args.options.iter().all(|(_, value)| value.contains('=')) =>
+                {
+                    args
+                }
+                _ => {
+                    eprintln!("usage: stash exec --file <VAR>=<file>... -- <command> [<args>...]");
+                    exit(1);
+                }
+            };
+            let files: Vec<(String, String)> = args
+                .options
+                .iter()
+                .filter_map(|(_, value)| value.split_once('='))
+                .map(|(var, file)| (var.to_string(), file.to_string()))
+                .collect();
+
+            //  Run command with decrypted files held in memory
+            //
+            match stash.exec(&files, &args.files) {
+                Ok(status) => exit(status.code().unwrap_or(1)),
+                Err(msg) => {
+                    eprintln!("{} {}", ERR, msg);
+                    exit(1);
+                }
+            }
+        }
+        "env" => {
+            let usage = "usage: stash env set <project> <KEY>=<value>...\n       stash env import <project> <file>\n       stash env unset <project> [<KEY>...]\n       stash env list [<project>]";
+            let result = match args.first().map(String::as_str) {
+                Some("set") if args.len() > 2 => {
+                    match args[2..]
+                        .iter()
+                        .map(|var| var.split_once('='))
+                        .collect::<Option<Vec<_>>>()
+                    {
+                        Some(vars) => {
+                            let vars: Vec<(String, String)> = vars
+                                .into_iter()
+                                .map(|(key, value)| (key.to_string(), value.to_string()))
+                                .collect();
+                            stash.env_set(&args[1], &vars)
+                        }
+                        None => {
+                            eprintln!("{}", usage);
+                            exit(1);
+                        }
+                    }
+                }
+                Some("import") if args.len() == 3 => stash
+                    .env_import(&args[1], Path::new(&args[2]))
+                    .map(|count| println!("{}: imported {} variables", args[1], count)),
+                Some("unset") if args.len() > 1 => stash.env_unset(&args[1], &args[2..]),
+                Some("list") if args.len() <= 2 => stash
+                    .env_list(args.get(1).map(String::as_str))
+                    .map(|names| {
+                        for name in names {
+                            println!("{}", name);
+                        }
+                    }),
+                _ => {
+                    eprintln!("{}", usage);
+                    exit(1);
+                }
+            };
+            if let Err(msg) = result {
+                eprintln!("{} {}", ERR, msg);
+                exit(1);
+            }
+        }
+        "run" => {
+            if args.len() < 2 {
+                eprintln!("usage: stash run <project> -- <command> [<args>...]");
+                exit(1);
+            }
+            let cmd = if args[1] == "--" {
+                &args[2..]
+            } else {
+                &args[1..]
+            };
+
+            //  Run command with the project's variables in its environment
+            //
+            match stash.run(&args[0], cmd) {
+                Ok(status) => exit(status.code().unwrap_or(1)),
+                Err(msg) => {
+                    eprintln!("{} {}", ERR, msg);
+                    exit(1);
+                }
+            }
+        }
+        "mount" => {
             if args.len() != 1 {
-                eprintln!("usage: stash delete <file>");
+                eprintln!("usage: stash mount <dir>");
                 exit(1);
             }
-            let file = &args[0];
+            //  Serve a decrypted view until unmounted
+            //
+            if let Err(msg) = stash.mount(Path::new(&args[0])) {
+                eprintln!("{} {}", ERR, msg);
+                exit(1);
+            }
+        }
+        "umount" => {
+            if args.len() > 1 {
+                eprintln!("usage: stash umount [<dir>]");
+                exit(1);
+            }
+            match Stash::umount(args.first().map(Path::new)) {
+                Ok(dirs) if dirs.is_empty() => {
+                    eprintln!("{} No stash is mounted", ERR);
+                    exit(1);
+                }
+                Ok(dirs) => {
+                    for dir in dirs {
+                        println!("{}: unmounted", dir.display());
+                    }
+                }
+                Err(msg) => {
+                    eprintln!("{} {}", ERR, msg);
+                    exit(1);
+                }
+            }
+        }
+        "open" => {
+            //  Decrypt selected files into a workspace on tmpfs
+            //
+            match stash.open(args) {
+                Ok(path) => println!("{}", path.display()),
+                Err(msg) => {
+                    eprintln!("{} {}", ERR, msg);
+                    exit(1);
+                }
+            }
+        }
+        "close" => {
+            let args = match parse_args(args, &["--discard"], &[]) {
+                Ok(args) if args.files.len() <= 1 => args,
+                _ => {
+                    eprintln!("usage: stash close [--discard] [<workspace>]");
+                    exit(1);
+                }
+            };
 
-            //  Delete a file in the stash
+            //  Stash what changed, then wipe the workspace
             //
-            match stash.delete(file) {
-                Ok(_) => {}
+            match stash.close(args.files.first().map(Path::new), args.has("--discard")) {
+                Ok(changes) => {
+                    for change in changes {
+                        println!("{}", format_change(&change));
+                    }
+                }
                 Err(msg) => {
                     eprintln!("{} {}", ERR, msg);
                     exit(1);
                 }
             }
         }
-        "list" => {
+        "status" => {
             if !args.is_empty() {
-                eprintln!("usage: stash list");
+                eprintln!("usage: stash status");
                 exit(1);
             }
-            //  Display contents of stash
+            match stash.status() {
+                Ok(status) if status.is_empty() => println!("No open workspaces"),
+                Ok(status) => {
+                    for workspace in status {
+                        println!(
+                            "{}  (opened {})",
+                            workspace.path.display(),
+                            format_time(workspace.opened)
+                        );
+                        if workspace.missing {
+                            println!("    missing; `stash close` to forget it");
+                        } else if workspace.changes.is_empty() {
+                            println!("    no changes");
+                        }
+                        for change in workspace.changes {
+                            println!("    {}", format_change(&change));
+                        }
+                    }
+                }
+                Err(msg) => {
+                    eprintln!("{} {}", ERR, msg);
+                    exit(1);
+                }
+            }
+        }
+        "delete" => {
+            let args = match parse_args(args, &["--fail-fast"], &[]) {
+                Ok(args) if !args.files.is_empty() => args,
+                _ => {
+                    eprintln!("usage: stash delete [--fail-fast] <file>...");
+                    exit(1);
+                }
+            };
+            let targets = expand(&stash, &args.files);
+
+            //  Delete each matching file in the stash
             //
-            match stash.list() {
-                Ok(contents) => println!("{}", contents),
+            let ok = batch(targets, "deleted", args.has("--fail-fast"), |file| {
+                stash.delete(file).map(|_| file.to_string())
+            });
+            if !ok {
+                exit(1);
+            }
+        }
+        "grep" => {
+            let args = match parse_args(args, &["-a"], &[]) {
+                Ok(args) if !args.files.is_empty() => args,
+                _ => {
+                    eprintln!("usage: stash grep [-a] <regex> [<pattern>...]");
+                    exit(1);
+                }
+            };
+            //  Search decrypted contents in memory, like `grep -Hn`
+            //
+            let found = match stash.grep(&args.files[0], &args.files[1..], args.has("-a")) {
+                Ok(found) => found,
                 Err(msg) => {
                     eprintln!("{} {}", ERR, msg);
+                    exit(2);
+                }
+            };
+            //  A file that can't be searched doesn't stop the others
+            //
+            let (mut matched, mut failed) = (false, false);
+            for (name, result) in found {
+                match result {
+                    Ok(lines) => {
+                        for line in lines {
+                            println!("{}:{}:{}", line.name, line.line, *line.text);
+                            matched = true;
+                        }
+                    }
+                    Err(msg) => {
+                        eprintln!("{} {}: {}", ERR, name, msg);
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                exit(2);
+            } else if !matched {
+                exit(1);
+            }
+        }
+        "info" => {
+            let args = match parse_args(args, &["--json"], &[]) {
+                Ok(args) if args.files.len() == 1 => args,
+                _ => {
+                    eprintln!("usage: stash info [--json] <file>");
+                    exit(1);
+                }
+            };
+            //  Describe the file without decrypting it
+            //
+            let result = stash.info(&args.files[0]).and_then(|details| {
+                if args.has("--json") {
+                    println!("{}", serde_json::to_string_pretty(&details)?);
+                } else {
+                    print!("{}", format_details(&details));
+                }
+                Ok(())
+            });
+            if let Err(msg) = result {
+                eprintln!("{} {}", ERR, msg);
+                exit(1);
+            }
+        }
+        "tag" => {
+            //  Tags are given as a comma-separated list
+            //
+            let split = |tags: &str| -> Vec<String> {
+                tags.split(',')
+                    .map(|tag| tag.trim().to_string())
+                    .filter(|tag| !tag.is_empty())
+                    .collect()
+            };
+            let result = match args {
+                [cmd, file, tags] if cmd == "add" => stash.tag(file, &split(tags)),
+                [cmd, file, tags] if cmd == "remove" => stash.untag(file, &split(tags)),
+                [file] => stash.tags(file).map(|tags| println!("{}", tags.join(","))),
+                _ => {
+                    eprintln!("usage: stash tag add|remove <file> <tag>[,<tag>...]\n       stash tag <file>");
                     exit(1);
                 }
+            };
+            if let Err(msg) = result {
+                eprintln!("{} {}", ERR, msg);
+                exit(1);
             }
         }
-        "archive" => {
-            if !args.is_empty() {
-                eprintln!("usage: stash archive");
+        "note" => {
+            //  Show the note, or replace it (an empty note clears it)
+            //
+            let result = match args {
+                [file] => stash.note(file).map(|note| {
+                    if !note.is_empty() {
+                        println!("{}", note);
+                    }
+                }),
+                [file, note] => stash.set_note(file, note),
+                _ => {
+                    eprintln!("usage: stash note <file> [<note>]");
+                    exit(1);
+                }
+            };
+            if let Err(msg) = result {
+                eprintln!("{} {}", ERR, msg);
+                exit(1);
+            }
+        }
+        "log" => {
+            if args.len() != 1 {
+                eprintln!("usage: stash log <file>");
                 exit(1);
             }
-            //  Create `.tar.gz` of stash contents
+            //  Display revisions of a stashed file, newest first
             //
-            match stash.archive() {
-                Ok(_) => {}
+            match stash.log(&args[0]) {
+                Ok(log) => {
+                    for rev in log {
+                        println!(
+                            "{:>4}  {}  {}{}",
+                            rev.number,
+                            format_time(rev.stashed),
+                            rev.hash.get(..12).unwrap_or(&rev.hash),
+                            if rev.current { "  (current)" } else { "" }
+                        );
+                    }
+                }
+                Err(msg) => {
+                    eprintln!("{} {}", ERR, msg);
+                    exit(1);
+                }
+            }
+        }
+        "prune" => {
+            let args = match parse_args(args, &["--fail-fast"], &["--keep", "--older-than"]) {
+                Ok(args) if args.get("--keep").is_some() || args.get("--older-than").is_some() => {
+                    args
+                }
+                _ => {
+                    eprintln!(
+                        "usage: stash prune [--keep <n>] [--older-than <age>] [--fail-fast] [<file>...]"
+                    );
+                    exit(1);
+                }
+            };
+            let keep = args.get("--keep").map(|keep| match keep.parse::<usize>() {
+                Ok(keep) => keep,
+                Err(_) => {
+                    eprintln!("{} Invalid number of revisions: {}", ERR, keep);
+                    exit(1);
+                }
+            });
+            let older_than = args.get("--older-than").map(|age| match parse_age(age) {
+                Some(age) => age,
+                None => {
+                    eprintln!("{} Invalid age: {} (try 30d, 12h or 2w)", ERR, age);
+                    exit(1);
+                }
+            });
+
+            //  Prune every file unless told otherwise
+            //
+            let targets = if args.files.is_empty() {
+                match stash.names() {
+                    Ok(names) => names.into_iter().map(Ok).collect(),
+                    Err(msg) => {
+                        eprintln!("{} {}", ERR, msg);
+                        exit(1);
+                    }
+                }
+            } else {
+                expand(&stash, &args.files)
+            };
+
+            //  Drop old revisions, shredding their keys
+            //
+            let ok = batch(targets, "pruned", args.has("--fail-fast"), |file| {
+                stash
+                    .prune(file, keep, older_than)
+                    .map(|dropped| match dropped.len() {
+                        0 => file.to_string(),
+                        n => format!("{} ({} revisions)", file, n),
+                    })
+            });
+            if !ok {
+                exit(1);
+            }
+        }
+        "list" => {
+            let args = match parse_args(
+                args,
+                &["--archives", "-l", "--tree", "--json"],
+                &["--archive", "--sort", "--filter", "--tag"],
+            ) {
+                Ok(args) if args.files.is_empty() => args,
+                _ => {
+                    eprintln!(
+                        "usage: stash list [-l] [--sort name|size|stashed|modified] [--filter <pattern>] [--tag <tag>] [--tree] [--json]\n       stash list --archives | --archive <name>"
+                    );
+                    exit(1);
+                }
+            };
+            //  Display named archives, the files in one, or contents of stash
+            //
+            let result = if let Some(name) = args.get("--archive") {
+                stash.manifest(name).map(|members| {
+                    for member in members {
+                        println!(
+                            "{:>10}  {}  {}",
+                            member.size,
+                            &member.hash[..member.hash.len().min(12)],
+                            member.name
+                        );
+                    }
+                })
+            } else if args.has("--archives") {
+                stash.archives().map(|archives| {
+                    for archive in archives {
+                        let kind = match (&archive.parent, archive.incremental) {
+                            (Some(parent), _) => format!("  (incremental on {})", parent),
+                            (None, true) => "  (incremental base)".to_string(),
+                            (None, false) => String::new(),
+                        };
+                        println!(
+                            "{}  {}  {} files{}",
+                            archive.name,
+                            format_time(archive.created),
+                            archive.files,
+                            kind
+                        );
+                    }
+                })
+            } else {
+                let sort = parse_policy(args.get("--sort").unwrap_or("name"));
+                stash
+                    .list(sort, args.get("--filter"), args.get("--tag"))
+                    .and_then(|mut infos| {
+                        if args.has("--json") {
+                            println!("{}", serde_json::to_string_pretty(&infos)?);
+                            return Ok(());
+                        }
+                        //  Keep each directory's files together, in the order asked for
+                        //
+                        if args.has("--tree") {
+                            infos.sort_by(|a, b| {
+                                let dirs = |name| parent_dir(name).split('/');
+                                dirs(&a.name).cmp(dirs(&b.name))
+                            });
+                        }
+                        let mut dir = "";
+                        for info in &infos {
+                            let mut name = info.name.clone();
+                            if args.has("--tree") {
+                                for line in tree_dirs(dir, parent_dir(&info.name)) {
+                                    println!("{}", line);
+                                }
+                                dir = parent_dir(&info.name);
+                                let depth = info.name.matches('/').count();
+                                let base = info.name.rsplit('/').next().unwrap_or(&info.name);
+                                name = format!("{}{}", "  ".repeat(depth), base);
+                            }
+                            if args.has("-l") {
+                                println!("{}", format_entry(info, &name));
+                            } else {
+                                println!("{}", name);
+                            }
+                        }
+                        Ok(())
+                    })
+            };
+            if let Err(msg) = result {
+                eprintln!("{} {}", ERR, msg);
+                exit(1);
+            }
+        }
+        "archive" => {
+            let args = match parse_args(
+                args,
+                &["--incremental"],
+                &["--name", "--compression", "--level", "--volume-size"],
+            ) {
+                Ok(args) => args,
+                _ => {
+                    eprintln!(
+                        "usage: stash archive [--name <name>] [--incremental] [--compression zstd|xz|gzip|none] [--level <n>] [--volume-size <size>] [<pattern>...]"
+                    );
+                    exit(1);
+                }
+            };
+            let name = match args.get("--name") {
+                Some(name) => name.to_string(),
+                None => Local::now().format("%Y-%m-%d-%H%M%S").to_string(),
+            };
+            let options = ArchiveOptions {
+                compression: parse_policy(args.get("--compression").unwrap_or("gzip")),
+                level: args.get("--level").map(|level| match level.parse::<u32>() {
+                    Ok(level) => level,
+                    Err(_) => {
+                        eprintln!("{} Invalid compression level: {}", ERR, level);
+                        exit(1);
+                    }
+                }),
+                volume_size: args
+                    .get("--volume-size")
+                    .map(|size| match parse_size(size) {
+                        Some(size) => size,
+                        None => {
+                            eprintln!("{} Invalid volume size: {} (try 650M or 4G)", ERR, size);
+                            exit(1);
+                        }
+                    }),
+                incremental: args.has("--incremental"),
+            };
+
+            //  Move selected files into a named, encrypted tarball, or copy
+            //  just what changed into an incremental one
+            //
+            match stash.archive(&name, &args.files, &options) {
+                Ok(files) => println!("{}: archived {} files", name, files.len()),
                 Err(msg) => {
                     eprintln!("{} {}", ERR, msg);
                     exit(1);
@@ -196,20 +908,199 @@ fn main() {
             }
         }
         "unpack" => {
+            let args = match parse_args(args, &["--chain"], &[]) {
+                Ok(args) if !args.has("--chain") || args.files.len() <= 1 => args,
+                _ => {
+                    eprintln!("usage: stash unpack [<name> [<file>...]] | --chain [<name>]");
+                    exit(1);
+                }
+            };
+            //  Restore the files of a named archive, or just those given, or
+            //  rebuild a chain of incremental archives
+            //
+            let name = args.files.first().map(String::as_str);
+            let result = if args.has("--chain") {
+                stash.unpack_chain(name)
+            } else {
+                stash.unpack(name, args.files.get(1..).unwrap_or_default())
+            };
+            match result {
+                Ok(files) => {
+                    for file in files {
+                        println!("{}: unpacked", file);
+                    }
+                }
+                Err(msg) => {
+                    eprintln!("{} {}", ERR, msg);
+                    exit(1);
+                }
+            }
+        }
+        "export-bundle" => {
+            let args = match parse_args(args, &[], &["--passphrase-file"]) {
+                Ok(args) if args.files.len() == 1 => args,
+                _ => {
+                    eprintln!("usage: stash export-bundle [--passphrase-file <file>] <bundle>");
+                    exit(1);
+                }
+            };
+            let passphrase = passphrase("Bundle", args.get("--passphrase-file"), true);
+
+            //  Seal every file, key and environment into one portable file
+            //
+            match stash.export_bundle(Path::new(&args.files[0]), &passphrase) {
+                Ok(contents) => println!(
+                    "{}: bundled {} files and {} environments",
+                    args.files[0],
+                    contents.files.len(),
+                    contents.envs.len()
+                ),
+                Err(msg) => {
+                    eprintln!("{} {}", ERR, msg);
+                    exit(1);
+                }
+            }
+        }
+        "import-bundle" => {
+            let args = match parse_args(args, &[], &["--on-conflict", "--passphrase-file"]) {
+                Ok(args) if args.files.len() == 1 => args,
+                _ => {
+                    eprintln!(
+                        "usage: stash import-bundle [--on-conflict=<policy>] [--passphrase-file <file>] <bundle>"
+                    );
+                    exit(1);
+                }
+            };
+            let conflict = parse_policy(args.get("--on-conflict").unwrap_or("error"));
+            let passphrase = passphrase("Bundle", args.get("--passphrase-file"), false);
+
+            //  Check the whole bundle, then merge it into the stash
+            //
+            match stash.import_bundle(Path::new(&args.files[0]), &passphrase, conflict) {
+                Ok(contents) => {
+                    for file in contents.files {
+                        println!("{}: imported", file);
+                    }
+                    for project in contents.envs {
+                        println!("{}: imported environment", project);
+                    }
+                }
+                Err(msg) => {
+                    eprintln!("{} {}", ERR, msg);
+                    exit(1);
+                }
+            }
+        }
+        "config" => {
+            //  Show every setting, show one, or change one
+            //
+            let result = match args {
+                [] => stash.config().map(|settings| {
+                    for (key, value) in settings {
+                        println!("{} = {}", key, value);
+                    }
+                }),
+                [key] => stash.get_config(key).map(|value| println!("{}", value)),
+                [key, value] => stash.set_config(key, value),
+                _ => {
+                    eprintln!("usage: stash config [<key> [<value>]]");
+                    exit(1);
+                }
+            };
+            if let Err(msg) = result {
+                eprintln!("{} {}", ERR, msg);
+                exit(1);
+            }
+        }
+        "gc" => {
             if !args.is_empty() {
-                eprintln!("usage: stash unpack");
+                eprintln!("usage: stash gc");
                 exit(1);
             }
-            //  Unpack `.tar.gz` of stash contents
+            //  Reclaim chunks nothing refers to any more
             //
-            match stash.unpack() {
-                Ok(_) => {}
+            match stash.gc() {
+                Ok((count, bytes)) => println!("removed {} chunks ({} bytes)", count, bytes),
                 Err(msg) => {
                     eprintln!("{} {}", ERR, msg);
                     exit(1);
                 }
             }
         }
+        "backup" => {
+            let usage = "usage: stash backup [--keep-daily <n>] [--keep-weekly <n>] [--passphrase-file <file>] <target>\n       stash backup list [--passphrase-file <file>] <target>\n       stash backup restore [--passphrase-file <file>] <target> [<backup>]";
+            let (action, rest) = match args.first().map(String::as_str) {
+                Some(action @ ("list" | "restore")) => (action, &args[1..]),
+                _ => ("", args),
+            };
+            let args = match parse_args(
+                rest,
+                &[],
+                &["--keep-daily", "--keep-weekly", "--passphrase-file"],
+            ) {
+                Ok(args) if !args.files.is_empty() && args.files.len() <= 2 => args,
+                _ => {
+                    eprintln!("{}", usage);
+                    exit(1);
+                }
+            };
+            let target = Path::new(&args.files[0]);
+            let keep = |option| match args.get(option).map(str::parse::<usize>) {
+                Some(Ok(count)) => Some(count),
+                Some(Err(_)) => {
+                    eprintln!("{} Invalid count for {}", ERR, option);
+                    exit(1);
+                }
+                None => None,
+            };
+            let retention = Retention {
+                daily: keep("--keep-daily"),
+                weekly: keep("--keep-weekly"),
+            };
+            if (action.is_empty() || action == "list") && args.files.len() > 1
+                || !action.is_empty() && retention != Retention::default()
+            {
+                eprintln!("{}", usage);
+                exit(1);
+            }
+            let confirm = action.is_empty() && !Stash::has_backup(target);
+            let passphrase = passphrase("Backup", args.get("--passphrase-file"), confirm);
+
+            //  Back up, list backups, or restore one into an empty stash
+            //
+            let result = match action {
+                "list" => stash.backups(target, &passphrase).map(|infos| {
+                    for info in infos {
+                        println!(
+                            "{}  {}  {} files",
+                            info.id,
+                            format_time(info.created),
+                            info.files
+                        );
+                    }
+                }),
+                "restore" => stash
+                    .restore_backup(target, &passphrase, args.files.get(1).map(String::as_str))
+                    .map(|files| {
+                        for file in files {
+                            println!("{}: restored", file);
+                        }
+                    }),
+                _ => stash.backup(target, &passphrase, retention).map(|report| {
+                    println!(
+                        "{}: backed up {} files ({} new)",
+                        report.snapshot, report.files, report.copied
+                    );
+                    for id in report.removed {
+                        println!("{}: removed", id);
+                    }
+                }),
+            };
+            if let Err(msg) = result {
+                eprintln!("{} {}", ERR, msg);
+                exit(1);
+            }
+        }
         _ => {
             eprintln!("{}", USAGE);
             eprintln!("Unknown command: {}", cmd);
@@ -218,6 +1109,412 @@ fn main() {
     }
 }
 
+/// Formats a stashed file as a line of `stash list -l`, shown as `name`.
+///
+fn format_entry(info: &EntryInfo, name: &str) -> String {
+    //
+    let mut cipher = info.cipher.clone();
+    if info.chunked {
+        cipher.push_str("+chunked");
+    }
+    if let Some(compression) = info.compression {
+        cipher.push_str(&format!("+{}", compression));
+    }
+    let original = match (info.symlink, info.original.as_str()) {
+        (_, "" | "-") => String::new(),
+        (true, original) => format!("  (link from {})", original),
+        (false, original) => format!("  (from {})", original),
+    };
+    let tags = match info.tags.is_empty() {
+        true => String::new(),
+        false => format!("  [{}]", info.tags.join(",")),
+    };
+    format!(
+        "{:>10}  {}  {:<20}  {:>3} revs  {}{}{}",
+        info.size,
+        format_time(info.stashed),
+        cipher,
+        info.revisions,
+        name,
+        original,
+        tags
+    )
+}
+
+/// Formats what `stash info` shows about a file, one field per line.
+///
+fn format_details(details: &FileDetails) -> String {
+    //
+    let mut storage = format!("{} (format {})", details.cipher, details.format);
+    if details.chunked {
+        storage.push_str(", chunked");
+    }
+    if let Some(compression) = details.compression {
+        storage.push_str(&format!(", compressed with {}", compression));
+    }
+    let yes_no = |yes: bool| if yes { "yes" } else { "no" };
+    let mut fields = vec![
+        ("name", details.name.clone()),
+        ("original", details.original.clone()),
+        ("size", format!("{} bytes", details.size)),
+        ("stored", format!("{} bytes", details.stored)),
+        ("sha256", details.hash.clone()),
+        ("storage", storage),
+        ("mode", format!("{:o}", details.mode)),
+        ("symlink", yes_no(details.symlink).to_string()),
+        ("created", format_time(details.created)),
+        ("stashed", format_time(details.stashed)),
+        ("modified", format_time(details.modified)),
+        (
+            "accessed",
+            details.accessed.map_or("-".to_string(), format_time),
+        ),
+        ("revisions", details.revisions.len().to_string()),
+        ("tags", details.tags.join(",")),
+        ("note", details.note.clone()),
+        ("key cached", yes_no(details.cached).to_string()),
+        ("in stash", yes_no(details.in_stash).to_string()),
+        ("archives", details.archives.join(", ")),
+    ];
+    fields.retain(|(_, value)| !value.is_empty());
+
+    let mut out = String::new();
+    for (field, value) in fields {
+        out.push_str(&format!("{:<12} {}\n", format!("{}:", field), value));
+    }
+    for rev in &details.revisions {
+        out.push_str(&format!(
+            "  {:>4}  {}  {}{}\n",
+            rev.number,
+            format_time(rev.stashed),
+            rev.hash.get(..12).unwrap_or(&rev.hash),
+            if rev.current { "  (current)" } else { "" }
+        ));
+    }
+
+    out
+}
+
+/// Returns the directory part of a stashed name, or `""` at the top.
+///
+fn parent_dir(name: &str) -> &str {
+    name.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+/// Returns the directory lines `stash list --tree` shows on moving from
+/// the files of `from` to those of `to`.
+///
+fn tree_dirs(from: &str, to: &str) -> Vec<String> {
+    //
+    //  Skip the directories both share, which are already shown
+    //
+    let from: Vec<&str> = from.split('/').filter(|part| !part.is_empty()).collect();
+    let to: Vec<&str> = to.split('/').filter(|part| !part.is_empty()).collect();
+    let shared = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+
+    (shared..to.len())
+        .map(|depth| format!("{}{}/", "  ".repeat(depth), to[depth]))
+        .collect()
+}
+
+/// Formats a workspace change in the style of `git status --short`.
+///
+fn format_change(change: &Change) -> String {
+    match change {
+        Change::Modified(name) => format!("M {}", name),
+        Change::Added(name) => format!("A {}", name),
+        Change::Deleted(name) => format!("D {}", name),
+    }
+}
+
+/// Flags, options and file arguments passed to a command.
+///
+struct Args {
+    flags: Vec<String>,
+    options: Vec<(String, String)>,
+    files: Vec<String>,
+}
+impl Args {
+    ///
+    /// Checks whether `flag` was passed.
+    ///
+    fn has(&self, flag: &str) -> bool {
+        self.flags.iter().any(|arg| arg == flag)
+    }
+
+    /// Returns the value of `option`, if it was passed.
+    ///
+    fn get(&self, option: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|(name, _)| name == option)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Sort command arguments into known flags, options and files.
+///
+/// Short flags may be combined (`-cr`), and options take their value either
+/// as `--option=value` or as the following argument. Everything after `--`
+/// is treated as a file.
+///
+fn parse_args(args: &[String], flags: &[&str], options: &[&str]) -> Result<Args, String> {
+    //
+    let mut parsed = Args {
+        flags: Vec::new(),
+        options: Vec::new(),
+        files: Vec::new(),
+    };
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        //
+        //  Stop parsing options after `--`
+        //
+        if arg == "--" {
+            parsed.files.extend(args.by_ref().cloned());
+            //  Long flags and options
+            //
+        } else if let Some(long) = arg.strip_prefix("--") {
+            let (name, value) = match long.split_once('=') {
+                Some((name, value)) => (format!("--{}", name), Some(value.to_string())),
+                None => (arg.clone(), None),
+            };
+            if options.contains(&name.as_str()) {
+                let value = match value {
+                    Some(value) => value,
+                    None => args
+                        .next()
+                        .cloned()
+                        .ok_or_else(|| format!("Missing value for {}", name))?,
+                };
+                parsed.options.push((name, value));
+            } else if flags.contains(&name.as_str()) && value.is_none() {
+                parsed.flags.push(name);
+            } else {
+                return Err(format!("Unknown option: {}", arg));
+            }
+            //  Short options take the following argument as their value
+            //
+        } else if options.contains(&arg.as_str()) {
+            let value = args
+                .next()
+                .cloned()
+                .ok_or_else(|| format!("Missing value for {}", arg))?;
+            parsed.options.push((arg.clone(), value));
+            //  Short flags, possibly combined
+            //
+        } else if arg.len() > 1 && arg.starts_with('-') {
+            for short in arg[1..].chars() {
+                let flag = format!("-{}", short);
+                if !flags.contains(&flag.as_str()) {
+                    return Err(format!("Unknown option: {}", flag));
+                }
+                parsed.flags.push(flag);
+            }
+            //  Anything else is a file
+            //
+        } else {
+            parsed.files.push(arg.clone());
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Read the passphrase of a bundle or backup (`what`) from `file`, or prompt
+/// for one on the terminal.
+///
+/// Only the first line of `file` is used. When prompting, `confirm` asks
+/// for the passphrase twice.
+///
+fn passphrase(what: &str, file: Option<&str>, confirm: bool) -> Zeroizing<String> {
+    //
+    let passphrase = match file {
+        Some(file) => std::fs::read_to_string(file).map(|text| {
+            let text = Zeroizing::new(text);
+            Zeroizing::new(text.lines().next().unwrap_or_default().to_string())
+        }),
+        None => rpassword::prompt_password(format!("{} passphrase: ", what)).map(Zeroizing::new),
+    };
+    let passphrase = match passphrase {
+        Ok(passphrase) if !passphrase.is_empty() => passphrase,
+        Ok(_) => {
+            eprintln!("{} Passphrase must not be empty", ERR);
+            exit(1);
+        }
+        Err(msg) => {
+            eprintln!("{} Failed to read passphrase: {}", ERR, msg);
+            exit(1);
+        }
+    };
+    if confirm && file.is_none() {
+        let again = rpassword::prompt_password("Repeat passphrase: ").map(Zeroizing::new);
+        match again {
+            Ok(again) if *again == *passphrase => {}
+            _ => {
+                eprintln!("{} Passphrases do not match", ERR);
+                exit(1);
+            }
+        }
+    }
+
+    passphrase
+}
+
+/// Expand each pattern against the logical names in the stash.
+///
+/// Patterns that match nothing are kept as errors to be reported in turn.
+///
+fn expand(stash: &Stash, patterns: &[String]) -> Vec<Result<String, Error>> {
+    //
+    patterns
+        .iter()
+        .flat_map(|pattern| match stash.find(pattern) {
+            Ok(names) => names.into_iter().map(Ok).collect(),
+            Err(err) => vec![Err(err)],
+        })
+        .collect()
+}
+
+/// Write each decrypted target to stdout, reporting failures on stderr.
+///
+/// Stops at the first failure if `fail_fast` is set. Returns whether every
+/// target succeeded.
+///
+fn cat(stash: &Stash, targets: Vec<Result<String, Error>>, fail_fast: bool) -> bool {
+    //
+    let mut stdout = io::stdout().lock();
+    let mut ok = true;
+
+    for target in targets {
+        let result = target.and_then(|file| {
+            stash
+                .cat(&file, &mut stdout)
+                .map_err(|msg| Error::new(msg.kind(), format!("{}: {}", file, msg)))
+        });
+        if let Err(msg) = result {
+            eprintln!("{} {}", ERR, msg);
+            ok = false;
+
+            if fail_fast {
+                break;
+            }
+        }
+    }
+
+    ok
+}
+
+/// Parse an age such as `90m`, `12h`, `30d` or `2w` into seconds.
+///
+/// A bare number is taken as seconds.
+///
+fn parse_age(age: &str) -> Option<u64> {
+    //
+    let (number, unit) = match age.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => age.split_at(i),
+        None => (age, "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+
+    number.parse::<u64>().ok()?.checked_mul(scale)
+}
+
+/// Parse a size such as `650M`, `4G` or `512K` into bytes.
+///
+/// Units are powers of 1024, and a bare number is taken as bytes.
+///
+fn parse_size(size: &str) -> Option<u64> {
+    //
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => size.split_at(i),
+        None => (size, "B"),
+    };
+    let scale: u64 = match unit.to_ascii_uppercase().as_str() {
+        "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return None,
+    };
+
+    number.parse::<u64>().ok()?.checked_mul(scale)
+}
+
+/// Format seconds since the Unix epoch as a local date and time.
+///
+fn format_time(secs: u64) -> String {
+    //
+    DateTime::from_timestamp(secs as i64, 0)
+        .map(|time| {
+            time.with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        })
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Parse a policy option, bailing out if it is invalid.
+///
+fn parse_policy<T: FromStr<Err = Error>>(value: &str) -> T {
+    match value.parse() {
+        Ok(policy) => policy,
+        Err(msg) => {
+            eprintln!("{} {}", ERR, msg);
+            exit(1);
+        }
+    }
+}
+
+/// Run `op` on each target, reporting success or failure per file.
+///
+/// `op` returns the name to report on success. Stops at the first failure
+/// if `fail_fast` is set. Returns whether every target succeeded.
+///
+fn batch<F>(targets: Vec<Result<String, Error>>, verb: &str, fail_fast: bool, mut op: F) -> bool
+where
+    F: FnMut(&str) -> Result<String, Error>,
+{
+    //
+    //  Only name files when there is more than one
+    //
+    let verbose = targets.len() > 1;
+    let mut ok = true;
+
+    for target in targets {
+        let result = match target {
+            Ok(file) => match op(&file) {
+                Ok(name) => {
+                    if verbose || name != file {
+                        println!("{} {}", verb, name);
+                    }
+                    continue;
+                }
+                Err(msg) if verbose => format!("{}: {}", file, msg),
+                Err(msg) => msg.to_string(),
+            },
+            Err(msg) => msg.to_string(),
+        };
+        eprintln!("{} {}", ERR, result);
+        ok = false;
+
+        if fail_fast {
+            break;
+        }
+    }
+
+    ok
+}
+
 /// Check if a user (ie. stash) exists on the local system.
 ///
 fn user_exists(user: &str) -> bool {
@@ -243,12 +1540,8 @@ fn user_exists(user: &str) -> bool {
 ///
 fn create_user(existing_user: &str, stash_user: &str) -> Result<(), Error> {
     //
-    let user_home = env::var("HOME").map_err(|err| {
-        Error::new(
-            ErrorKind::Other,
-            format!("Failed to retrieve home directory: {}", err),
-        )
-    })?;
+    let user_home = env::var("HOME")
+        .map_err(|err| Error::other(format!("Failed to retrieve home directory: {}", err)))?;
     let stash_path = Path::new(&user_home).join(".stash");
 
     //  Create `stash` user: same group as `$USER`
@@ -264,19 +1557,11 @@ fn create_user(existing_user: &str, stash_user: &str) -> Result<(), Error> {
             stash_user,
         ])
         .output()
-        .map_err(|err| {
-            Error::new(
-                ErrorKind::Other,
-                format!("Failed to execute `useradd` command: {}", err),
-            )
-        })?;
+        .map_err(|err| Error::other(format!("Failed to execute `useradd` command: {}", err)))?;
 
     if !useradd.status.success() {
         let err = String::from_utf8_lossy(&useradd.stderr);
-        return Err(Error::new(
-            ErrorKind::Other,
-            format!("Error creating user: {}", err),
-        ));
+        return Err(Error::other(format!("Error creating user: {}", err)));
     }
 
     //  Set password for `stash` user (prompt)
@@ -284,18 +1569,13 @@ fn create_user(existing_user: &str, stash_user: &str) -> Result<(), Error> {
     let passwd = Command::new("sudo")
         .args(["passwd", stash_user])
         .status()
-        .map_err(|err| {
-            Error::new(
-                ErrorKind::Other,
-                format!("Failed to execute 'passwd': {}", err),
-            )
-        })?;
+        .map_err(|err| Error::other(format!("Failed to execute 'passwd': {}", err)))?;
 
     if !passwd.success() {
-        return Err(Error::new(
-            ErrorKind::Other,
-            format!("Error setting password for user {}", stash_user),
-        ));
+        return Err(Error::other(format!(
+            "Error setting password for user {}",
+            stash_user
+        )));
     }
 
     Ok(())
@@ -307,12 +1587,8 @@ fn run_as_stash(stash_user: &str, args: Vec<String>) -> Result<(), io::Error> {
     //
     //  Find source of the binary currently running
     //
-    let current_exe = env::current_exe().map_err(|err| {
-        Error::new(
-            ErrorKind::Other,
-            format!("Failed to get current executable path: {}", err),
-        )
-    })?;
+    let current_exe = env::current_exe()
+        .map_err(|err| Error::other(format!("Failed to get current executable path: {}", err)))?;
 
     //  Build `sudo` command to execute as `stash`
     //
@@ -322,19 +1598,63 @@ fn run_as_stash(stash_user: &str, args: Vec<String>) -> Result<(), io::Error> {
         command.arg(arg);
     }
 
-    let status = command.status().map_err(|err| {
-        Error::new(
-            ErrorKind::Other,
-            format!("Failed to execute `sudo` command: {}", err),
-        )
-    })?;
+    let status = command
+        .status()
+        .map_err(|err| Error::other(format!("Failed to execute `sudo` command: {}", err)))?;
 
     if !status.success() {
-        return Err(Error::new(
-            ErrorKind::Other,
-            format!("Failed to execute as `stash` user (exit code: {})", status),
-        ));
+        return Err(Error::other(format!(
+            "Failed to execute as `stash` user (exit code: {})",
+            status
+        )));
     }
 
     Ok(())
 }
+
+//  Unit tests
+//
+#[cfg(test)]
+//
+mod tests {
+    use crate::*;
+
+    #[test]
+    //
+    fn test_valid_parent_dir() {
+        assert_eq!(parent_dir("test"), "");
+        assert_eq!(parent_dir("dir/test"), "dir");
+        assert_eq!(parent_dir("dir/nested/test"), "dir/nested");
+        assert_eq!(parent_dir(".hidden/test"), ".hidden");
+    }
+
+    #[test]
+    //
+    fn test_valid_tree_dirs() {
+        //
+        //  Entering directories shows each level, indented by its depth
+        //
+        assert!(tree_dirs("", "").is_empty());
+        assert_eq!(tree_dirs("", "dir"), ["dir/"]);
+        assert_eq!(tree_dirs("", "dir/nested"), ["dir/", "  nested/"]);
+
+        //  Shared directories aren't shown again
+        //
+        assert!(tree_dirs("dir", "dir").is_empty());
+        assert_eq!(tree_dirs("dir", "dir/nested"), ["  nested/"]);
+        assert_eq!(tree_dirs("dir/one", "dir/two"), ["  two/"]);
+        assert!(tree_dirs("dir/nested", "dir").is_empty());
+        assert_eq!(tree_dirs("one/nested", "two"), ["two/"]);
+
+        //  Walking sorted names shows each directory once, before its files
+        //
+        let names = ["top", "a/one", "a/b/two", "a/b/three", "a/c/four", "d/five"];
+        let mut dir = "";
+        let mut lines = Vec::new();
+        for name in names {
+            lines.extend(tree_dirs(dir, parent_dir(name)));
+            dir = parent_dir(name);
+        }
+        assert_eq!(lines, ["a/", "  b/", "  c/", "d/"]);
+    }
+}